@@ -0,0 +1,168 @@
+// A corpus of the responses no probe matched, for writing new signatures
+// from. Each distinct response is stored once, however many targets sent it.
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::input::ErrorKind;
+use crate::output::{RadarOutput, Timestamp};
+use crate::scan::Target;
+use crate::serviceprobes::TransportProtocol;
+
+/// Where an unmatched response was first seen, written next to it
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmatchedEntry {
+    /// sha256 of the response, lowercase hex, also the name of its files
+    pub sha256: String,
+    pub target: Target,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<TransportProtocol>,
+    /// read inside a tls session
+    pub tls: bool,
+    /// the probe that drew the response, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<String>,
+    pub timestamp: Timestamp,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_set: Option<String>,
+}
+
+/// A directory of unmatched responses, `<sha256>.bin` holding the bytes as
+/// they're stored in results, cut to the max stored response if set, and
+/// `<sha256>.json` the UnmatchedEntry of the first target that sent them.
+/// Responses already in the directory, from earlier runs too, are skipped.
+pub struct UnmatchedCorpus {
+    dir: PathBuf,
+    seen: HashSet<String>,
+}
+
+impl UnmatchedCorpus {
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<UnmatchedCorpus> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut seen = HashSet::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "bin") {
+                if let Some(stem) = path.file_stem() {
+                    seen.insert(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+        Ok(UnmatchedCorpus { dir, seen })
+    }
+
+    /// Add the responses of a result that were read from an open port and
+    /// matched nothing, returning how many weren't in the corpus yet.
+    /// Connection failures and empty responses are left out.
+    pub fn add(&mut self, output: &RadarOutput) -> io::Result<usize> {
+        let mut added = 0;
+        if let Some(response) = unmatched(&output.error, &output.response) {
+            added += self.add_response(output, &response, false)? as usize;
+        }
+        if let Some(response) = unmatched(&output.tls_error, &output.tls_response) {
+            added += self.add_response(output, &response, true)? as usize;
+        }
+        Ok(added)
+    }
+
+    fn add_response(
+        &mut self,
+        output: &RadarOutput,
+        response: &[u8],
+        tls: bool,
+    ) -> io::Result<bool> {
+        let sha256: String = Sha256::digest(response)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if self.seen.contains(&sha256) {
+            return Ok(false);
+        }
+        let entry = UnmatchedEntry {
+            sha256: sha256.clone(),
+            target: output.target.clone(),
+            transport: output.transport.clone(),
+            tls,
+            // response_probe is only known for the plaintext response
+            probe: output.response_probe.clone().filter(|_| !tls),
+            timestamp: output.timestamp,
+            label: output.label.clone(),
+            run_id: output.run_id.clone(),
+            probe_set: output.probe_set.clone(),
+        };
+        // the metadata first, so a response file always has it alongside
+        let metadata = serde_json::to_vec_pretty(&entry).map_err(io::Error::other)?;
+        fs::write(self.dir.join(format!("{}.json", sha256)), metadata)?;
+        fs::write(self.dir.join(format!("{}.bin", sha256)), response)?;
+        self.seen.insert(sha256);
+        Ok(true)
+    }
+}
+
+// The decoded response when the error says nothing matched it
+fn unmatched(error: &Option<String>, response: &Option<String>) -> Option<Vec<u8>> {
+    if ErrorKind::of(error.as_ref()?) != ErrorKind::NoDetection {
+        return None;
+    }
+    let response = base64::decode(response.as_ref()?).ok()?;
+    Some(response).filter(|r| !r.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RadarError;
+
+    #[test]
+    fn test_unmatched_corpus() {
+        let dir = std::env::temp_dir().join(format!("radar-corpus-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut corpus = UnmatchedCorpus::open(&dir).unwrap();
+
+        let unmatched = |port| {
+            let r = Err(RadarError::NoDetection(b"* OK ready\r\n".to_vec()));
+            let mut output = RadarOutput::from_detection(Target::new("10.0.0.1", port), r);
+            output.response_probe = Some("NULL".into());
+            output
+        };
+        assert_eq!(corpus.add(&unmatched(143)).unwrap(), 1);
+        // the same banner again is already stored
+        assert_eq!(corpus.add(&unmatched(144)).unwrap(), 0);
+        // as is a connection failure, or a port that sent nothing
+        let refused = RadarError::Io(io::ErrorKind::ConnectionRefused.into());
+        let refused = RadarOutput::from_detection(Target::new("10.0.0.1", 1), Err(refused));
+        assert_eq!(corpus.add(&refused).unwrap(), 0);
+        let silent = RadarError::NoDetection(vec![]);
+        let silent = RadarOutput::from_detection(Target::new("10.0.0.1", 2), Err(silent));
+        assert_eq!(corpus.add(&silent).unwrap(), 0);
+
+        let files: HashSet<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(files.len(), 2);
+        let stored = files.iter().find(|f| f.ends_with(".bin")).unwrap();
+        assert_eq!(fs::read(dir.join(stored)).unwrap(), b"* OK ready\r\n");
+        let metadata = stored.replace(".bin", ".json");
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.join(&metadata)).unwrap()).unwrap();
+        assert_eq!(metadata["target"]["port"], 143);
+        assert_eq!(metadata["probe"], "NULL");
+        assert_eq!(metadata["tls"], false);
+        assert_eq!(metadata["sha256"], stored.trim_end_matches(".bin"));
+
+        // a corpus opened again knows what it holds
+        let mut corpus = UnmatchedCorpus::open(&dir).unwrap();
+        assert_eq!(corpus.add(&unmatched(145)).unwrap(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
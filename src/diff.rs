@@ -0,0 +1,224 @@
+// Comparing the results of two runs over the same targets, to see which
+// services appeared, went away or changed version between them
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+
+use crate::output::RadarOutput;
+use crate::scan::Target;
+use crate::serviceprobes::TransportProtocol;
+
+// Results are joined on their target and transport
+type ResultKey = (Target, Option<TransportProtocol>);
+
+/// The service detected on a target in one run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Detected {
+    /// the service inside tls for a tls service
+    pub service: String,
+    pub version_info: String,
+    pub tls: bool,
+}
+
+impl Detected {
+    fn of(output: RadarOutput) -> Option<Detected> {
+        let tls = output.tls_service_match.is_some();
+        let m = output.tls_service_match.or(output.service_match)?;
+        Some(Detected {
+            service: m.service,
+            version_info: m.version_info,
+            tls,
+        })
+    }
+}
+
+/// The detections of one run's results, by target
+#[derive(Debug, Default)]
+pub struct RunResults {
+    // None for a target that was scanned without detecting anything
+    detections: HashMap<ResultKey, Option<Detected>>,
+}
+
+impl RunResults {
+    /// Read json results, one per line. Probe attempt records are left out,
+    /// and of a two phase scan's records the later version one is kept.
+    /// Lines that aren't results are skipped with a warning.
+    pub fn read<R: BufRead>(reader: R) -> io::Result<RunResults> {
+        let mut results = RunResults::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: RadarOutput = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    tracing::warn!("failed to parse result {}: {}", line, e);
+                    continue;
+                }
+            };
+            if record.probe_attempt.is_some() {
+                continue;
+            }
+            let key = (record.target.clone(), record.transport.clone());
+            results.detections.insert(key, Detected::of(record));
+        }
+        Ok(results)
+    }
+
+    pub fn len(&self) -> usize {
+        self.detections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.detections.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// detected in the new run only, including targets the old run didn't
+    /// scan
+    Added,
+    /// detected in the old run only, including targets the new run didn't
+    /// scan
+    Removed,
+    /// detected in both, as a different service, version or over tls or not
+    Changed,
+}
+
+/// A target whose detection differs between two runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiffEntry {
+    pub target: Target,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<TransportProtocol>,
+    pub change: Change,
+    pub old: Option<Detected>,
+    pub new: Option<Detected>,
+    /// Set when the target is in only one of the runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub only_in: Option<DiffSide>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffSide {
+    Old,
+    New,
+}
+
+/// The targets whose detection changed from the old run to the new, ordered
+/// by address. Targets with nothing detected in either run are left out.
+pub fn diff(old: &RunResults, new: &RunResults) -> Vec<DiffEntry> {
+    let mut entries = vec![];
+    let none = None;
+    for (key, old_detected) in &old.detections {
+        let (new_detected, only_in) = match new.detections.get(key) {
+            Some(new_detected) => (new_detected, None),
+            None => (&none, Some(DiffSide::Old)),
+        };
+        entries.extend(diff_entry(key, old_detected, new_detected, only_in));
+    }
+    for (key, new_detected) in &new.detections {
+        if !old.detections.contains_key(key) {
+            entries.extend(diff_entry(key, &none, new_detected, Some(DiffSide::New)));
+        }
+    }
+    entries.sort_by(|a, b| {
+        a.target
+            .cmp_by_address(&b.target)
+            .then_with(|| a.transport.cmp(&b.transport))
+    });
+    entries
+}
+
+fn diff_entry(
+    key: &ResultKey,
+    old: &Option<Detected>,
+    new: &Option<Detected>,
+    only_in: Option<DiffSide>,
+) -> Option<DiffEntry> {
+    let change = match (old, new) {
+        (None, None) => return None,
+        (None, Some(_)) => Change::Added,
+        (Some(_), None) => Change::Removed,
+        (Some(old), Some(new)) if old == new => return None,
+        (Some(_), Some(_)) => Change::Changed,
+    };
+    Some(DiffEntry {
+        target: key.0.clone(),
+        transport: key.1.clone(),
+        change,
+        old: old.clone(),
+        new: new.clone(),
+        only_in,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(port: u16, service: Option<&str>, version_info: &str) -> String {
+        let service_match = match service {
+            Some(service) => format!(
+                r#"{{"service":"{}","pattern":"^x","pattern_options":"","version_info":"{}"}}"#,
+                service, version_info
+            ),
+            None => "null".into(),
+        };
+        format!(
+            r#"{{"target":{{"ip":"10.0.0.1","domain":null,"port":{}}},"transport":"TCP","timestamp":1,"tls":false,"service_match":{},"tls_service_match":null}}"#,
+            port, service_match
+        )
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = [
+            record(21, Some("ftp"), "p/vsftpd/ v/3.0.2/"),
+            record(22, Some("ssh"), "p/OpenSSH/"),
+            record(25, None, ""),
+            record(80, Some("http"), "p/nginx/"),
+            record(3306, Some("mysql"), ""),
+        ];
+        let new = [
+            record(21, Some("ftp"), "p/vsftpd/ v/3.0.5/"),
+            record(22, Some("ssh"), "p/OpenSSH/"),
+            record(25, Some("smtp"), "p/Postfix/"),
+            record(80, None, ""),
+            record(443, Some("http"), "p/nginx/"),
+            "not json".into(),
+        ];
+        let old = RunResults::read(old.join("\n").as_bytes()).unwrap();
+        let new = RunResults::read(new.join("\n").as_bytes()).unwrap();
+        assert_eq!(new.len(), 5);
+
+        let entries = diff(&old, &new);
+        let changes: Vec<(u16, Change, Option<DiffSide>)> = entries
+            .iter()
+            .map(|e| (e.target.port, e.change, e.only_in))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (21, Change::Changed, None),
+                (25, Change::Added, None),
+                (80, Change::Removed, None),
+                (443, Change::Added, Some(DiffSide::New)),
+                (3306, Change::Removed, Some(DiffSide::Old)),
+            ]
+        );
+        assert_eq!(
+            entries[0].old.as_ref().unwrap().version_info,
+            "p/vsftpd/ v/3.0.2/"
+        );
+        assert_eq!(
+            entries[0].new.as_ref().unwrap().version_info,
+            "p/vsftpd/ v/3.0.5/"
+        );
+    }
+}
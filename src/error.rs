@@ -8,6 +8,13 @@ pub enum RadarError {
     Elapsed(Elapsed),
     NoDetection(Vec<u8>),
     Tls(native_tls::Error),
+    /// The tls handshake completed but the server's certificate couldn't
+    /// be read
+    Certificate(String),
+    Proxy {
+        proxy: String,
+        error: tokio_socks::Error,
+    },
 }
 
 impl fmt::Display for RadarError {
@@ -16,7 +23,12 @@ impl fmt::Display for RadarError {
             RadarError::Io(ref err) => err.fmt(f),
             RadarError::Elapsed(ref err) => err.fmt(f),
             RadarError::Tls(ref err) => err.fmt(f),
+            RadarError::Certificate(ref err) => write!(f, "bad certificate: {}", err),
             RadarError::NoDetection(_) => write!(f, "No Detection"),
+            RadarError::Proxy {
+                ref proxy,
+                ref error,
+            } => write!(f, "proxy {}: {}", proxy, error),
         }
     }
 }
@@ -0,0 +1,315 @@
+// Sending results to a Splunk HTTP Event Collector. Events are posted in
+// batches, and a batch the collector doesn't take is posted again after a
+// growing delay, so a collector that's busy or restarting loses nothing.
+
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::http::{post, HttpError};
+
+pub const DEFAULT_HEC_BATCH_SIZE: usize = 100;
+pub const DEFAULT_HEC_RETRIES: usize = 5;
+const HEC_TIMEOUT: Duration = Duration::from_secs(30);
+// how long a batch that isn't full waits for more events before it's posted
+const HEC_BATCH_WAIT: Duration = Duration::from_secs(1);
+const HEC_RETRY_DELAY: Duration = Duration::from_secs(1);
+const HEC_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct HecConfig {
+    /// The collector's event endpoint, as
+    /// https://splunk:8088/services/collector/event
+    pub url: String,
+    pub token: String,
+    /// Most events posted at once
+    pub batch_size: usize,
+    /// Times a batch is posted again before giving up on it
+    pub retries: usize,
+    /// Wait before the first retry, doubled for each one after
+    pub retry_delay: Duration,
+    /// Verify the collector's certificate, off for self-signed ones
+    pub verify: bool,
+}
+
+impl HecConfig {
+    pub fn new(url: &str, token: &str) -> HecConfig {
+        HecConfig {
+            url: url.to_string(),
+            token: token.to_string(),
+            batch_size: DEFAULT_HEC_BATCH_SIZE,
+            retries: DEFAULT_HEC_RETRIES,
+            retry_delay: HEC_RETRY_DELAY,
+            verify: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HecError {
+    Http(HttpError),
+    /// The collector answered 200 OK but without acknowledging the events,
+    /// its answer
+    Rejected(String),
+}
+
+impl fmt::Display for HecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HecError::Http(ref err) => err.fmt(f),
+            HecError::Rejected(ref answer) => write!(f, "events not acknowledged: {}", answer),
+        }
+    }
+}
+
+impl std::error::Error for HecError {}
+
+impl From<HttpError> for HecError {
+    fn from(err: HttpError) -> HecError {
+        HecError::Http(err)
+    }
+}
+
+// The answer a collector gives to events it took, {"text":"Success","code":0}
+#[derive(serde::Deserialize)]
+struct Acknowledgement {
+    code: i64,
+}
+
+/// Post the events received, each encoded by OutputFormat::Hec, until the
+/// channel closes. A batch is posted once it's full or a second after its
+/// first event. Returns the error of a batch that failed every retry, or
+/// that the collector refused outright, and posts nothing after it.
+pub async fn post_events(
+    config: HecConfig,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+) -> Result<(), HecError> {
+    let mut batch = vec![];
+    let mut events = 0;
+    let mut deadline = Instant::now();
+    loop {
+        let event = match events {
+            0 => rx.recv().await,
+            _ => match timeout_at(deadline, rx.recv()).await {
+                Ok(event) => event,
+                Err(_) => {
+                    post_batch(&config, &batch, events).await?;
+                    batch.clear();
+                    events = 0;
+                    continue;
+                }
+            },
+        };
+        let event = match event {
+            Some(event) => event,
+            None if events > 0 => return post_batch(&config, &batch, events).await,
+            None => return Ok(()),
+        };
+        if events == 0 {
+            deadline = Instant::now() + HEC_BATCH_WAIT;
+        }
+        batch.extend_from_slice(&event);
+        events += 1;
+        if events >= config.batch_size {
+            post_batch(&config, &batch, events).await?;
+            batch.clear();
+            events = 0;
+        }
+    }
+}
+
+async fn post_batch(config: &HecConfig, batch: &[u8], events: usize) -> Result<(), HecError> {
+    let authorization = format!("Splunk {}", config.token);
+    let headers = [
+        ("Authorization", authorization.as_str()),
+        ("Content-Type", "application/json"),
+    ];
+    let mut delay = config.retry_delay;
+    let mut retries = 0;
+    loop {
+        match post(&config.url, &headers, batch, HEC_TIMEOUT, config.verify).await {
+            Ok(answer) => return acknowledged(&answer),
+            Err(e) if retries < config.retries && retryable(&e) => {
+                retries += 1;
+                tracing::warn!(
+                    "failed to post {} events to {}, retrying in {}s: {}",
+                    events,
+                    config.url,
+                    delay.as_secs_f64(),
+                    e
+                );
+                sleep(delay).await;
+                delay = (delay * 2).min(HEC_MAX_RETRY_DELAY);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// A batch is only delivered once the collector says so. Anything in front
+// of it answering 200 OK, a proxy or a login page, isn't enough.
+fn acknowledged(answer: &[u8]) -> Result<(), HecError> {
+    match serde_json::from_slice::<Acknowledgement>(answer) {
+        Ok(Acknowledgement { code: 0 }) => Ok(()),
+        _ => Err(HecError::Rejected(
+            String::from_utf8_lossy(answer).into_owned(),
+        )),
+    }
+}
+
+// Whether posting again could work, when the collector is down, busy or
+// failing. Malformed events or a bad token are refused again.
+fn retryable(e: &HttpError) -> bool {
+    match e {
+        HttpError::Status(status) => {
+            let code = status.split_whitespace().nth(1);
+            match code.and_then(|code| code.parse::<u16>().ok()) {
+                Some(code) => code == 429 || code >= 500,
+                None => true,
+            }
+        }
+        HttpError::Url(_) => false,
+        HttpError::Io(_) | HttpError::Tls(_) | HttpError::Timeout | HttpError::Incomplete(_) => {
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    // The head and body of a request, read up to its Content-Length
+    async fn read_request(socket: &mut TcpStream) -> (String, Vec<u8>) {
+        let mut request = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let end = match request.windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(end) => end,
+                None if n == 0 => return (String::from_utf8_lossy(&request).into_owned(), vec![]),
+                None => continue,
+            };
+            let head = String::from_utf8_lossy(&request[..end]).into_owned();
+            let len: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map_or(0, |len| len.parse().unwrap());
+            if request.len() >= end + 4 + len || n == 0 {
+                return (head, request[end + 4..].to_vec());
+            }
+        }
+    }
+
+    // Answers each request with the next status and body, returning the url
+    // to post to and the requests received
+    async fn hec_server(
+        responses: Vec<(&'static str, &'static str)>,
+    ) -> (String, Arc<Mutex<Vec<(String, Vec<u8>)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            for (status, body) in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                received.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        let url = format!("http://127.0.0.1:{}/services/collector/event", port);
+        (url, requests)
+    }
+
+    const SUCCESS: &str = "{\"text\":\"Success\",\"code\":0}";
+
+    fn config(url: &str) -> HecConfig {
+        HecConfig {
+            batch_size: 2,
+            retry_delay: Duration::from_millis(10),
+            ..HecConfig::new(url, "token-1")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_events() {
+        let (url, requests) = hec_server(vec![
+            ("503 Service Unavailable", ""),
+            ("200 OK", SUCCESS),
+            ("200 OK", SUCCESS),
+        ])
+        .await;
+        let (tx, rx) = mpsc::channel(8);
+        for event in ["{\"event\":1}\n", "{\"event\":2}\n", "{\"event\":3}\n"] {
+            tx.send(event.as_bytes().to_vec()).await.unwrap();
+        }
+        drop(tx);
+        post_events(config(&url), rx).await.unwrap();
+
+        // the first batch was posted again after the collector was busy, the
+        // last one once the channel closed
+        let requests = requests.lock().unwrap();
+        let bodies: Vec<&[u8]> = requests.iter().map(|(_, body)| &body[..]).collect();
+        assert_eq!(
+            bodies,
+            [
+                &b"{\"event\":1}\n{\"event\":2}\n"[..],
+                b"{\"event\":1}\n{\"event\":2}\n",
+                b"{\"event\":3}\n",
+            ]
+        );
+        let (head, _) = &requests[0];
+        assert!(head.starts_with("POST /services/collector/event HTTP/1.0"));
+        assert!(head.contains("Authorization: Splunk token-1"));
+    }
+
+    #[tokio::test]
+    async fn test_post_events_refused() {
+        let (url, requests) = hec_server(vec![("403 Forbidden", "")]).await;
+        let (tx, rx) = mpsc::channel(8);
+        tx.send(b"{\"event\":1}\n".to_vec()).await.unwrap();
+        drop(tx);
+        // a bad token isn't retried
+        let e = post_events(config(&url), rx).await.unwrap_err();
+        assert!(
+            matches!(e, HecError::Http(HttpError::Status(ref s)) if s.contains("403")),
+            "{}",
+            e
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_events_unacknowledged() {
+        // something other than the collector answering, or the collector
+        // answering with an error code
+        let answers = [
+            "<html>Sign in</html>",
+            "{\"text\":\"Invalid data format\",\"code\":6}",
+        ];
+        for answer in answers {
+            let (url, _) = hec_server(vec![("200 OK", answer)]).await;
+            let (tx, rx) = mpsc::channel(8);
+            tx.send(b"{\"event\":1}\n".to_vec()).await.unwrap();
+            drop(tx);
+            let e = post_events(config(&url), rx).await.unwrap_err();
+            assert!(
+                matches!(e, HecError::Rejected(ref s) if s == answer),
+                "{}",
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,243 @@
+// A plain HTTP/1.0 client over the same tcp and tls the scans use, for the
+// few requests radar makes itself: downloading a probes file and posting
+// results to a collector. An http client crate would be a lot of code for
+// that.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// Not an http or https url
+    Url(String),
+    Io(io::Error),
+    Tls(native_tls::Error),
+    Timeout,
+    /// The server answered with something other than 200 OK, the status line
+    Status(String),
+    /// The response was cut short, or its body isn't as long as its
+    /// Content-Length says or it has none
+    Incomplete(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpError::Url(ref url) => write!(f, "unsupported url {}", url),
+            HttpError::Io(ref err) => err.fmt(f),
+            HttpError::Tls(ref err) => write!(f, "tls error: {}", err),
+            HttpError::Timeout => write!(f, "timed out"),
+            HttpError::Status(ref status) => write!(f, "server answered {}", status),
+            HttpError::Incomplete(ref why) => write!(f, "incomplete response, {}", why),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        HttpError::Io(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+// http://host[:port][/path] or https://, the host a name, an address or a
+// bracketed ipv6 address
+fn parse_url(url: &str) -> Result<Url, HttpError> {
+    let invalid = || HttpError::Url(url.to_string());
+    let (tls, rest) = match (url.strip_prefix("http://"), url.strip_prefix("https://")) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => return Err(invalid()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, port) = bracketed.split_once(']').ok_or_else(invalid)?;
+            (host, port.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| invalid())?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Url {
+        tls,
+        host: host.to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+/// Download the file at an http or https url. Certificates are verified,
+/// unlike when scanning, and redirects aren't followed.
+pub async fn get(url: &str, timeout: Duration) -> Result<Vec<u8>, HttpError> {
+    let url = parse_url(url)?;
+    let request = request("GET", &url, &[], &[]);
+    tokio::time::timeout(timeout, send(&url, &request, true))
+        .await
+        .map_err(|_| HttpError::Timeout)?
+}
+
+/// Post the body to an http or https url with the headers given, returning
+/// the body of the response. Anything but 200 OK is an error. Certificates
+/// are verified unless verify is false, and redirects aren't followed.
+pub async fn post(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+    verify: bool,
+) -> Result<Vec<u8>, HttpError> {
+    let url = parse_url(url)?;
+    let request = request("POST", &url, headers, body);
+    tokio::time::timeout(timeout, send(&url, &request, verify))
+        .await
+        .map_err(|_| HttpError::Timeout)?
+}
+
+// 1.0, so the response body comes whole and not chunked
+fn request(method: &str, url: &Url, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let host = match url.host.contains(':') {
+        true => format!("[{}]:{}", url.host, url.port),
+        false => format!("{}:{}", url.host, url.port),
+    };
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: radar\r\nConnection: close\r\n",
+        method, url.path, host
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if method == "POST" {
+        request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    request.push_str("\r\n");
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+async fn send(url: &Url, request: &[u8], verify: bool) -> Result<Vec<u8>, HttpError> {
+    let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let mut response = vec![];
+    if url.tls {
+        let cx = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(!verify)
+            .build()
+            .map_err(HttpError::Tls)?;
+        let cx = tokio_native_tls::TlsConnector::from(cx);
+        let mut stream = cx
+            .connect(&url.host, stream)
+            .await
+            .map_err(HttpError::Tls)?;
+        stream.write_all(request).await?;
+        stream.read_to_end(&mut response).await?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(request).await?;
+        stream.read_to_end(&mut response).await?;
+    }
+    body(response)
+}
+
+// The body of a 200 OK response. The connection closing is all that ends a
+// 1.0 response, so a body is only taken whole when it's as long as its
+// Content-Length, and a connection cut part way isn't mistaken for it.
+fn body(mut response: Vec<u8>) -> Result<Vec<u8>, HttpError> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| HttpError::Incomplete("the head never ended".into()))?;
+    let head = String::from_utf8_lossy(&response[..end]).into_owned();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(HttpError::Status(status.to_string()));
+    }
+    let length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, length)| length.trim());
+    let length: usize = match length.map(str::parse) {
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return Err(HttpError::Incomplete("a bad Content-Length".into())),
+        None => return Err(HttpError::Incomplete("no Content-Length".into())),
+    };
+    let body = response.split_off(end + 4);
+    if body.len() != length {
+        return Err(HttpError::Incomplete(format!(
+            "{} bytes of a {} byte body",
+            body.len(),
+            length
+        )));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = parse_url("https://[::1]:8443/probes/latest").unwrap();
+        assert_eq!(
+            url,
+            Url {
+                tls: true,
+                host: "::1".into(),
+                port: 8443,
+                path: "/probes/latest".into(),
+            }
+        );
+        let url = parse_url("http://signatures.internal").unwrap();
+        assert_eq!((url.port, url.path.as_str()), (80, "/"));
+        assert!(parse_url("ftp://signatures.internal/probes").is_err());
+        assert!(parse_url("http://signatures.internal:http/").is_err());
+    }
+
+    #[test]
+    fn test_body() {
+        let response = |r: &str| body(r.as_bytes().to_vec());
+        let ok = response("HTTP/1.0 200 OK\r\ncontent-length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(ok, b"hello");
+
+        // cut off at a line boundary, or with a length that can't be checked
+        let e = response("HTTP/1.0 200 OK\r\nContent-Length: 12\r\n\r\nhello\n").unwrap_err();
+        assert!(matches!(e, HttpError::Incomplete(_)), "{}", e);
+        let e = response("HTTP/1.0 200 OK\r\n\r\nhello").unwrap_err();
+        assert!(matches!(e, HttpError::Incomplete(_)), "{}", e);
+        let e = response("HTTP/1.0 200 OK\r\nContent-Length: 5\r\n").unwrap_err();
+        assert!(matches!(e, HttpError::Incomplete(_)), "{}", e);
+
+        let e = response("HTTP/1.0 404 Not Found\r\n\r\n").unwrap_err();
+        assert!(
+            matches!(e, HttpError::Status(ref s) if s.contains("404")),
+            "{}",
+            e
+        );
+    }
+}
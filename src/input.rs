@@ -0,0 +1,504 @@
+use crate::scan::Target;
+use futures::stream::{self, Stream, StreamExt};
+use ipnet::IpNet;
+use rand::Rng;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+/// Targets held at once to shuffle the input order, enough to spread a scan
+/// of a few /16s without holding the whole input
+pub const DEFAULT_SHUFFLE_WINDOW: usize = 65536;
+
+/// Format of the targets read from stdin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// ip,domain,port rows, the ip an address, a name or a dashed range
+    #[default]
+    Csv,
+    /// masscan -oJ or -oD output, one object per line
+    MasscanJson,
+    /// zmap output with the saddr and sport fields, tab or comma separated
+    Zmap,
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<InputFormat, Self::Err> {
+        match input {
+            "csv" => Ok(InputFormat::Csv),
+            "masscan-json" => Ok(InputFormat::MasscanJson),
+            "zmap" => Ok(InputFormat::Zmap),
+            _ => Err(format!(
+                "unknown input format {}, expected csv, masscan-json or zmap",
+                input
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MasscanRecord {
+    ip: String,
+    ports: Vec<MasscanPort>,
+}
+
+#[derive(Deserialize)]
+struct MasscanPort {
+    port: u16,
+    status: Option<String>,
+}
+
+/// Parse one line of masscan json output into a target for each open port.
+/// The array brackets around -oJ output give no targets.
+pub fn parse_masscan_line(line: &str) -> Result<Vec<Target>, String> {
+    let line = line.trim().trim_end_matches(',');
+    if line.is_empty() || line == "[" || line == "]" {
+        return Ok(vec![]);
+    }
+    let MasscanRecord { ip, ports } = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    Ok(ports
+        .into_iter()
+        .filter(|p| p.status.as_deref().is_none_or(|status| status == "open"))
+        .map(|p| Target::new(&ip, p.port))
+        .collect())
+}
+
+/// Parse one line of zmap output into a target. The saddr,sport header line
+/// gives no target.
+pub fn parse_zmap_line(line: &str) -> Result<Option<Target>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with("saddr") {
+        return Ok(None);
+    }
+    let mut fields = line.split(['\t', ',']).map(str::trim);
+    let ip = fields.next().unwrap_or_default();
+    let port = fields
+        .next()
+        .ok_or_else(|| format!("no port in {}, run zmap with -f saddr,sport", line))?;
+    let port = port
+        .parse()
+        .map_err(|e| format!("bad port {}: {}", port, e))?;
+    Ok(Some(Target::new(ip, port)))
+}
+
+/// Kind of error recorded for a target in a previous run's output, to pick
+/// which targets to retry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// the connection or a read timed out
+    Timeout,
+    /// the connection was refused
+    Refused,
+    /// the connection was reset
+    Reset,
+    /// a response came back that no probe matched
+    NoDetection,
+    /// anything else
+    Other,
+}
+
+impl ErrorKind {
+    /// Classify an error message from the error field of an output record
+    pub fn of(error: &str) -> ErrorKind {
+        let error = error.to_lowercase();
+        if error == "no detection" {
+            ErrorKind::NoDetection
+        } else if error.contains("elapsed") || error.contains("timed out") {
+            ErrorKind::Timeout
+        } else if error.contains("refused") {
+            ErrorKind::Refused
+        } else if error.contains("reset") {
+            ErrorKind::Reset
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
+
+impl FromStr for ErrorKind {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<ErrorKind, Self::Err> {
+        match input {
+            "timeout" => Ok(ErrorKind::Timeout),
+            "refused" => Ok(ErrorKind::Refused),
+            "reset" => Ok(ErrorKind::Reset),
+            "no-detection" => Ok(ErrorKind::NoDetection),
+            "other" => Ok(ErrorKind::Other),
+            _ => Err(format!(
+                "unknown error kind {}, expected timeout, refused, reset, no-detection or other",
+                input
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Refused => "refused",
+            ErrorKind::Reset => "reset",
+            ErrorKind::NoDetection => "no-detection",
+            ErrorKind::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A record from a previous run's output, either a target to scan again or
+/// a result to keep as it is
+#[derive(Debug, PartialEq)]
+pub enum RetryRecord {
+    Retry(Target),
+    Keep(serde_json::Value),
+}
+
+/// Parse one line of a previous run's output. Records with an error of one of
+/// the given kinds, or any error when no kinds are given, are retried.
+pub fn parse_retry_line(line: &str, kinds: &[ErrorKind]) -> Result<RetryRecord, String> {
+    let record: serde_json::Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let retry = match record.get("error").and_then(|e| e.as_str()) {
+        Some(error) => kinds.is_empty() || kinds.contains(&ErrorKind::of(error)),
+        None => false,
+    };
+    if !retry {
+        return Ok(RetryRecord::Keep(record));
+    }
+    let target = record.get("target").cloned().ok_or("no target")?;
+    let target = serde_json::from_value(target).map_err(|e| e.to_string())?;
+    Ok(RetryRecord::Retry(target))
+}
+
+/// The networks a scan may and may not reach, to keep it within an
+/// engagement's scope. Deny wins over allow, and with no allowed networks
+/// every address that isn't denied is in scope.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+impl Scope {
+    /// Nothing allowed or denied, so every target is in scope
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Why the target is out of scope, None when it's in. A name in the ip
+    /// column isn't resolved until it's scanned, so it can't be checked and
+    /// is out of any scope that isn't empty. An IPv4-mapped IPv6 address
+    /// is checked as the IPv4 address it reaches as well.
+    pub fn excludes(&self, target: &Target) -> Option<&'static str> {
+        if self.is_empty() {
+            return None;
+        }
+        let ip: IpAddr = match target.ip.parse() {
+            Ok(ip) => ip,
+            Err(_) => return Some("not an ip address to check against the scope"),
+        };
+        let within = |nets: &[IpNet]| {
+            nets.iter()
+                .any(|net| net.contains(&ip) || net.contains(&ip.to_canonical()))
+        };
+        if within(&self.deny) {
+            Some("in a denied network")
+        } else if !self.allow.is_empty() && !within(&self.allow) {
+            Some("outside the allowed networks")
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse a network for a scope, in CIDR notation or a single address
+pub fn parse_network(input: &str) -> Result<IpNet, String> {
+    input
+        .parse()
+        .or_else(|_| input.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| {
+            format!(
+                "bad network {}, expected a cidr like 10.0.0.0/8 or an address",
+                input
+            )
+        })
+}
+
+/// An inclusive range of addresses in dashed notation, as nmap and masscan
+/// take them: 10.0.0.1-10.0.0.50, or with only the last octet of the end,
+/// 10.0.0.1-50. ipv6 ranges give the end in full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    start: IpAddr,
+    end: IpAddr,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<IpRange, Self::Err> {
+        let bad = |why: &str| format!("bad range {}, {}", input, why);
+        let (start, end) = input
+            .split_once('-')
+            .ok_or_else(|| bad("expected start-end"))?;
+        let start: IpAddr = start
+            .trim()
+            .parse()
+            .map_err(|_| bad("the start isn't an address"))?;
+        let end = end.trim();
+        let end = match (start, end.parse::<u8>()) {
+            (IpAddr::V4(start), Ok(last)) => {
+                let mut octets = start.octets();
+                octets[3] = last;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            _ => end
+                .parse()
+                .map_err(|_| bad("the end isn't an address or a last octet"))?,
+        };
+        if start.is_ipv4() != end.is_ipv4() {
+            return Err(bad("the start and end are different ip versions"));
+        }
+        if end < start {
+            return Err(bad("the end comes before the start"));
+        }
+        Ok(IpRange { start, end })
+    }
+}
+
+impl IpRange {
+    /// Every address in the range in order, made as they're needed, so a
+    /// range too large to hold costs nothing until it's scanned
+    pub fn addresses(&self) -> Box<dyn Iterator<Item = IpAddr> + Send> {
+        match (self.start, self.end) {
+            (IpAddr::V4(start), IpAddr::V4(end)) => {
+                Box::new((u32::from(start)..=u32::from(end)).map(|ip| IpAddr::V4(ip.into())))
+            }
+            (IpAddr::V6(start), IpAddr::V6(end)) => {
+                Box::new((u128::from(start)..=u128::from(end)).map(|ip| IpAddr::V6(ip.into())))
+            }
+            _ => unreachable!("a range's start and end are parsed as the same ip version"),
+        }
+    }
+}
+
+/// A target for each address when the target's ip is a dashed range, each
+/// with the rest of the row, or the target itself for an address or a name.
+/// Names have dashes too, so only an address before the dash makes a range.
+pub fn expand_range(target: Target) -> Result<Box<dyn Iterator<Item = Target> + Send>, String> {
+    let range: IpRange = match target.ip.split_once('-') {
+        Some((start, _)) if start.trim().parse::<IpAddr>().is_ok() => target.ip.parse()?,
+        _ => return Ok(Box::new(std::iter::once(target))),
+    };
+    Ok(Box::new(range.addresses().map(move |ip| Target {
+        ip: ip.to_string(),
+        ..target.clone()
+    })))
+}
+
+/// Shuffle a stream too long to hold in memory. Up to window items are
+/// buffered and each one out is picked at random from the buffer, so memory is
+/// bounded by window items, a few megabytes of targets at the default, while
+/// items can move at most window places earlier than they came in. An input
+/// shorter than the window gets a full shuffle.
+pub fn shuffle<S, T>(items: S, window: usize) -> impl Stream<Item = T>
+where
+    S: Stream<Item = T> + Unpin,
+{
+    let window = window.max(1);
+    let state = (items, Vec::with_capacity(window), false);
+    stream::unfold(state, move |(mut items, mut buffer, mut done)| async move {
+        while !done && buffer.len() < window {
+            match items.next().await {
+                Some(item) => buffer.push(item),
+                None => done = true,
+            }
+        }
+        if buffer.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0, buffer.len());
+        let item = buffer.swap_remove(i);
+        Some((item, (items, buffer, done)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scope() {
+        let networks = |nets: &[&str]| nets.iter().map(|n| parse_network(n).unwrap()).collect();
+        let scope = Scope {
+            allow: networks(&["10.0.0.0/8", "2001:db8::/32"]),
+            deny: networks(&["10.0.5.0/24", "10.0.0.1"]),
+        };
+        assert_eq!(scope.excludes(&Target::new("10.1.2.3", 80)), None);
+        assert_eq!(scope.excludes(&Target::new("2001:db8::1", 80)), None);
+        assert!(scope.excludes(&Target::new("10.0.5.9", 80)).is_some());
+        assert!(scope.excludes(&Target::new("10.0.0.1", 80)).is_some());
+        assert!(scope.excludes(&Target::new("192.168.0.1", 80)).is_some());
+        assert!(scope.excludes(&Target::new("2001:db9::1", 80)).is_some());
+        assert_eq!(scope.excludes(&Target::new("::ffff:10.1.2.3", 80)), None);
+        assert!(scope
+            .excludes(&Target::new("::ffff:10.0.5.9", 80))
+            .is_some());
+        assert!(scope.excludes(&Target::new("example.com", 80)).is_some());
+
+        // deny only, everything else is allowed
+        let scope = Scope {
+            deny: networks(&["192.168.0.0/16"]),
+            ..Default::default()
+        };
+        assert_eq!(scope.excludes(&Target::new("10.0.0.1", 80)), None);
+        assert!(scope.excludes(&Target::new("192.168.1.1", 80)).is_some());
+        // the same address written IPv4-mapped
+        assert!(scope
+            .excludes(&Target::new("::ffff:192.168.1.1", 80))
+            .is_some());
+        assert_eq!(scope.excludes(&Target::new("::ffff:10.0.0.1", 80)), None);
+
+        assert_eq!(
+            Scope::default().excludes(&Target::new("example.com", 80)),
+            None
+        );
+        assert!(parse_network("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let addresses = |range: &str| -> Vec<String> {
+            let range: IpRange = range.parse().unwrap();
+            range.addresses().map(|ip| ip.to_string()).collect()
+        };
+        assert_eq!(
+            addresses("10.0.0.254-10.0.1.1"),
+            ["10.0.0.254", "10.0.0.255", "10.0.1.0", "10.0.1.1"]
+        );
+        assert_eq!(
+            addresses("10.0.0.1-3"),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3"]
+        );
+        assert_eq!(addresses("10.0.0.7-7"), ["10.0.0.7"]);
+        assert_eq!(addresses("2001:db8::fe-2001:db8::100").len(), 3);
+
+        // a range too large to hold is only walked as far as it's read
+        let range: IpRange = "0.0.0.0-255.255.255.255".parse().unwrap();
+        assert_eq!(range.addresses().nth(65536).unwrap().to_string(), "0.1.0.0");
+
+        assert!("10.0.0.50-10.0.0.1".parse::<IpRange>().is_err());
+        assert!("10.0.0.50-1".parse::<IpRange>().is_err());
+        assert!("10.0.0.1-256".parse::<IpRange>().is_err());
+        assert!("10.0.0.1-2001:db8::1".parse::<IpRange>().is_err());
+        assert!("10.0.0.1".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn test_expand_range() {
+        let mut row = Target::new("10.0.0.1-2", 443);
+        row.domain = Some("example.com".into());
+        let targets: Vec<Target> = expand_range(row).unwrap().collect();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].ip, "10.0.0.2");
+        assert_eq!(targets[1].port, 443);
+        assert_eq!(targets[1].domain.as_deref(), Some("example.com"));
+
+        // addresses and names, dashes and all, are left as they are
+        for ip in ["10.0.0.1", "db-1.example.com", "2001:db8::1"] {
+            let targets: Vec<Target> = expand_range(Target::new(ip, 80)).unwrap().collect();
+            assert_eq!(targets, [Target::new(ip, 80)]);
+        }
+        assert!(expand_range(Target::new("10.0.0.9-1", 80)).is_err());
+    }
+
+    #[test]
+    fn test_parse_masscan_line() {
+        let line = r#"{   "ip": "10.0.0.1",   "timestamp": "1700000000", "ports": [ {"port": 80, "proto": "tcp", "status": "open", "reason": "syn-ack", "ttl": 64} ] },"#;
+        assert_eq!(
+            parse_masscan_line(line),
+            Ok(vec![Target::new("10.0.0.1", 80)])
+        );
+
+        let line =
+            r#"{"ip": "10.0.0.2", "ports": [{"port": 22, "status": "closed"}, {"port": 443}]}"#;
+        assert_eq!(
+            parse_masscan_line(line),
+            Ok(vec![Target::new("10.0.0.2", 443)])
+        );
+
+        assert_eq!(parse_masscan_line("["), Ok(vec![]));
+        assert_eq!(parse_masscan_line("]"), Ok(vec![]));
+        assert!(parse_masscan_line("{\"ip\": ").is_err());
+    }
+
+    #[test]
+    fn test_parse_zmap_line() {
+        assert_eq!(
+            parse_zmap_line("10.0.0.1\t80"),
+            Ok(Some(Target::new("10.0.0.1", 80)))
+        );
+        assert_eq!(
+            parse_zmap_line("10.0.0.1,443"),
+            Ok(Some(Target::new("10.0.0.1", 443)))
+        );
+        assert_eq!(parse_zmap_line("saddr,sport"), Ok(None));
+        assert!(parse_zmap_line("10.0.0.1").is_err());
+        assert!(parse_zmap_line("10.0.0.1\tnope").is_err());
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(ErrorKind::of("No Detection"), ErrorKind::NoDetection);
+        assert_eq!(ErrorKind::of("deadline has elapsed"), ErrorKind::Timeout);
+        assert_eq!(
+            ErrorKind::of("Connection refused (os error 111)"),
+            ErrorKind::Refused
+        );
+        assert_eq!(
+            ErrorKind::of("Connection reset by peer (os error 104)"),
+            ErrorKind::Reset
+        );
+        assert_eq!(ErrorKind::of("proxy 127.0.0.1:1080: ..."), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_parse_retry_line() {
+        let failed = r#"{"target": {"ip": "10.0.0.1", "domain": null, "port": 80}, "error": "deadline has elapsed"}"#;
+        let found = r#"{"target": {"ip": "10.0.0.2", "domain": null, "port": 80}, "error": null}"#;
+
+        assert_eq!(
+            parse_retry_line(failed, &[]),
+            Ok(RetryRecord::Retry(Target::new("10.0.0.1", 80)))
+        );
+        assert_eq!(
+            parse_retry_line(failed, &[ErrorKind::Timeout]),
+            Ok(RetryRecord::Retry(Target::new("10.0.0.1", 80)))
+        );
+        assert!(matches!(
+            parse_retry_line(failed, &[ErrorKind::Refused]),
+            Ok(RetryRecord::Keep(_))
+        ));
+        assert!(matches!(
+            parse_retry_line(found, &[]),
+            Ok(RetryRecord::Keep(_))
+        ));
+        assert!(parse_retry_line("{", &[]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shuffle() {
+        let items: Vec<u32> = (0..1000).collect();
+
+        let mut shuffled: Vec<u32> = shuffle(stream::iter(items.clone()), 100).collect().await;
+        assert_ne!(shuffled, items);
+        shuffled.sort_unstable();
+        assert_eq!(shuffled, items);
+
+        let unshuffled: Vec<u32> = shuffle(stream::iter(items.clone()), 1).collect().await;
+        assert_eq!(unshuffled, items);
+    }
+}
@@ -1,5 +1,15 @@
-#![feature(ascii_char)]
+pub mod certificate;
+pub mod corpus;
+pub mod diff;
 mod error;
+pub mod hec;
+pub mod http;
+pub mod input;
+// The mock servers the scan tests run against, shared with tests/
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod mock_server;
 pub mod output;
 pub mod scan;
 pub mod serviceprobes;
+pub mod sink;
@@ -1,121 +1,1545 @@
 //! Radar protocol detector CLI
-use futures::stream::StreamExt;
+use futures::stream::{self, LocalBoxStream, StreamExt};
 
-use clap::Parser;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressStyle};
+use ipnet::IpNet;
+use std::cell::Cell;
 use std::error::Error;
-use std::time::Instant;
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio::fs::File;
-use tokio::io::{self, AsyncWriteExt, BufWriter};
+use tokio::io::{self, AsyncBufReadExt, AsyncRead};
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use radar::output::RadarOutput;
-use radar::scan::{start_scan, ScanConfig, Target};
-use radar::serviceprobes::parse::read_service_probes_file;
+use radar::corpus::UnmatchedCorpus;
+use radar::diff::{diff, Change, RunResults};
+use radar::hec::{HecConfig, DEFAULT_HEC_BATCH_SIZE, DEFAULT_HEC_RETRIES};
+use radar::input::{
+    expand_range, parse_masscan_line, parse_network, parse_retry_line, parse_zmap_line, shuffle,
+    ErrorKind, InputFormat, RetryRecord, Scope, DEFAULT_SHUFFLE_WINDOW,
+};
+use radar::output::{
+    LiveStats, OutputFormat, RadarOutput, ScanSummary, SignatureCoverage, TimestampFormat,
+};
+use radar::scan::{
+    check_interface, start_scan, start_scan_probe_sets, AdaptiveTimeout, ProbeSet, RampUp,
+    Resolver, ScanConfig, Target, DEFAULT_MAX_CONCURRENT_SCANS, DEFAULT_MAX_READ_TIMEOUT_MS,
+    DEFAULT_MIN_READ_TIMEOUT_MS, DEFAULT_RAMP_UP_START, DEFAULT_READ_BUFFER_SIZE,
+    DEFAULT_RTT_MULTIPLIER, DEFAULT_UDP_RETRANSMITS, DEFAULT_UDP_RETRANSMIT_INTERVAL_MS,
+};
+use radar::serviceprobes::parse::{
+    cache::{build_cache, read_service_probes_cached},
+    fetch::{read_service_probes_url, DEFAULT_FETCH_TIMEOUT},
+    read_service_probes_file_with_options, ParseOptions, DEFAULT_MAX_LINE_LENGTH,
+};
+use radar::sink::{
+    open_sinks, write_host_results, write_results, write_sorted_results, OutSink, SinkOptions,
+    Sinks,
+};
 
 /// Run Radar Protocol Detector
 #[derive(Debug, Clone, Parser)]
 #[command(version = "0.0.1", author = "Collins Huff")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[command(group(ArgGroup::new("transport").args(["tcp", "udp"]).required(true).multiple(true)))]
 struct Opts {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to output file, defaults to stdout
     #[clap(short, long)]
     out_file: Option<String>,
 
+    /// Once --out-file reaches this size, carry on in a new file numbered
+    /// before the extension, out.1.json, out.2.json and so on. Records are
+    /// never split across files. Bytes, or with a K, M or G suffix.
+    #[clap(long, requires = "out_file", value_parser = parse_size)]
+    max_output_size: Option<u64>,
+
+    /// Indent each result over several lines for reading by eye, json only
+    #[clap(long)]
+    pretty: bool,
+
+    /// Encoding of the results: json, one object per line, msgpack or cbor,
+    /// each record preceded by its length as a 4 byte big endian integer, or
+    /// hec, json lines wrapped as Splunk HTTP Event Collector events
+    #[clap(long, default_value = "json")]
+    output_format: OutputFormat,
+
+    /// Also write results to stdout when writing them to --out-file,
+    /// --out-sink or --hec-url
+    #[clap(long)]
+    also_stdout: bool,
+
+    /// Stream results to a collector at tcp://host:port or unix:///path,
+    /// reconnecting when the connection drops
+    #[clap(long)]
+    out_sink: Option<OutSink>,
+
+    /// Post results to a Splunk HTTP Event Collector at this event endpoint,
+    /// as https://splunk:8088/services/collector/event, in batches
+    #[clap(long, requires = "hec_token")]
+    hec_url: Option<String>,
+
+    /// The token to post to --hec-url with
+    #[clap(long, requires = "hec_url")]
+    hec_token: Option<String>,
+
+    /// Most results posted to --hec-url at once
+    #[clap(long, default_value_t = DEFAULT_HEC_BATCH_SIZE, value_parser = parse_nonzero, requires = "hec_url")]
+    hec_batch_size: usize,
+
+    /// Times a post to --hec-url the collector fails is retried, waiting
+    /// twice as long before each, before results stop being posted
+    #[clap(long, default_value_t = DEFAULT_HEC_RETRIES, requires = "hec_url")]
+    hec_retries: usize,
+
+    /// Don't verify the certificate of --hec-url, for self-signed collectors
+    #[clap(long, requires = "hec_url")]
+    hec_insecure: bool,
+
     /// Path to log file, defaults to stderr
     #[clap(short, long)]
     log_file: Option<String>,
 
-    /// Path to log file, defaults to stderr
-    #[clap(short, long)]
-    probes_file: String,
+    /// Log format: text, or json with one object per line for log shippers
+    #[clap(long, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Path to the nmap-service-probes file
+    #[clap(short, long, required_unless_present = "probes_url")]
+    probes_file: Option<String>,
+
+    /// Download the probes file from this http or https url at startup
+    /// instead of reading --probes-file
+    #[clap(long, conflicts_with_all = ["probes_file", "probes_cache"])]
+    probes_url: Option<String>,
+
+    /// Give up on downloading --probes-url after this long, in seconds or
+    /// with a ms, s, m or h suffix, 30 seconds when not given
+    #[clap(long, value_parser = parse_duration, requires = "probes_url")]
+    probes_url_timeout: Option<Duration>,
+
+    /// Save the probes downloaded from --probes-url to this file, and load
+    /// them from it when a later download fails
+    #[clap(long, requires = "probes_url")]
+    probes_url_cache: Option<String>,
+
+    /// Load the probes from a cache written by build-cache, parsing the
+    /// probes file instead when it has changed since
+    #[clap(long)]
+    probes_cache: Option<String>,
+
+    /// Scan each target a second time with the probes in this file, to compare
+    /// a new set of signatures with the probes file's. Every result is tagged
+    /// with probe_set, the path of the file it was scanned with. Each set
+    /// makes its own connections.
+    #[clap(long, conflicts_with_all = ["tcp_connect_only", "coverage_file"])]
+    compare_probes: Option<String>,
+
+    /// Skip malformed lines in the probes file instead of failing
+    #[clap(long)]
+    lenient: bool,
+
+    /// Fail on probe strings with escapes that don't decode cleanly, like
+    /// \x4 or \q, instead of warning about them
+    #[clap(long)]
+    strict_escapes: bool,
+
+    /// Write a JSON record for each line of the probes file that was left
+    /// out to this file, with its line number, text and the reason
+    #[clap(long)]
+    skipped_lines_file: Option<String>,
+
+    /// Match every pattern regardless of case, even ones without the i
+    /// option. Can over-match, where case is what tells services apart.
+    #[clap(long)]
+    ignore_case: bool,
+
+    /// Only match patterns at the start of a response, as if each began with
+    /// \A. nmap lets a pattern without ^ match anywhere in the response.
+    #[clap(long)]
+    anchored: bool,
+
+    /// Longest line allowed in the probes file, in bytes
+    #[clap(long, default_value_t = DEFAULT_MAX_LINE_LENGTH)]
+    max_line_length: usize,
+
+    /// Scan targets in a random order, shuffling within a window of targets
+    #[clap(long)]
+    randomize: bool,
+
+    /// Targets held in memory to shuffle with --randomize
+    #[clap(long, default_value_t = DEFAULT_SHUFFLE_WINDOW, value_parser = parse_nonzero)]
+    randomize_window: usize,
+
+    /// Stop taking targets after this long, as 90s, 15m or 2h, letting scans
+    /// already started finish and writing their results
+    #[clap(long, value_parser = parse_duration)]
+    max_runtime: Option<Duration>,
+
+    /// Buffer all results and write them sorted by ip and port at the end
+    #[clap(long)]
+    sort: bool,
+
+    /// Buffer all results and write one record per ip at the end, holding
+    /// its results ordered by port. Nothing is written until the targets run
+    /// out, so with targets streamed in indefinitely bound the scan with
+    /// --max-runtime.
+    #[clap(long)]
+    group_by_host: bool,
+
+    /// Write totals for the scan as JSON to this file: results, open ports,
+    /// detections, the detection rate, and counts by service and error
+    #[clap(long)]
+    summary_file: Option<String>,
+
+    /// Write how many times each match and softmatch in the probes file
+    /// matched as JSON to this file, including those that never did, to find
+    /// signatures to prune
+    #[clap(long)]
+    coverage_file: Option<String>,
+
+    /// Store each distinct response nothing matched in this directory, with
+    /// the target and probe it came from, as a corpus to write signatures
+    /// from. Responses already there are skipped.
+    #[clap(long)]
+    unmatched_dir: Option<String>,
 
     /// Max concurrent scans
-    #[clap(long, default_value = "50000")]
+    #[clap(long, default_value_t = DEFAULT_MAX_CONCURRENT_SCANS, value_parser = parse_nonzero)]
     max_concurrent_scans: usize,
 
-    /// run tcp probes
+    /// Grow the scans in flight from --ramp-up-start to max concurrent scans
+    /// over this long, in seconds or with a ms, s, m or h suffix, instead of
+    /// starting them all at once
+    #[clap(long, value_parser = parse_duration)]
+    ramp_up: Option<Duration>,
+
+    /// With --ramp-up, the scans in flight at the start
+    #[clap(long, default_value_t = DEFAULT_RAMP_UP_START, value_parser = parse_nonzero, requires = "ramp_up")]
+    ramp_up_start: usize,
+
+    /// Most scans in flight against any one ip. Results can then come out of
+    /// the order of the targets, as other hosts' targets are scanned while
+    /// one host's wait.
+    #[clap(long, value_parser = parse_nonzero)]
+    per_host_concurrency: Option<usize>,
+
+    /// Raise the open file limit to fit max concurrent scans, if allowed
+    #[clap(long)]
+    raise_fd_limit: bool,
+
+    /// Minimum number of bytes to read before matching a response
+    #[clap(long, default_value = "0")]
+    min_banner_bytes: usize,
+
+    /// Read more of a response that doesn't match and match it again, up to
+    /// this many times per probe, returning at the first match
+    #[clap(long, value_parser = parse_nonzero)]
+    incremental_match: Option<usize>,
+
+    /// Add a Host header with the target's domain to HTTP probes
+    #[clap(long)]
+    http_host_header: bool,
+
+    /// Report HTTP responses no signature matches as http, with the product
+    /// from their Server and X-Powered-By headers
+    #[clap(long)]
+    http_header_fallback: bool,
+
+    /// Number of times to resend a UDP probe that got no response
+    #[clap(long, default_value_t = DEFAULT_UDP_RETRANSMITS)]
+    udp_retransmits: usize,
+
+    /// Milliseconds to wait for a UDP response before resending the probe
+    #[clap(long, default_value_t = DEFAULT_UDP_RETRANSMIT_INTERVAL_MS)]
+    udp_retransmit_interval_ms: u64,
+
+    /// When a port is open but nothing matched, wait this many milliseconds
+    /// and run the probes once more
+    #[clap(long)]
+    no_detection_retry_ms: Option<u64>,
+
+    /// Wait on reads for a multiple of each target's connect round trip time,
+    /// clamped to the min and max read timeouts, instead of five seconds
+    #[clap(long)]
+    adaptive_timeout: bool,
+
+    /// With --adaptive-timeout, the multiple of the round trip time to wait
+    #[clap(long, default_value_t = DEFAULT_RTT_MULTIPLIER, requires = "adaptive_timeout")]
+    rtt_multiplier: u32,
+
+    /// With --adaptive-timeout, the shortest read timeout in milliseconds
+    #[clap(long, default_value_t = DEFAULT_MIN_READ_TIMEOUT_MS, requires = "adaptive_timeout")]
+    min_read_timeout_ms: u64,
+
+    /// With --adaptive-timeout, the longest read timeout in milliseconds
+    #[clap(long, default_value_t = DEFAULT_MAX_READ_TIMEOUT_MS, requires = "adaptive_timeout")]
+    max_read_timeout_ms: u64,
+
+    /// Include a connect/tls/read timing breakdown in each result
+    #[clap(long)]
+    timings: bool,
+
+    /// Write a record for every probe sent, tagged with a probe_attempt field,
+    /// ahead of each target's result. Verbose, for debugging detection.
+    #[clap(long)]
+    trace_probes: bool,
+
+    /// Write one result per target, the best match found over its transports
+    /// and scan phases: a match with version info, then a match, then a
+    /// softmatch. With --compare-probes, one result per probe set
+    #[clap(long)]
+    best_match: bool,
+
+    /// Find open services with the common probes, then rescan them with all probes
+    #[clap(long)]
+    two_phase: bool,
+
+    /// Reuse a connection across probes when the server leaves it open, and
+    /// answered the previous probe in time
+    #[clap(long)]
+    reuse_connections: bool,
+
+    /// Send every probe to a target at once, each on its own connection, and
+    /// stop at the first match. Uses more connections to get results sooner.
+    #[clap(long, conflicts_with = "reuse_connections")]
+    concurrent_probes: bool,
+
+    /// Also check responses to other probes against the NULL probe's matches
+    #[clap(long)]
+    null_fallback: bool,
+
+    /// Local address, or on linux interface name, to scan from over tcp and udp
+    #[clap(long)]
+    interface: Option<String>,
+
+    /// Timestamp format: unix, unix_ms or rfc3339
+    #[clap(long, default_value = "unix")]
+    timestamp_format: TimestampFormat,
+
+    /// Wait this many milliseconds for a banner before writing probe data
+    #[clap(long)]
+    pre_read_ms: Option<u64>,
+
+    /// Write probe data in chunks of this many bytes, each in its own tcp
+    /// segment, instead of in a single write
+    #[clap(long, value_parser = parse_nonzero)]
+    write_chunk_size: Option<usize>,
+
+    /// Milliseconds to wait between chunks of probe data
+    #[clap(long, requires = "write_chunk_size")]
+    write_chunk_delay_ms: Option<u64>,
+
+    /// Size in bytes of the buffer responses are read into
+    #[clap(long, default_value_t = DEFAULT_READ_BUFFER_SIZE, value_parser = parse_nonzero)]
+    read_buffer: usize,
+
+    /// If no probe matches, match against all responses concatenated
+    #[clap(long)]
+    match_combined_responses: bool,
+
+    /// Only run probes with at most this rarity, probes without one count as 0
+    #[clap(long)]
+    max_rarity: Option<usize>,
+
+    /// Only run probes with at least this rarity, the NULL probe always runs
+    #[clap(long)]
+    min_rarity: Option<usize>,
+
+    /// Pick probes as nmap -sV --version-intensity does, 0 to 9: probes up to
+    /// this rarity, the NULL probe, and probes listing the target's port
+    #[clap(long, value_parser = parse_intensity)]
+    version_intensity: Option<usize>,
+
+    /// DNS server, ip:port, to resolve target names with instead of the system resolver
+    #[clap(long)]
+    resolver: Option<SocketAddr>,
+
+    /// Comma separated SOCKS5 proxies to spread tcp connections across
+    #[clap(long, value_delimiter = ',')]
+    socks5: Vec<String>,
+
+    /// Keep at most this many bytes of each response in the output, matching
+    /// still uses the whole response
+    #[clap(long)]
+    max_stored_response: Option<usize>,
+
+    /// Also output responses with non-printable bytes escaped
+    #[clap(long)]
+    response_ascii: bool,
+
+    /// Also output responses hex encoded
+    #[clap(long)]
+    response_hex: bool,
+
+    /// Only connect to each target and report whether the port is open,
+    /// sending no probes. Applies to tcp, udp targets are still probed.
+    #[clap(long)]
+    tcp_connect_only: bool,
+
+    /// Format of the targets on stdin: csv, masscan-json or zmap. The ip of
+    /// a csv row can be a range, 10.0.0.1-10.0.0.50 or 10.0.0.1-50.
+    #[clap(long, default_value = "csv")]
+    input_format: InputFormat,
+
+    /// Scan again the targets that failed in this previous output instead of
+    /// reading stdin. Records that aren't retried are written out unchanged,
+    /// ahead of the new results, so it can't be the output file.
+    #[clap(long)]
+    retry_from: Option<String>,
+
+    /// Comma separated kinds of error to retry with --retry-from: timeout,
+    /// refused, reset, no-detection or other. Defaults to every error.
+    #[clap(long, value_delimiter = ',', requires = "retry_from")]
+    retry_errors: Vec<ErrorKind>,
+
+    /// Include the ports the matching probe expects its services on, to spot
+    /// services on unusual ports
+    #[clap(long)]
+    probe_expected_ports: bool,
+
+    /// Never try tls, not even on services matched as ssl, for plaintext only scans
+    #[clap(long, conflicts_with = "tls_on_no_detection")]
+    no_tls: bool,
+
+    /// When nothing is detected in plaintext on a tls port, scan again over tls
+    #[clap(long)]
+    tls_on_no_detection: bool,
+
+    /// Only scan targets in these networks, comma separated CIDRs or
+    /// addresses. Targets given by name are dropped, they can't be checked.
+    #[clap(long, value_delimiter = ',', value_parser = parse_network)]
+    allow: Vec<IpNet>,
+
+    /// Never scan targets in these networks, comma separated CIDRs or
+    /// addresses, even ones --allow lets through
+    #[clap(long, value_delimiter = ',', value_parser = parse_network)]
+    deny: Vec<IpNet>,
+
+    /// Comma separated ports to treat as tls ports, on top of the probes' sslports
+    #[clap(long, value_delimiter = ',')]
+    tls_ports: Vec<u16>,
+
+    /// Treat only --tls-ports as tls ports, not the probes' sslports
+    #[clap(long)]
+    ignore_sslports: bool,
+
+    /// Scan tls ports over tls first, and never try tls on other ports
+    #[clap(long, conflicts_with = "no_tls")]
+    port_aware_tls: bool,
+
+    /// Only handshake with tls services to report their certificate, sending
+    /// no probes over tls
+    #[clap(long, conflicts_with = "no_tls")]
+    tls_cert_only: bool,
+
+    /// Do a full tls handshake every time, never resuming a session. Only the
+    /// openssl tls backend can promise it, which never resumes anyway. On
+    /// macOS and Windows it's ignored with a warning.
+    #[clap(long, conflicts_with = "no_tls")]
+    no_tls_resumption: bool,
+
+    /// Added to every result, to group the results of a campaign
+    #[clap(long)]
+    label: Option<String>,
+
+    /// Added to every result to tell runs apart, a random uuid by default
+    #[clap(long)]
+    run_id: Option<String>,
+
+    /// Show a progress bar on stderr, when it's a terminal and not logging to a file
+    #[clap(long)]
+    progress: bool,
+
+    /// run tcp probes, at least one of --tcp and --udp is required
     #[clap(short, long)]
     tcp: bool,
 
-    /// run udp probes
+    /// run udp probes, with --tcp too each target is scanned over both
     #[clap(short, long)]
     udp: bool,
 }
 
-impl Into<ScanConfig> for Opts {
-    fn into(self) -> ScanConfig {
+#[derive(Debug, Clone, Subcommand)]
+enum Command {
+    /// List the services the probes file can detect
+    Services {
+        /// Path to nmap-service-probes file
+        #[clap(short, long)]
+        probes_file: String,
+        #[command(flatten)]
+        parse: ParseArgs,
+    },
+    /// Compare two runs' json results, writing a json record for each target
+    /// whose detected service was added, removed or changed
+    Diff {
+        /// Results of the earlier run
+        old: String,
+        /// Results of the later run
+        new: String,
+    },
+    /// Parse the probes file and write a cache of it for --probes-cache. As
+    /// for scanning, the cache is only used with the same parse options.
+    BuildCache {
+        /// Path to nmap-service-probes file
+        #[clap(short, long)]
+        probes_file: String,
+        /// Path to write the cache to
+        #[clap(short, long)]
+        cache_file: String,
+        #[command(flatten)]
+        parse: ParseArgs,
+    },
+}
+
+/// How the subcommands parse the probes file, as the scan options of the
+/// same names
+#[derive(Debug, Clone, Args)]
+struct ParseArgs {
+    #[clap(long)]
+    lenient: bool,
+    #[clap(long)]
+    strict_escapes: bool,
+    #[clap(long, default_value_t = DEFAULT_MAX_LINE_LENGTH)]
+    max_line_length: usize,
+    #[clap(long)]
+    ignore_case: bool,
+    #[clap(long)]
+    anchored: bool,
+}
+
+impl ParseArgs {
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            strict: !self.lenient,
+            strict_escapes: self.strict_escapes,
+            max_line_length: self.max_line_length,
+            ignore_case: self.ignore_case,
+            anchored: self.anchored,
+            ..Default::default()
+        }
+    }
+}
+
+impl Opts {
+    fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            strict: !self.lenient,
+            strict_escapes: self.strict_escapes,
+            max_line_length: self.max_line_length,
+            payload_dir: None,
+            ignore_case: self.ignore_case,
+            anchored: self.anchored,
+            ..Default::default()
+        }
+    }
+
+    fn sink_options(&self) -> SinkOptions {
+        let hec = match (&self.hec_url, &self.hec_token) {
+            (Some(url), Some(token)) => Some(HecConfig {
+                batch_size: self.hec_batch_size,
+                retries: self.hec_retries,
+                verify: !self.hec_insecure,
+                ..HecConfig::new(url, token)
+            }),
+            _ => None,
+        };
+        SinkOptions {
+            out_file: self.out_file.clone(),
+            max_output_size: self.max_output_size,
+            out_sink: self.out_sink.clone(),
+            hec,
+            also_stdout: self.also_stdout,
+            format: self.output_format,
+            pretty: self.pretty,
+        }
+    }
+}
+
+impl From<Opts> for ScanConfig {
+    fn from(opts: Opts) -> ScanConfig {
+        let ramp_up_start = opts.ramp_up_start;
         ScanConfig {
-            tcp: self.tcp,
-            udp: self.udp,
-            max_concurrent_scans: self.max_concurrent_scans,
+            tcp: opts.tcp,
+            udp: opts.udp,
+            max_concurrent_scans: opts.max_concurrent_scans,
+            min_banner_bytes: opts.min_banner_bytes,
+            incremental_match_attempts: opts.incremental_match,
+            http_host_header: opts.http_host_header,
+            http_header_fallback: opts.http_header_fallback,
+            udp_retransmits: opts.udp_retransmits,
+            udp_retransmit_interval_ms: opts.udp_retransmit_interval_ms,
+            timings: opts.timings,
+            two_phase: opts.two_phase,
+            reuse_connections: opts.reuse_connections,
+            concurrent_probes: opts.concurrent_probes,
+            null_fallback: opts.null_fallback,
+            interface: opts.interface,
+            timestamp_format: opts.timestamp_format,
+            pre_read_ms: opts.pre_read_ms,
+            write_chunk_size: opts.write_chunk_size,
+            write_chunk_delay_ms: opts.write_chunk_delay_ms,
+            read_buffer_size: opts.read_buffer,
+            match_combined_responses: opts.match_combined_responses,
+            max_rarity: opts.max_rarity,
+            min_rarity: opts.min_rarity,
+            version_intensity: opts.version_intensity,
+            socks5_proxies: opts.socks5,
+            response_ascii: opts.response_ascii,
+            response_hex: opts.response_hex,
+            tcp_connect_only: opts.tcp_connect_only,
+            probe_expected_ports: opts.probe_expected_ports,
+            per_host_concurrency: opts.per_host_concurrency,
+            tls_on_no_detection: opts.tls_on_no_detection,
+            tls_ports: opts.tls_ports,
+            ignore_ssl_ports: opts.ignore_sslports,
+            port_aware_tls: opts.port_aware_tls,
+            tls_cert_only: opts.tls_cert_only,
+            label: opts.label,
+            run_id: opts.run_id,
+            no_detection_retry_ms: opts.no_detection_retry_ms,
+            adaptive_timeout: match opts.adaptive_timeout {
+                true => Some(AdaptiveTimeout {
+                    rtt_multiplier: opts.rtt_multiplier,
+                    min_ms: opts.min_read_timeout_ms,
+                    max_ms: opts.max_read_timeout_ms,
+                }),
+                false => None,
+            },
+            ramp_up: opts.ramp_up.map(|duration| RampUp {
+                start: ramp_up_start,
+                duration_ms: duration.as_millis() as u64,
+            }),
+            max_stored_response: opts.max_stored_response,
+            resolver: opts.resolver.map(Resolver::new),
+            observer: None,
+            no_tls: opts.no_tls,
+            no_tls_resumption: opts.no_tls_resumption,
+            scan_limit: None,
+            trace_probes: opts.trace_probes,
+            best_match: opts.best_match,
         }
     }
 }
 
+// A random version 4 uuid
+fn new_run_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn parse_nonzero(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err("must be greater than 0".into()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// A number of seconds, or a number with a ms, s, m or h suffix
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("invalid duration {}", s))?;
+    let multiplier: u64 = match unit {
+        "ms" => return Ok(Duration::from_millis(n)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("invalid duration unit {}, use ms, s, m or h", unit)),
+    };
+    match n.checked_mul(multiplier) {
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("duration {} is too long", s)),
+    }
+}
+
+// A number of bytes, or a number with a K, M or G suffix for powers of 1024
+fn parse_size(s: &str) -> Result<u64, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: u64 = n.parse().map_err(|_| format!("invalid size {}", s))?;
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "K" | "k" => 1024,
+        "M" | "m" => 1024 * 1024,
+        "G" | "g" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size unit {}, use K, M or G", unit)),
+    };
+    match n.checked_mul(multiplier) {
+        Some(0) => Err("must be greater than 0".into()),
+        Some(size) => Ok(size),
+        None => Err(format!("size {} is too large", s)),
+    }
+}
+
+fn parse_intensity(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n <= 9 => Ok(n),
+        Ok(_) => Err("must be from 0 to 9".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Warn when max concurrent scans won't fit in the soft open file limit, since
+// every in flight scan holds a socket. Optionally raise the soft limit up to
+// the hard limit first.
+#[cfg(unix)]
+fn check_fd_limit(max_concurrent_scans: usize, raise: bool) {
+    // file descriptors needed beyond one per scan, for stdio, output and probe files
+    const FD_HEADROOM: libc::rlim_t = 32;
+
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        tracing::warn!(
+            "failed to read open file limit: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+
+    let needed = max_concurrent_scans as libc::rlim_t + FD_HEADROOM;
+    if let (true, Some(raised)) = (raise, raised_fd_limit(&limit, needed)) {
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        } else {
+            tracing::warn!(
+                "failed to raise open file limit: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+
+    tracing::info!(
+        "max concurrent scans {}, open file limit {}",
+        max_concurrent_scans,
+        limit.rlim_cur
+    );
+    if limit.rlim_cur < needed {
+        tracing::warn!(
+            "max concurrent scans {} exceeds open file limit {}, expect too many open files errors",
+            max_concurrent_scans,
+            limit.rlim_cur
+        );
+    }
+}
+
+// The limit with its soft limit raised toward needed, never past the hard
+// limit. None when the soft limit is already enough or can't go higher.
+#[cfg(unix)]
+fn raised_fd_limit(limit: &libc::rlimit, needed: libc::rlim_t) -> Option<libc::rlimit> {
+    let raised = std::cmp::min(needed, limit.rlim_max);
+    (raised > limit.rlim_cur).then_some(libc::rlimit {
+        rlim_cur: raised,
+        rlim_max: limit.rlim_max,
+    })
+}
+
+#[cfg(not(unix))]
+fn check_fd_limit(max_concurrent_scans: usize, _raise: bool) {
+    tracing::info!("max concurrent scans {}", max_concurrent_scans);
+}
+
 const MAX_BUFFERED_RESULTS: usize = 10000;
-async fn run(opts: Opts) -> Result<(), Box<dyn Error>> {
-    let start = Instant::now();
-    let service_probes = read_service_probes_file(&opts.probes_file);
-    tracing::info!("loaded service probes in {}", start.elapsed().as_secs_f64());
+// A spinner rather than a bar, since targets stream in and the total isn't
+// known up front
+fn progress_bar(opts: &Opts) -> ProgressBar {
+    if !opts.progress || opts.log_file.is_some() || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(
+        ProgressStyle::with_template("{spinner} {pos} targets scanned, {per_sec} [{elapsed}]")
+            .expect("invalid progress template"),
+    );
+    bar.enable_steady_tick(Duration::from_millis(100));
+    bar
+}
 
-    let f = io::stdin();
+// Log lines go through the progress bar so they're printed above it instead
+// of being drawn over
+struct ProgressWriter(ProgressBar);
 
-    let mut rdr = csv_async::AsyncReaderBuilder::new()
-        .has_headers(false)
-        .create_deserializer(f);
+impl std::io::Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.suspend(|| std::io::stderr().write(buf))
+    }
 
-    let f = &opts.out_file.as_ref().unwrap();
-    let f = File::create(&f).await?;
-    let writer = io::BufWriter::new(f);
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
 
-    let (tx, rx) = mpsc::channel(MAX_BUFFERED_RESULTS);
-    let writer_task = tokio::spawn(async move { write_results(writer, rx).await });
+async fn run(mut opts: Opts, progress: ProgressBar) -> Result<(), Box<dyn Error>> {
+    let start = Instant::now();
+    let run_id = opts.run_id.get_or_insert_with(new_run_id).clone();
+    tracing::info!("run id {}", run_id);
+    check_fd_limit(opts.max_concurrent_scans, opts.raise_fd_limit);
+    if let Some(interface) = &opts.interface {
+        check_interface(interface)?;
+    }
+    let (probes_file, service_probes) = match (&opts.probes_url, &opts.probes_file) {
+        (Some(url), _) => {
+            let cache = opts.probes_url_cache.as_deref();
+            let timeout = opts.probes_url_timeout.unwrap_or(DEFAULT_FETCH_TIMEOUT);
+            let service_probes =
+                read_service_probes_url(url, timeout, cache, &opts.parse_options()).await?;
+            (url, service_probes)
+        }
+        (None, Some(f)) => match &opts.probes_cache {
+            Some(cache) => (
+                f,
+                read_service_probes_cached(f, cache, &opts.parse_options())?,
+            ),
+            None => (
+                f,
+                read_service_probes_file_with_options(f, &opts.parse_options())?,
+            ),
+        },
+        (None, None) => unreachable!("probes file is required"),
+    };
+    let compare_probes = match &opts.compare_probes {
+        Some(f) => Some(ProbeSet {
+            name: f.clone(),
+            probes: read_service_probes_file_with_options(f, &opts.parse_options())?,
+        }),
+        None => None,
+    };
+    tracing::info!("loaded service probes in {}", start.elapsed().as_secs_f64());
+    if let Some(f) = &opts.skipped_lines_file {
+        let mut records = vec![];
+        for skipped in &service_probes.skipped_lines {
+            serde_json::to_writer(&mut records, skipped)?;
+            records.push(b'\n');
+        }
+        std::fs::write(f, records)?;
+    }
+    if service_probes.skipped_matches > 0 {
+        tracing::warn!(
+            "skipped {} match lines that failed to parse",
+            service_probes.skipped_matches
+        );
+    }
 
-    let records = rdr.deserialize::<Target>();
+    if let Some(f) = &opts.retry_from {
+        check_retry_from(f, opts.out_file.as_deref())?;
+    }
+    let mut sinks = open_sinks(opts.sink_options()).await?;
+    let retry_targets = match &opts.retry_from {
+        Some(f) => Some(read_retry_targets(f, &opts.retry_errors, &mut sinks).await?),
+        None => None,
+    };
 
-    let targets = records.filter_map(|record| async move {
-        match record {
-            Ok(target) => Some(target),
-            Err(e) => {
-                tracing::warn!("failed to parse input {:?}", e);
-                None
-            }
+    let (tx, rx) = mpsc::channel(MAX_BUFFERED_RESULTS);
+    let rx = match &opts.unmatched_dir {
+        Some(dir) => collect_unmatched(rx, UnmatchedCorpus::open(dir)?),
+        None => rx,
+    };
+    let (sort, group_by_host) = (opts.sort, opts.group_by_host);
+    let summary_file = opts.summary_file.clone();
+    let coverage_file = opts.coverage_file.clone();
+    let live = Arc::new(LiveStats::default());
+    let summary = ScanSummary {
+        run_id: Some(run_id),
+        // each target gets a result per probe set
+        probe_set: opts.compare_probes.is_some().then(|| probes_file.clone()),
+        coverage: coverage_file
+            .as_ref()
+            .map(|_| SignatureCoverage::new(&service_probes)),
+        live: Some(live.clone()),
+        ..Default::default()
+    };
+    let stats_on_signal = print_stats_on_signal(live, progress.clone());
+    let writer_progress = progress.clone();
+    let writer_task = tokio::spawn(async move {
+        if group_by_host {
+            write_host_results(sinks, rx, writer_progress, summary).await
+        } else if sort {
+            write_sorted_results(sinks, rx, writer_progress, summary).await
+        } else {
+            write_results(sinks, rx, writer_progress, summary).await
         }
     });
 
-    start_scan(targets, service_probes, tx, opts.into()).await;
-    let n_targets = writer_task.await??;
+    let mut targets = match retry_targets {
+        Some(targets) => stream::iter(targets).boxed_local(),
+        None => read_targets(io::stdin(), opts.input_format),
+    };
+    let scope = Scope {
+        allow: opts.allow.clone(),
+        deny: opts.deny.clone(),
+    };
+    if !scope.is_empty() {
+        targets = in_scope(targets, scope);
+    }
+    if opts.randomize {
+        targets = shuffle(targets, opts.randomize_window).boxed_local();
+    }
+    let deadline_reached = Rc::new(Cell::new(false));
+    if let Some(max_runtime) = opts.max_runtime {
+        let deadline = tokio::time::Instant::from_std(start + max_runtime);
+        targets = until_deadline(targets, deadline, deadline_reached.clone());
+    }
+    match compare_probes {
+        Some(compare_probes) => {
+            let probes = ProbeSet {
+                name: probes_file.clone(),
+                probes: service_probes,
+            };
+            let probe_sets = vec![probes, compare_probes];
+            start_scan_probe_sets(targets, probe_sets, tx, opts.into()).await
+        }
+        None => start_scan(targets, service_probes, tx, opts.into()).await,
+    }
+    let mut summary = writer_task.await??;
+    if let Some(task) = stats_on_signal {
+        task.abort();
+    }
+    progress.finish_and_clear();
+    summary.deadline_reached = deadline_reached.get();
+    if summary.deadline_reached {
+        tracing::warn!("reached the max runtime, targets left unscanned");
+    }
 
     let duration = start.elapsed();
     tracing::info!(
         "scanned {} targets in {} seconds",
-        n_targets,
+        summary.results,
         duration.as_secs_f64()
     );
+    log_summary(&summary);
+    if let Some(f) = summary_file {
+        std::fs::write(&f, serde_json::to_vec_pretty(&summary)?)?;
+    }
+    if let (Some(f), Some(coverage)) = (coverage_file, &summary.coverage) {
+        tracing::info!(
+            "{} of {} signatures never matched",
+            coverage.never_matched().count(),
+            coverage.signatures.len()
+        );
+        std::fs::write(&f, serde_json::to_vec_pretty(coverage)?)?;
+    }
 
     Ok(())
 }
 
-async fn write_results<T>(
-    mut writer: BufWriter<T>,
+// Print the stats so far to stderr on every SIGUSR1, for a look at a long
+// scan's progress without the progress bar
+#[cfg(unix)]
+fn print_stats_on_signal(stats: Arc<LiveStats>, progress: ProgressBar) -> Option<JoinHandle<()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            tracing::warn!("failed to listen for SIGUSR1, no stats on request: {}", e);
+            return None;
+        }
+    };
+    Some(tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            let snapshot = stats.snapshot();
+            progress.suspend(|| eprintln!("{}", snapshot));
+        }
+    }))
+}
+
+#[cfg(not(unix))]
+fn print_stats_on_signal(_: Arc<LiveStats>, _: ProgressBar) -> Option<JoinHandle<()>> {
+    None
+}
+
+// Add the unmatched responses of results on their way to the writer to the
+// corpus. A corpus that fails to be written to is given up on, the results
+// still go through.
+fn collect_unmatched(
     mut rx: mpsc::Receiver<RadarOutput>,
-) -> io::Result<u64>
+    corpus: UnmatchedCorpus,
+) -> mpsc::Receiver<RadarOutput> {
+    let (tx, collected) = mpsc::channel(MAX_BUFFERED_RESULTS);
+    tokio::spawn(async move {
+        let mut corpus = Some(corpus);
+        let mut added = 0;
+        while let Some(result) = rx.recv().await {
+            let result = match corpus.take() {
+                // the corpus writes files, so it's added to off the runtime
+                Some(mut c) => {
+                    let (c, result, written) = tokio::task::spawn_blocking(move || {
+                        let written = c.add(&result);
+                        (c, result, written)
+                    })
+                    .await
+                    .expect("adding to the unmatched corpus panicked");
+                    match written {
+                        Ok(n) => {
+                            added += n;
+                            corpus = Some(c);
+                        }
+                        Err(e) => tracing::error!(
+                            "failed to write to the unmatched corpus, no longer writing to it: {}",
+                            e
+                        ),
+                    }
+                    result
+                }
+                None => result,
+            };
+            if tx.send(result).await.is_err() {
+                break;
+            }
+        }
+        tracing::info!("added {} unmatched responses to the corpus", added);
+    });
+    collected
+}
+
+// Drop and log the targets outside the scope
+fn in_scope(
+    targets: LocalBoxStream<'static, Target>,
+    scope: Scope,
+) -> LocalBoxStream<'static, Target> {
+    targets
+        .filter(move |target| {
+            let excluded = scope.excludes(target);
+            if let Some(reason) = excluded {
+                tracing::warn!("skipping {}:{}, {}", target.ip, target.port, reason);
+            }
+            futures::future::ready(excluded.is_none())
+        })
+        .boxed_local()
+}
+
+// Stop taking targets at the deadline, setting reached if any were left.
+// Scans already started still finish.
+fn until_deadline(
+    targets: LocalBoxStream<'static, Target>,
+    deadline: tokio::time::Instant,
+    reached: Rc<Cell<bool>>,
+) -> LocalBoxStream<'static, Target> {
+    let timer = async move {
+        tokio::time::sleep_until(deadline).await;
+        reached.set(true);
+    };
+    targets.take_until(timer).boxed_local()
+}
+
+// Read targets in the given format, logging and skipping malformed input
+fn read_targets<R>(reader: R, format: InputFormat) -> LocalBoxStream<'static, Target>
 where
-    T: AsyncWriteExt + Unpin,
+    R: AsyncRead + Unpin + Send + Sync + 'static,
 {
-    let mut n = 0;
-    while let Some(result) = rx.recv().await {
-        writer.write_all(&serde_json::to_vec(&result)?).await?;
-        writer.write_all(b"\n").await?;
-        writer.flush().await?;
-        n += 1;
+    if format == InputFormat::Csv {
+        // rows may leave off the trailing probe column
+        let records = csv_async::AsyncReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .create_deserializer(reader)
+            .into_deserialize::<Target>();
+        return records
+            .filter_map(|record| async move {
+                match record {
+                    Ok(target) => Some(target),
+                    Err(e) => {
+                        tracing::warn!("failed to parse input {:?}", e);
+                        None
+                    }
+                }
+            })
+            // a row with a dashed range is a target for each address in it
+            .flat_map(|target| {
+                let targets = expand_range(target).unwrap_or_else(|e| {
+                    tracing::warn!("failed to parse input: {}", e);
+                    Box::new(std::iter::empty())
+                });
+                stream::iter(targets)
+            })
+            .boxed_local();
     }
-    Ok(n)
+
+    let lines = io::BufReader::new(reader).lines();
+    stream::unfold(lines, |mut lines| async move {
+        match lines.next_line().await {
+            Ok(Some(line)) => Some((line, lines)),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::warn!("failed to read input {:?}", e);
+                None
+            }
+        }
+    })
+    .flat_map(move |line| {
+        let targets = match format {
+            InputFormat::MasscanJson => parse_masscan_line(&line),
+            _ => parse_zmap_line(&line).map(|target| target.into_iter().collect()),
+        };
+        let targets = targets.unwrap_or_else(|e| {
+            tracing::warn!("failed to parse input {}: {}", line, e);
+            vec![]
+        });
+        stream::iter(targets)
+    })
+    .boxed_local()
+}
+
+// Opening the output truncates it, so it can't also be the previous output
+// the targets to retry are read from
+fn check_retry_from(retry_from: &str, out_file: Option<&str>) -> io::Result<()> {
+    let same = match (std::fs::canonicalize(retry_from), out_file) {
+        (Ok(retry_from), Some(out_file)) => std::fs::canonicalize(out_file)
+            .map(|out_file| out_file == retry_from)
+            .unwrap_or(false),
+        _ => false,
+    };
+    if same {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--retry-from {} is also the output file, write the retried results elsewhere",
+                retry_from
+            ),
+        ));
+    }
+    Ok(())
+}
+
+// Read a previous run's output, writing the records that aren't retried
+// straight to the sinks and returning the targets to scan again
+async fn read_retry_targets(
+    path: &str,
+    kinds: &[ErrorKind],
+    sinks: &mut Sinks,
+) -> io::Result<Vec<Target>> {
+    let mut lines = io::BufReader::new(File::open(path).await?).lines();
+    let mut targets = vec![];
+    let mut kept = 0;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_retry_line(&line, kinds) {
+            Ok(RetryRecord::Retry(target)) => targets.push(target),
+            Ok(RetryRecord::Keep(record)) => {
+                sinks.write(&record).await?;
+                kept += 1;
+            }
+            Err(e) => tracing::warn!("failed to parse previous result {}: {}", line, e),
+        }
+    }
+    sinks.flush().await?;
+    tracing::info!(
+        "retrying {} targets from {}, keeping {} results",
+        targets.len(),
+        path,
+        kept
+    );
+    Ok(targets)
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<LogFormat, Self::Err> {
+        match input {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "unknown log format {}, expected text or json",
+                input
+            )),
+        }
+    }
+}
+
+// Logs go to the log file, appending, or else to stderr above the progress bar
+fn log_subscriber(
+    opts: &Opts,
+    progress: ProgressBar,
+) -> io::Result<Box<dyn tracing::Subscriber + Send + Sync>> {
+    let writer = match &opts.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            BoxMakeWriter::new(std::sync::Mutex::new(file))
+        }
+        None => BoxMakeWriter::new(move || ProgressWriter(progress.clone())),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_ansi(opts.log_file.is_none());
+    Ok(match opts.log_format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.json().finish()),
+    })
+}
+
+// Services shown in the summary logged at the end of a scan
+const SUMMARY_TOP_SERVICES: usize = 10;
+
+fn log_summary(summary: &ScanSummary) {
+    tracing::info!(
+        "{} open, {} detected, {:.1}% detection rate",
+        summary.open,
+        summary.detected,
+        summary.detection_rate * 100.0
+    );
+    let top: Vec<String> = summary
+        .top_services(SUMMARY_TOP_SERVICES)
+        .iter()
+        .map(|(service, count)| format!("{} {}", service, count))
+        .collect();
+    if !top.is_empty() {
+        tracing::info!("top services: {}", top.join(", "));
+    }
+    let errors: Vec<String> = summary
+        .errors
+        .iter()
+        .map(|(kind, count)| format!("{} {}", kind, count))
+        .collect();
+    if !errors.is_empty() {
+        tracing::info!("errors: {}", errors.join(", "));
+    }
+}
+
+// Print the services the probes file can detect, one a line
+fn list_services(probes_file: &str, options: &ParseOptions) -> Result<(), Box<dyn Error>> {
+    let probes = read_service_probes_file_with_options(probes_file, options)?;
+    for service in probes.services() {
+        println!("{}", service);
+    }
+    Ok(())
+}
+
+fn diff_results(old: &str, new: &str) -> Result<(), Box<dyn Error>> {
+    use std::io::Write;
+
+    let read = |f: &str| RunResults::read(std::io::BufReader::new(std::fs::File::open(f)?));
+    let (old, new) = (read(old)?, read(new)?);
+    let entries = diff(&old, &new);
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    for entry in &entries {
+        serde_json::to_writer(&mut out, entry)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    let count = |change| entries.iter().filter(|e| e.change == change).count();
+    tracing::info!(
+        "{} added, {} removed, {} changed",
+        count(Change::Added),
+        count(Change::Removed),
+        count(Change::Changed)
+    );
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
     let opts: Opts = Opts::parse();
-    run(opts).await.expect("fail");
+    let progress = progress_bar(&opts);
+    log_subscriber(&opts, progress.clone())
+        .expect("failed to open log file")
+        .init();
+    match opts.command {
+        Some(Command::Services { probes_file, parse }) => {
+            list_services(&probes_file, &parse.parse_options()).expect("fail")
+        }
+        Some(Command::BuildCache {
+            probes_file,
+            cache_file,
+            parse,
+        }) => {
+            let options = parse.parse_options();
+            let probes = build_cache(&probes_file, &cache_file, &options).expect("fail");
+            let count = probes.tcp_probes.len() + probes.udp_probes.len();
+            tracing::info!("wrote {} probes to {}", count, cache_file);
+        }
+        Some(Command::Diff { old, new }) => diff_results(&old, &new).expect("fail"),
+        None => run(opts, progress).await.expect("fail"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    #[tokio::test]
+    async fn test_read_retry_targets() {
+        let path = std::env::temp_dir().join(format!("radar-retry-{}.jsonl", std::process::id()));
+        let previous = [
+            r#"{"target": {"ip": "10.0.0.1", "domain": null, "port": 80}, "error": "deadline has elapsed"}"#,
+            r#"{"target": {"ip": "10.0.0.2", "domain": null, "port": 80}, "error": "Connection refused (os error 111)"}"#,
+            r#"{"target": {"ip": "10.0.0.3", "domain": null, "port": 80}, "error": null}"#,
+        ];
+        std::fs::write(&path, previous.join("\n")).unwrap();
+
+        let (writer, mut reader) = io::duplex(4096);
+        let mut sinks = Sinks::new(false);
+        sinks.push("kept", writer);
+        let targets = read_retry_targets(
+            path.to_str().unwrap(),
+            &[radar::input::ErrorKind::Timeout],
+            &mut sinks,
+        )
+        .await
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        drop(sinks);
+
+        let ips: Vec<&str> = targets.iter().map(|t| t.ip.as_str()).collect();
+        assert_eq!(ips, vec!["10.0.0.1"]);
+        let mut kept = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut kept)
+            .await
+            .unwrap();
+        assert_eq!(kept.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_check_retry_from() {
+        let dir = std::env::temp_dir().join(format!("radar-retry-from-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let previous = dir.join("out.jsonl");
+        std::fs::write(&previous, "").unwrap();
+        let previous = previous.to_str().unwrap();
+
+        assert!(check_retry_from(previous, None).is_ok());
+        let other = dir.join("retried.jsonl");
+        assert!(check_retry_from(previous, other.to_str()).is_ok());
+        // the same file by another path
+        let same = dir.join(".").join("out.jsonl");
+        let e = check_retry_from(previous, same.to_str()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_new_run_id() {
+        let id = new_run_id();
+        let groups: Vec<usize> = id.split('-').map(str::len).collect();
+        assert_eq!(groups, vec![8, 4, 4, 4, 12]);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+        assert_eq!(&id[14..15], "4");
+        assert_ne!(id, new_run_id());
+    }
+
+    #[test]
+    fn test_parse_nonzero() {
+        assert_eq!(parse_nonzero("1"), Ok(1));
+        assert_eq!(parse_nonzero("4096"), Ok(4096));
+        assert!(parse_nonzero("0").is_err());
+        assert!(parse_nonzero("ten").is_err());
+        assert!(parse_nonzero("-1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_raised_fd_limit() {
+        let limit = |rlim_cur, rlim_max| libc::rlimit { rlim_cur, rlim_max };
+        let raised = |limit: libc::rlimit, needed| {
+            raised_fd_limit(&limit, needed).map(|l| (l.rlim_cur, l.rlim_max))
+        };
+        assert_eq!(raised(limit(1024, 4096), 2000), Some((2000, 4096)));
+        // clamped to the hard limit
+        assert_eq!(raised(limit(1024, 4096), 10_000), Some((4096, 4096)));
+        // enough already, or no room to raise it
+        assert_eq!(raised(limit(1024, 4096), 512), None);
+        assert_eq!(raised(limit(4096, 4096), 10_000), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("3m"), Ok(3 * 1024 * 1024));
+        assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("1T").is_err());
+        // past u64 once multiplied, rather than wrapping or panicking
+        let e = parse_size("17179869184G").unwrap_err();
+        assert!(e.contains("too large"), "{}", e);
+        assert!(parse_size(&format!("{}K", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_duration("1d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("9999999999999999h").is_err());
+        assert!(parse_duration("999999999999999999m").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_until_deadline() {
+        let target = |port| Target::new("10.0.0.1", port);
+
+        // targets stop at the deadline
+        let reached = Rc::new(Cell::new(false));
+        let targets = stream::iter(vec![target(1)])
+            .chain(stream::pending())
+            .boxed_local();
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(50);
+        let taken: Vec<Target> = until_deadline(targets, deadline, reached.clone())
+            .collect()
+            .await;
+        assert_eq!(taken, vec![target(1)]);
+        assert!(reached.get());
+
+        // targets that run out first don't reach it
+        let reached = Rc::new(Cell::new(false));
+        let targets = stream::iter(vec![target(1), target(2)]).boxed_local();
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+        let taken: Vec<Target> = until_deadline(targets, deadline, reached.clone())
+            .collect()
+            .await;
+        assert_eq!(taken.len(), 2);
+        assert!(!reached.get());
+    }
+
+    #[test]
+    fn test_json_log_file() {
+        let path = std::env::temp_dir().join(format!("radar-log-{}.json", std::process::id()));
+        let opts = Opts::try_parse_from([
+            "radar",
+            "-p",
+            "probes",
+            "--tcp",
+            "--log-format",
+            "json",
+            "--log-file",
+            path.to_str().unwrap(),
+        ])
+        .unwrap();
+        let subscriber = log_subscriber(&opts, ProgressBar::hidden()).unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(port = 22, "scanned");
+        });
+
+        let logs = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logs.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "scanned");
+        assert_eq!(line["fields"]["port"], 22);
+
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_targets_range() {
+        let input: &[u8] = b"10.0.0.1-3,,80\nweb-1.example.com,,443\n10.0.0.9-1,,80\n";
+        let targets: Vec<Target> = read_targets(input, InputFormat::Csv).collect().await;
+        let targets: Vec<(&str, u16)> = targets.iter().map(|t| (t.ip.as_str(), t.port)).collect();
+        // the backwards range is skipped
+        assert_eq!(
+            targets,
+            [
+                ("10.0.0.1", 80),
+                ("10.0.0.2", 80),
+                ("10.0.0.3", 80),
+                ("web-1.example.com", 443)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transport_required() {
+        let e = Opts::try_parse_from(["radar", "-p", "probes"]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::MissingRequiredArgument);
+
+        let opts = Opts::try_parse_from(["radar", "-p", "probes", "--udp"]).unwrap();
+        let config: ScanConfig = opts.into();
+        assert!(!config.tcp && config.udp);
+
+        let opts = Opts::try_parse_from(["radar", "services", "-p", "probes"]).unwrap();
+        assert!(opts.command.is_some());
+    }
+
+    #[test]
+    fn test_list_services() {
+        let path = std::env::temp_dir().join(format!("radar-services-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "Probe TCP NULL q||\nmatch ftp m/(^220/\nmatch ssh m/^SSH/\n",
+        )
+        .unwrap();
+        let f = path.to_str().unwrap();
+
+        // a bad pattern is an error, not a panic, unless told to skip it
+        let args = ["radar", "services", "-p", f];
+        let Some(Command::Services { parse, .. }) = Opts::try_parse_from(args).unwrap().command
+        else {
+            panic!("not the services command");
+        };
+        assert!(list_services(f, &parse.parse_options()).is_err());
+        let args = ["radar", "services", "-p", f, "--lenient"];
+        let Some(Command::Services { parse, .. }) = Opts::try_parse_from(args).unwrap().command
+        else {
+            panic!("not the services command");
+        };
+        assert!(list_services(f, &parse.parse_options()).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_probes_url_conflicts_with_probes_file() {
+        let args = [
+            "radar",
+            "-p",
+            "probes",
+            "--probes-url",
+            "http://127.0.0.1/",
+            "--tcp",
+        ];
+        let e = Opts::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn test_null_fallback_opt_in() {
+        let opts = Opts::try_parse_from(["radar", "-p", "probes", "--tcp"]).unwrap();
+        let config: ScanConfig = opts.into();
+        assert!(!config.null_fallback);
+
+        let args = ["radar", "-p", "probes", "--tcp", "--null-fallback"];
+        let config: ScanConfig = Opts::try_parse_from(args).unwrap().into();
+        assert!(config.null_fallback);
+    }
 }
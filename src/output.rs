@@ -1,35 +1,593 @@
+use crate::certificate::CertificateInfo;
 use crate::error::*;
+use crate::input::ErrorKind;
 use crate::scan::*;
+use crate::serviceprobes::parse::match_directive::{parse_version_info, VersionInfo};
 use crate::serviceprobes::*;
 use base64::encode;
-use serde::Serialize;
-use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, SecondsFormat};
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RadarOutput {
     pub target: Target,
-    pub timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<TransportProtocol>,
+    pub timestamp: Timestamp,
+    /// The run's label, the same on every result of the run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// The probe set scanned with, when each target is scanned with several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_set: Option<String>,
     pub tls: Option<bool>,
+    /// Set when a tls handshake completed, whatever was found behind it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_handshake: Option<bool>,
+    /// Set when the scan was told not to try tls
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_disabled: Option<bool>,
+    /// Application data read inside a tls session, base64
     pub tls_response: Option<String>,
-    pub tls_service_match: Option<Match>,
+    pub tls_service_match: Option<OutputMatch>,
+    /// What the service answered over plaintext, base64
     pub response: Option<String>,
-    pub service_match: Option<Match>,
+    pub service_match: Option<OutputMatch>,
+    /// Set when service_match came from a softmatch line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soft_match: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_soft_match: Option<bool>,
+    /// The tls records a tls service answered the plaintext probes with, base64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_records: Option<String>,
     pub error: Option<String>,
     pub tls_error: Option<String>,
+    /// The server's certificate, from a handshake that sent no probes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_certificate: Option<CertificateInfo>,
+    /// false when the service inside tls was left unprobed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_inner_attempted: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<ScanPhase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_state: Option<PortState>,
+    /// Set when the peer closed the connection before sending anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closed_without_banner: Option<bool>,
+    /// Set when the probes were run again after finding nothing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_detection_retried: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_expected_ports: Option<Vec<u16>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_probe_expected_ports: Option<Vec<u16>>,
+    /// Rarity of the probe that matched, when it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_rarity: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_probe_rarity: Option<usize>,
+    /// The probe that drew response, when nothing matched it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_probe: Option<String>,
+    /// Set when response was cut down to the max stored response size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_response_truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_records_truncated: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_ascii: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_response_ascii: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_response_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_records_ascii: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_records_hex: Option<String>,
+    /// Set only on the records added for each probe sent when tracing probes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_attempt: Option<ProbeAttempt>,
+    /// Probes sent to the target when tracing probes, written as records
+    #[serde(skip)]
+    pub probe_attempts: Vec<ProbeAttempt>,
+}
+
+/// A match as it's written in results, the Match it came from without the
+/// compiled regex, so results can be read back
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputMatch {
+    pub service: String,
+    pub pattern: String,
+    pub pattern_options: String,
+    pub version_info: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// version_info split into product, version and the rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<VersionInfo>,
+}
+
+impl OutputMatch {
+    // The version info fields, as split before the captures went in, or
+    // split now for a match read back from results written without them
+    fn version_fields(&self) -> Option<VersionInfo> {
+        self.fields
+            .clone()
+            .or_else(|| parse_version_info(&self.version_info))
+    }
+
+    // Whether the version info says anything, leaving out empty fields
+    fn has_version(&self) -> bool {
+        let Some(fields) = self.version_fields() else {
+            return !self.version_info.trim().is_empty();
+        };
+        let named = [
+            &fields.product,
+            &fields.version,
+            &fields.info,
+            &fields.hostname,
+            &fields.os,
+            &fields.device_type,
+        ];
+        named.iter().any(|field| is_named(field.as_deref()))
+            || fields.cpe.iter().any(|cpe| is_named(Some(cpe)))
+    }
+
+    // How many of the product, version and info are named, and one more for
+    // a cpe
+    fn specificity(&self) -> usize {
+        let Some(fields) = self.version_fields() else {
+            return 0;
+        };
+        let named = [&fields.product, &fields.version, &fields.info];
+        named
+            .iter()
+            .filter(|field| is_named(field.as_deref()))
+            .count()
+            + usize::from(fields.cpe.iter().any(|cpe| is_named(Some(cpe))))
+    }
+}
+
+fn is_named(field: Option<&str>) -> bool {
+    field.is_some_and(|field| !field.trim().is_empty())
+}
+
+impl From<Match> for OutputMatch {
+    fn from(m: Match) -> OutputMatch {
+        OutputMatch {
+            service: m.service,
+            pattern: m.pattern,
+            pattern_options: m.pattern_options,
+            version_info: m.version_info,
+            id: m.id,
+            fields: m.fields,
+        }
+    }
+}
+
+/// One probe sent while scanning a target
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeAttempt {
+    pub probe: String,
+    pub tls: bool,
+    pub bytes_read: usize,
+    pub matched: bool,
+    /// base64 of what was read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ProbeAttempt {
+    pub fn new(probe: &str, tls: bool, r: &Result<DetectionInner, RadarError>) -> ProbeAttempt {
+        let mut attempt = ProbeAttempt {
+            probe: probe.to_string(),
+            tls,
+            bytes_read: 0,
+            matched: false,
+            response: None,
+            service: None,
+            error: None,
+        };
+        match r {
+            Ok(d) => {
+                attempt.bytes_read = base64::decode(&d.response).map_or(0, |r| r.len());
+                attempt.matched = true;
+                attempt.response = Some(d.response.clone());
+                attempt.service = Some(d.service_match.service.clone());
+            }
+            Err(RadarError::NoDetection(response)) => {
+                attempt.bytes_read = response.len();
+                attempt.response = Some(encode(response));
+            }
+            Err(e) => attempt.error = Some(e.to_string()),
+        }
+        attempt
+    }
+}
+
+/// How well a result says what runs on its target, from worst to best
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchRank {
+    None,
+    /// a softmatch line matched, naming the service but not its version
+    Soft,
+    /// a match line matched
+    Hard,
+    /// a match line with version info matched, naming the product
+    HardWithVersion,
+}
+
+/// State of a tcp port, as nmap reports it, from how connecting to it went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortState {
+    /// the connection was accepted
+    Open,
+    /// the connection was accepted but nothing was read back
+    OpenNoBanner,
+    /// the connection was refused
+    Closed,
+    /// the connection timed out or failed some other way
+    Filtered,
+}
+
+impl PortState {
+    /// The port state a scan that failed with this error points to
+    pub fn from_error(e: &RadarError) -> PortState {
+        match e {
+            RadarError::NoDetection(response) if response.is_empty() => PortState::OpenNoBanner,
+            RadarError::NoDetection(_) | RadarError::Tls(_) | RadarError::Certificate(_) => {
+                PortState::Open
+            }
+            // connect timeouts are io errors, so this was a read
+            RadarError::Elapsed(_) => PortState::OpenNoBanner,
+            RadarError::Io(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused => PortState::Closed,
+                // the peer hung up on an established connection
+                io::ErrorKind::ConnectionReset
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof => PortState::Open,
+                _ => PortState::Filtered,
+            },
+            RadarError::Proxy { .. } => PortState::Filtered,
+        }
+    }
+}
+
+/// Which pass of a two phase scan produced a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScanPhase {
+    /// quick pass with the common probes
+    Detect,
+    /// full pass over targets found open by the detect pass
+    Version,
+}
+
+/// When a result was produced, serialized according to its format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub millis: u64,
+    pub format: TimestampFormat,
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time before unix epoch")
+            .as_millis() as u64;
+        Timestamp {
+            millis,
+            format: TimestampFormat::default(),
+        }
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.format {
+            TimestampFormat::Unix => serializer.serialize_u64(self.millis / 1000),
+            TimestampFormat::UnixMs => serializer.serialize_u64(self.millis),
+            TimestampFormat::Rfc3339 => {
+                let t = DateTime::from_timestamp_millis(self.millis as i64)
+                    .expect("timestamp out of range");
+                serializer.serialize_str(&t.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+        }
+    }
+}
+
+// Unix timestamps read back at or above this are taken to be in milliseconds.
+// As seconds it's past the year 5000, as milliseconds it's 1973.
+const UNIX_MS_THRESHOLD: u64 = 100_000_000_000;
+
+// Read back in any of the formats, which the timestamp then keeps
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl<'de> Visitor<'de> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a unix timestamp or an rfc3339 string")
+            }
+
+            fn visit_u64<E: de::Error>(self, n: u64) -> Result<Timestamp, E> {
+                Ok(if n >= UNIX_MS_THRESHOLD {
+                    Timestamp {
+                        millis: n,
+                        format: TimestampFormat::UnixMs,
+                    }
+                } else {
+                    Timestamp {
+                        millis: n * 1000,
+                        format: TimestampFormat::Unix,
+                    }
+                })
+            }
+
+            fn visit_i64<E: de::Error>(self, n: i64) -> Result<Timestamp, E> {
+                let n = u64::try_from(n).map_err(|_| E::custom("timestamp before unix epoch"))?;
+                self.visit_u64(n)
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Timestamp, E> {
+                let t = DateTime::parse_from_rfc3339(s).map_err(E::custom)?;
+                let millis = u64::try_from(t.timestamp_millis())
+                    .map_err(|_| E::custom("timestamp before unix epoch"))?;
+                Ok(Timestamp {
+                    millis,
+                    format: TimestampFormat::Rfc3339,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// whole seconds since the unix epoch
+    #[default]
+    Unix,
+    /// milliseconds since the unix epoch
+    UnixMs,
+    /// rfc3339 string in utc with millisecond precision
+    Rfc3339,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<TimestampFormat, Self::Err> {
+        match input {
+            "unix" => Ok(TimestampFormat::Unix),
+            "unix_ms" => Ok(TimestampFormat::UnixMs),
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            _ => Err(format!(
+                "unknown timestamp format {}, expected unix, unix_ms or rfc3339",
+                input
+            )),
+        }
+    }
+}
+
+/// Every result for one host, the record written per host when grouping
+/// results by host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostOutput {
+    pub ip: String,
+    pub results: Vec<RadarOutput>,
+}
+
+impl HostOutput {
+    /// Group results by their target's ip, with the hosts ordered by address
+    /// and each host's results by port
+    pub fn group(mut results: Vec<RadarOutput>) -> Vec<HostOutput> {
+        // stable, so probe attempt records stay ahead of their result
+        results.sort_by(|a, b| a.target.cmp_by_address(&b.target));
+        let mut hosts: Vec<HostOutput> = vec![];
+        for result in results {
+            match hosts.last_mut() {
+                Some(host) if host.ip == result.target.ip => host.results.push(result),
+                _ => hosts.push(HostOutput {
+                    ip: result.target.ip.clone(),
+                    results: vec![result],
+                }),
+            }
+        }
+        hosts
+    }
+}
+
+/// Encoding of the records written out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// one JSON object per line
+    #[default]
+    Json,
+    /// MessagePack maps, each preceded by its length in bytes as a 4 byte big
+    /// endian integer
+    Msgpack,
+    /// CBOR maps, each preceded by its length in bytes as a 4 byte big endian
+    /// integer
+    Cbor,
+    /// Splunk HTTP Event Collector events, one JSON object per line with the
+    /// record as its event and the record's timestamp as its time
+    Hec,
+}
+
+// The envelope of a record sent to a Splunk HTTP Event Collector
+#[derive(Serialize)]
+struct HecEvent {
+    /// seconds since the unix epoch, to the millisecond
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<f64>,
+    sourcetype: &'static str,
+    event: serde_json::Value,
+}
+
+impl HecEvent {
+    fn new<R: Serialize>(record: &R) -> io::Result<HecEvent> {
+        let event = serde_json::to_value(record)?;
+        // a record without a timestamp is given the time it's received
+        let time = event
+            .get("timestamp")
+            .and_then(|t| Timestamp::deserialize(t).ok())
+            .map(|t| t.millis as f64 / 1000.0);
+        Ok(HecEvent {
+            time,
+            sourcetype: "radar",
+            event,
+        })
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<OutputFormat, Self::Err> {
+        match input {
+            "json" => Ok(OutputFormat::Json),
+            "msgpack" => Ok(OutputFormat::Msgpack),
+            "cbor" => Ok(OutputFormat::Cbor),
+            "hec" => Ok(OutputFormat::Hec),
+            _ => Err(format!(
+                "unknown output format {}, expected json, msgpack, cbor or hec",
+                input
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Encode one record with its framing, a trailing newline for JSON and
+    /// HEC or a length prefix for the binary formats. pretty indents JSON
+    /// over several lines and is ignored by the others.
+    pub fn encode<R: Serialize>(&self, record: &R, pretty: bool) -> io::Result<Vec<u8>> {
+        let mut encoded = match self {
+            OutputFormat::Json if pretty => serde_json::to_vec_pretty(record)?,
+            OutputFormat::Json => serde_json::to_vec(record)?,
+            OutputFormat::Hec => serde_json::to_vec(&HecEvent::new(record)?)?,
+            // fields are written by name since empty optional fields are
+            // left out
+            OutputFormat::Msgpack => rmp_serde::to_vec_named(record).map_err(io::Error::other)?,
+            OutputFormat::Cbor => {
+                let mut encoded = vec![];
+                ciborium::into_writer(record, &mut encoded).map_err(io::Error::other)?;
+                encoded
+            }
+        };
+        if matches!(self, OutputFormat::Json | OutputFormat::Hec) {
+            encoded.push(b'\n');
+            return Ok(encoded);
+        }
+        let len = u32::try_from(encoded.len()).map_err(io::Error::other)?;
+        let mut framed = len.to_be_bytes().to_vec();
+        framed.append(&mut encoded);
+        Ok(framed)
+    }
+}
+
+/// Time spent in each stage of a scan, summed over every probe attempted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Timings {
+    pub connect_ms: u64,
+    pub tls_ms: u64,
+    pub read_ms: u64,
+    pub total_ms: u64,
+    /// Smoothed round trip time of the direct tcp connects, in microseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_rtt_us: Option<u64>,
+}
+
+impl Timings {
+    /// Fold a connect's round trip time into the smoothed one, weighting
+    /// each new connect by an eighth as tcp does
+    pub fn observe_connect_rtt(&mut self, rtt: Duration) {
+        let rtt = rtt.as_micros() as u64;
+        self.connect_rtt_us = Some(match self.connect_rtt_us {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+    }
+
+    pub fn connect_rtt(&self) -> Option<Duration> {
+        self.connect_rtt_us.map(Duration::from_micros)
+    }
 }
 
 impl RadarOutput {
-    fn new(target: Target, timestamp: u64) -> RadarOutput {
+    fn new(target: Target, timestamp: Timestamp) -> RadarOutput {
         RadarOutput {
             target,
+            transport: None,
             timestamp,
+            label: None,
+            run_id: None,
+            probe_set: None,
             tls: None,
+            tls_handshake: None,
+            tls_disabled: None,
             tls_response: None,
             tls_service_match: None,
             response: None,
             service_match: None,
+            soft_match: None,
+            tls_soft_match: None,
+            tls_records: None,
             error: None,
             tls_error: None,
+            tls_certificate: None,
+            tls_inner_attempted: None,
+            timings: None,
+            phase: None,
+            port_state: None,
+            closed_without_banner: None,
+            no_detection_retried: None,
+            probe_expected_ports: None,
+            tls_probe_expected_ports: None,
+            probe_rarity: None,
+            tls_probe_rarity: None,
+            response_probe: None,
+            response_truncated: None,
+            tls_response_truncated: None,
+            tls_records_truncated: None,
+            response_ascii: None,
+            response_hex: None,
+            tls_response_ascii: None,
+            tls_response_hex: None,
+            tls_records_ascii: None,
+            tls_records_hex: None,
+            probe_attempt: None,
+            probe_attempts: vec![],
         }
     }
 }
@@ -43,51 +601,96 @@ impl RadarOutput {
         tls_wrapped_detection: DetectionInner,
     ) {
         self.tls = Some(true);
-        self.response = Some(detection.response);
-        self.service_match = Some(detection.service_match);
+        self.tls_handshake = Some(true);
+        self.set_plaintext_detection(detection);
         self.tls_response = Some(tls_wrapped_detection.response);
-        self.tls_service_match = Some(tls_wrapped_detection.service_match);
+        self.tls_service_match = Some(tls_wrapped_detection.service_match.into());
+        self.tls_soft_match = tls_wrapped_detection.soft.then_some(true);
+        self.tls_probe_expected_ports = tls_wrapped_detection.probe_ports;
+        self.tls_probe_rarity = tls_wrapped_detection.probe_rarity;
     }
 
     // successful detection of a tls service, and error attempting to detect
-    // tls wrapped service
-    fn update_detection_with_tls_error(&mut self, detection: DetectionInner, e: RadarError) {
+    // tls wrapped service, after a handshake or not
+    fn update_detection_with_tls_error(
+        &mut self,
+        detection: DetectionInner,
+        e: RadarError,
+        tls_handshake: bool,
+    ) {
         self.tls = Some(true);
-        // this will be some kind of tls response
-        self.response = Some(detection.response);
-        self.service_match = Some(detection.service_match);
-        match e {
-            RadarError::NoDetection(ref r) => self.tls_response = Some(encode(r)),
-            _ => (),
+        self.set_plaintext_detection(detection);
+        self.tls_handshake = tls_handshake.then_some(true);
+        if let RadarError::NoDetection(ref r) = e {
+            self.tls_response = Some(encode(r))
         }
         self.tls_error = Some(e.to_string());
     }
 
+    // a tls service's certificate, with the service inside it left unprobed
+    fn update_detection_certificate(&mut self, d: DetectionCertificate) {
+        self.tls = Some(true);
+        self.tls_inner_attempted = Some(false);
+        if let Some(detection) = d.detection {
+            self.set_plaintext_detection(detection);
+        }
+        match d.certificate {
+            Ok(certificate) => {
+                self.tls_handshake = Some(true);
+                self.tls_certificate = Some(certificate);
+            }
+            Err(e) => {
+                self.tls_handshake = matches!(e, RadarError::Certificate(_)).then_some(true);
+                self.tls_error = Some(e.to_string());
+            }
+        }
+    }
+
     fn update_detection_without_tls(&mut self, d: DetectionInner) {
         self.tls = Some(false);
-        self.response = Some(d.response);
-        self.service_match = Some(d.service_match);
+        self.set_plaintext_detection(d);
+    }
+
+    // the detection from the plaintext probes, which for a tls service
+    // matched the server's tls records. A plaintext probe answered with tls
+    // records is detected as ssl.
+    fn set_plaintext_detection(&mut self, d: DetectionInner) {
+        if d.service_match.service.starts_with("ssl") {
+            self.tls_records = Some(d.response);
+        } else {
+            self.response = Some(d.response);
+        }
+        self.service_match = Some(d.service_match.into());
+        self.soft_match = d.soft.then_some(true);
+        self.probe_expected_ports = d.probe_ports;
+        self.probe_rarity = d.probe_rarity;
+    }
+
+    // nothing detected in plaintext, and successful detection of a service
+    // when connecting with tls directly
+    fn update_detection_only_tls(&mut self, d: DetectionInner) {
+        self.tls = Some(true);
+        self.tls_handshake = Some(true);
+        self.tls_response = Some(d.response);
+        self.tls_service_match = Some(d.service_match.into());
+        self.tls_soft_match = d.soft.then_some(true);
+        self.tls_probe_expected_ports = d.probe_ports;
+        self.tls_probe_rarity = d.probe_rarity;
     }
 
     fn update_error(&mut self, e: RadarError) {
         self.tls = Some(false);
-        match e {
-            RadarError::NoDetection(ref r) => self.response = Some(encode(r)),
-            _ => (),
+        if let RadarError::NoDetection(ref r) = e {
+            self.response = Some(encode(r));
         }
         self.error = Some(e.to_string());
     }
 }
 
-impl From<(Target, Result<Detection, RadarError>)> for RadarOutput {
-    fn from(target_result: (Target, Result<Detection, RadarError>)) -> RadarOutput {
-        let (target, r) = target_result;
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("System time before unix epoch")
-            .as_secs();
-
-        let mut output = RadarOutput::new(target, timestamp);
+impl RadarOutput {
+    /// Build the output record for a target from the result of scanning it
+    pub fn from_detection(target: Target, r: Result<Detection, RadarError>) -> RadarOutput {
+        let mut output = RadarOutput::new(target, Timestamp::now());
 
         match r {
             Ok(detection) => match detection {
@@ -95,14 +698,1052 @@ impl From<(Target, Result<Detection, RadarError>)> for RadarOutput {
                     Ok(tls_wrapped_detection) => {
                         output.update_detection_with_tls(detection.detection, tls_wrapped_detection)
                     }
-                    Err(e) => output.update_detection_with_tls_error(detection.detection, e),
+                    Err(e) => output.update_detection_with_tls_error(
+                        detection.detection,
+                        e,
+                        detection.tls_handshake,
+                    ),
                 },
                 Detection::DetectionWithoutTls(detection) => {
                     output.update_detection_without_tls(detection)
                 }
+                Detection::DetectionOnlyTls(detection) => {
+                    output.update_detection_only_tls(detection)
+                }
+                Detection::DetectionCertificate(detection) => {
+                    output.update_detection_certificate(detection)
+                }
             },
-            Err(e) => output.update_error(e),
+            Err(e) => {
+                output.port_state = Some(PortState::from_error(&e));
+                output.update_error(e)
+            }
         }
+        output.port_state.get_or_insert(PortState::Open);
         output
     }
 }
+
+impl RadarOutput {
+    /// Add readable copies of the responses next to the base64 ones, which
+    /// stay the lossless form. ascii keeps printable characters and escapes
+    /// the rest, hex is lowercase with no separators.
+    pub fn add_response_encodings(&mut self, ascii: bool, hex: bool) {
+        let decode = |response: &Option<String>| {
+            response
+                .as_ref()
+                .and_then(|response| base64::decode(response).ok())
+        };
+        let response = decode(&self.response);
+        let tls_response = decode(&self.tls_response);
+        let tls_records = decode(&self.tls_records);
+        if ascii {
+            self.response_ascii = response.as_deref().map(escape_ascii);
+            self.tls_response_ascii = tls_response.as_deref().map(escape_ascii);
+            self.tls_records_ascii = tls_records.as_deref().map(escape_ascii);
+        }
+        if hex {
+            self.response_hex = response.as_deref().map(encode_hex);
+            self.tls_response_hex = tls_response.as_deref().map(encode_hex);
+            self.tls_records_hex = tls_records.as_deref().map(encode_hex);
+        }
+    }
+}
+
+impl RadarOutput {
+    /// Cut the stored responses down to at most max bytes, marking the ones
+    /// that were cut. Matching has already run on the full responses.
+    pub fn truncate_responses(&mut self, max: usize) {
+        fn truncate(response: &mut Option<String>, max: usize) -> Option<bool> {
+            let mut decoded = base64::decode(response.as_ref()?).ok()?;
+            if decoded.len() <= max {
+                return None;
+            }
+            decoded.truncate(max);
+            *response = Some(encode(decoded));
+            Some(true)
+        }
+        self.response_truncated = truncate(&mut self.response, max);
+        self.tls_response_truncated = truncate(&mut self.tls_response, max);
+        self.tls_records_truncated = truncate(&mut self.tls_records, max);
+    }
+}
+
+fn escape_ascii(response: &[u8]) -> String {
+    response.escape_ascii().to_string()
+}
+
+fn encode_hex(response: &[u8]) -> String {
+    response.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl RadarOutput {
+    /// Build the output record for a target from the result of only
+    /// connecting to it
+    pub fn from_connect(target: Target, r: Result<(), RadarError>) -> RadarOutput {
+        let mut output = RadarOutput::new(target, Timestamp::now());
+        let state = match r {
+            Ok(()) => PortState::Open,
+            Err(RadarError::Io(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                PortState::Closed
+            }
+            Err(_) => PortState::Filtered,
+        };
+        output.port_state = Some(state);
+        output.error = r.err().map(|e| e.to_string());
+        output
+    }
+}
+
+impl RadarOutput {
+    // The match that says what the target runs, the service inside tls when
+    // there is one rather than the ssl match around it, and whether it's soft
+    fn answer(&self) -> Option<(&OutputMatch, bool)> {
+        match (&self.tls_service_match, &self.service_match) {
+            (Some(m), _) => Some((m, self.tls_soft_match == Some(true))),
+            (None, Some(m)) => Some((m, self.soft_match == Some(true))),
+            (None, None) => None,
+        }
+    }
+
+    /// A hard match with version info ranks over a hard match, which ranks
+    /// over a soft match, which ranks over no match. The match ranked is the
+    /// service inside tls for tls services.
+    pub fn match_rank(&self) -> MatchRank {
+        match self.answer() {
+            None => MatchRank::None,
+            Some((_, true)) => MatchRank::Soft,
+            Some((m, false)) if !m.has_version() => MatchRank::Hard,
+            Some((_, false)) => MatchRank::HardWithVersion,
+        }
+    }
+
+    /// Of the results for one target, as from scanning it over several
+    /// transports or phases, keep only the one with the best
+    /// match_rank. Between equal ranks the one naming more of the product,
+    /// version, info and cpe is the more specific and wins, then the first.
+    /// Probe attempt records are kept, ahead of the result.
+    pub fn best_match(outputs: Vec<RadarOutput>) -> Vec<RadarOutput> {
+        let (mut kept, results): (Vec<_>, Vec<_>) = outputs
+            .into_iter()
+            .partition(|output| output.probe_attempt.is_some());
+        let specificity = |output: &RadarOutput| {
+            let named = output.answer().map_or(0, |(m, _)| m.specificity());
+            (output.match_rank(), named)
+        };
+        let mut best: Option<RadarOutput> = None;
+        for output in results {
+            if best
+                .as_ref()
+                .is_none_or(|best| specificity(&output) > specificity(best))
+            {
+                best = Some(output);
+            }
+        }
+        kept.extend(best);
+        kept
+    }
+
+    /// A record for each probe attempt traced, followed by this result
+    pub fn with_probe_attempt_records(mut self) -> Vec<RadarOutput> {
+        let attempts = std::mem::take(&mut self.probe_attempts);
+        let mut records: Vec<RadarOutput> = attempts
+            .into_iter()
+            .map(|attempt| {
+                let mut record = RadarOutput::new(self.target.clone(), self.timestamp);
+                record.transport = self.transport.clone();
+                record.probe_attempt = Some(attempt);
+                record
+            })
+            .collect();
+        records.push(self);
+        records
+    }
+}
+
+/// Totals over the results of a scan, for a quick picture of what was found
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScanSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    /// With several probe sets, the one whose results are counted, so each
+    /// target is counted once
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_set: Option<String>,
+    pub results: u64,
+    /// results with an open port, whether or not the service was detected
+    pub open: u64,
+    pub detected: u64,
+    /// detected over open, 0 when nothing was open
+    pub detection_rate: f64,
+    /// results by detected service, the tls wrapped one for tls services
+    pub services: BTreeMap<String, u64>,
+    /// results by the kind of error they ended with
+    pub errors: BTreeMap<String, u64>,
+    /// Matches by signature, when tallying coverage
+    #[serde(skip)]
+    pub coverage: Option<SignatureCoverage>,
+    /// Counts kept for reading while the scan runs, when reporting on it
+    #[serde(skip)]
+    pub live: Option<Arc<LiveStats>>,
+    /// Set when the run hit its max runtime and stopped taking targets
+    pub deadline_reached: bool,
+}
+
+impl ScanSummary {
+    /// Count a result. Probe attempt records are left out, and so are the
+    /// results of probe sets other than the one counted.
+    pub fn add(&mut self, output: &RadarOutput) {
+        let other_set = self.probe_set.is_some() && output.probe_set != self.probe_set;
+        if output.probe_attempt.is_some() || other_set {
+            return;
+        }
+        self.results += 1;
+        let service = output
+            .tls_service_match
+            .as_ref()
+            .or(output.service_match.as_ref());
+        let open = matches!(
+            output.port_state,
+            Some(PortState::Open) | Some(PortState::OpenNoBanner)
+        );
+        if open || service.is_some() {
+            self.open += 1;
+        }
+        if let Some(service) = service {
+            self.detected += 1;
+            *self.services.entry(service.service.clone()).or_default() += 1;
+        }
+        if let Some(error) = &output.error {
+            let kind = ErrorKind::of(error).to_string();
+            *self.errors.entry(kind).or_default() += 1;
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add(output);
+        }
+        if let Some(live) = &self.live {
+            live.add(service.is_some(), output.error.is_some());
+        }
+        self.detection_rate = self.detected as f64 / self.open.max(1) as f64;
+    }
+
+    /// The most detected services, most first
+    pub fn top_services(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut services: Vec<(&str, u64)> = self
+            .services
+            .iter()
+            .map(|(service, count)| (service.as_str(), *count))
+            .collect();
+        services.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        services.truncate(n);
+        services
+    }
+}
+
+/// Running counts of a scan's results, updated as they're written and safe
+/// to read from another task meanwhile
+#[derive(Debug)]
+pub struct LiveStats {
+    started: Instant,
+    completed: AtomicU64,
+    detected: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for LiveStats {
+    fn default() -> Self {
+        LiveStats {
+            started: Instant::now(),
+            completed: AtomicU64::new(0),
+            detected: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LiveStats {
+    fn add(&self, detected: bool, error: bool) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if detected {
+            self.detected.fetch_add(1, Ordering::Relaxed);
+        }
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The counts so far. Each is read on its own, so they may be a result
+    /// apart from one another.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let completed = self.completed.load(Ordering::Relaxed);
+        StatsSnapshot {
+            completed,
+            detected: self.detected.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            elapsed_secs: elapsed,
+            rate: match elapsed > 0.0 {
+                true => completed as f64 / elapsed,
+                false => 0.0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StatsSnapshot {
+    pub completed: u64,
+    pub detected: u64,
+    /// results that ended with an error, including no detection
+    pub errors: u64,
+    pub elapsed_secs: f64,
+    /// results completed per second
+    pub rate: f64,
+}
+
+impl fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} completed, {} detected, {} errors, {:.1} per second over {:.0} seconds",
+            self.completed, self.detected, self.errors, self.rate, self.elapsed_secs
+        )
+    }
+}
+
+/// How often each match and softmatch in the probes file matched over a scan,
+/// to find signatures that never fire
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SignatureCoverage {
+    /// in probes file order, including those that never matched
+    pub signatures: Vec<SignatureCount>,
+    #[serde(skip)]
+    index: HashMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SignatureCount {
+    pub id: String,
+    pub probe: String,
+    pub service: String,
+    pub pattern: String,
+    pub pattern_options: String,
+    pub soft: bool,
+    pub matches: u64,
+}
+
+impl SignatureCoverage {
+    /// Every signature in the probes, each matched 0 times
+    pub fn new(service_probes: &ServiceProbes) -> Self {
+        let mut coverage = SignatureCoverage::default();
+        let probes = service_probes
+            .tcp_probes
+            .iter()
+            .chain(service_probes.udp_probes.iter());
+        for service_probe in probes {
+            for (m, soft) in service_probe.signatures() {
+                let id = match &m.id {
+                    Some(id) => id.clone(),
+                    None => continue,
+                };
+                coverage.index.insert(id.clone(), coverage.signatures.len());
+                coverage.signatures.push(SignatureCount {
+                    id,
+                    probe: service_probe.probe.name.clone(),
+                    service: m.service.clone(),
+                    pattern: m.pattern.clone(),
+                    pattern_options: m.pattern_options.clone(),
+                    soft,
+                    matches: 0,
+                });
+            }
+        }
+        coverage
+    }
+
+    /// Count the signatures a result matched, plaintext and tls
+    pub fn add(&mut self, output: &RadarOutput) {
+        let matched = output.service_match.iter().chain(&output.tls_service_match);
+        for id in matched.filter_map(|m| m.id.as_ref()) {
+            if let Some(&i) = self.index.get(id) {
+                self.signatures[i].matches += 1;
+            }
+        }
+    }
+
+    /// The signatures that never matched
+    pub fn never_matched(&self) -> impl Iterator<Item = &SignatureCount> {
+        self.signatures.iter().filter(|s| s.matches == 0)
+    }
+}
+
+impl From<(Target, Result<Detection, RadarError>)> for RadarOutput {
+    fn from(target_result: (Target, Result<Detection, RadarError>)) -> RadarOutput {
+        let (target, r) = target_result;
+        RadarOutput::from_detection(target, r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::serviceprobes::parse::match_directive::parse_match_line;
+
+    fn detection(service: &str, response: &str) -> DetectionInner {
+        let line = format!("match {} m|^{}|", service, response);
+        DetectionInner {
+            response: encode(response),
+            service_match: parse_match_line(&line).expect("failed to parse match line"),
+            probe_ports: None,
+            probe_rarity: None,
+            soft: false,
+            probe_tls: false,
+        }
+    }
+
+    #[test]
+    fn test_timestamp_format() {
+        let mut t = Timestamp {
+            millis: 1_700_000_000_123,
+            format: TimestampFormat::Unix,
+        };
+        assert_eq!(serde_json::to_string(&t).unwrap(), "1700000000");
+        t.format = TimestampFormat::UnixMs;
+        assert_eq!(serde_json::to_string(&t).unwrap(), "1700000000123");
+        t.format = TimestampFormat::Rfc3339;
+        assert_eq!(
+            serde_json::to_string(&t).unwrap(),
+            "\"2023-11-14T22:13:20.123Z\""
+        );
+        // read back in the format written
+        let read: Timestamp = serde_json::from_str("\"2023-11-14T22:13:20.123Z\"").unwrap();
+        assert_eq!(read, t);
+        let read: Timestamp = serde_json::from_str("1700000000").unwrap();
+        assert_eq!(
+            (read.millis, read.format),
+            (1_700_000_000_000, TimestampFormat::Unix)
+        );
+        let read: Timestamp = serde_json::from_str("1700000000123").unwrap();
+        assert_eq!(
+            (read.millis, read.format),
+            (1_700_000_000_123, TimestampFormat::UnixMs)
+        );
+    }
+
+    #[test]
+    fn test_host_output_group() {
+        let result = |ip, port| RadarOutput::from_connect(Target::new(ip, port), Ok(()));
+        let results = vec![
+            result("10.0.0.10", 22),
+            result("10.0.0.2", 443),
+            result("10.0.0.10", 21),
+            result("10.0.0.2", 80),
+        ];
+        let hosts = HostOutput::group(results);
+        let grouped: Vec<(&str, Vec<u16>)> = hosts
+            .iter()
+            .map(|h| {
+                (
+                    h.ip.as_str(),
+                    h.results.iter().map(|r| r.target.port).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            grouped,
+            vec![("10.0.0.2", vec![80, 443]), ("10.0.0.10", vec![21, 22])]
+        );
+    }
+
+    #[test]
+    fn test_radar_output_round_trip() {
+        let d = Detection::DetectionWithoutTls(detection("ftp", "220 vsftpd"));
+        let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        output.timings = Some(Timings::default());
+
+        for format in [
+            TimestampFormat::Unix,
+            TimestampFormat::UnixMs,
+            TimestampFormat::Rfc3339,
+        ] {
+            output.timestamp.format = format;
+            let json = serde_json::to_value(&output).unwrap();
+            let read: RadarOutput = serde_json::from_value(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&read).unwrap(), json);
+            assert_eq!(read.timestamp.format, format);
+        }
+        let read: RadarOutput =
+            serde_json::from_value(serde_json::to_value(&output).unwrap()).unwrap();
+        assert_eq!(read.service_match.unwrap().pattern, "^220 vsftpd");
+        assert_eq!(read.timestamp.millis, output.timestamp.millis);
+
+        // no handshake, no tls_handshake, as in results from before it
+        let json = serde_json::to_value(&output).unwrap();
+        assert!(json.get("tls_handshake").is_none());
+
+        let framed = OutputFormat::Msgpack.encode(&output, false).unwrap();
+        let read: RadarOutput = rmp_serde::from_slice(&framed[4..]).unwrap();
+        assert_eq!(read.port_state, Some(PortState::Open));
+    }
+
+    #[test]
+    fn test_version_info_fields() {
+        let m = parse_match_line(r"match ftp m/^220 ([\w-]+) ([\d.]+)/ p/$1/ v/$2/ cpe:/a:$1/")
+            .unwrap();
+        let service_match = get_match(&m, b"220 vsftpd 3.0.3").unwrap();
+        let d = Detection::DetectionWithoutTls(DetectionInner {
+            service_match,
+            ..detection("ftp", "220 vsftpd 3.0.3")
+        });
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(
+            json["service_match"]["fields"],
+            serde_json::json!({
+                "product": "vsftpd",
+                "version": "3.0.3",
+                "cpe": ["a:vsftpd"],
+            })
+        );
+        let read: RadarOutput = serde_json::from_value(json).unwrap();
+        let fields = read.service_match.unwrap().fields.unwrap();
+        assert_eq!(fields.version.as_deref(), Some("3.0.3"));
+
+        // a match not run against a response has no fields
+        let d = Detection::DetectionWithoutTls(detection("ftp", "220 vsftpd"));
+        let json = serde_json::to_value(RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            Ok(d),
+        ))
+        .unwrap();
+        assert!(json["service_match"].get("fields").is_none());
+    }
+
+    #[test]
+    fn test_from_detection_with_tls() {
+        let d = Detection::DetectionWithTls(DetectionWithTls {
+            detection: detection("ssl", "tls"),
+            tls_wrapped_result: Ok(detection("http", "HTTP")),
+            tls_handshake: true,
+        });
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+
+        assert_eq!(output.target, Target::new("127.0.0.1", 443));
+        assert_eq!(output.tls, Some(true));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert!(output.response.is_none());
+        assert_eq!(output.tls_records, Some(encode("tls")));
+        assert_eq!(output.service_match.unwrap().service, "ssl");
+        assert_eq!(output.tls_response, Some(encode("HTTP")));
+        assert_eq!(output.tls_service_match.unwrap().service, "http");
+        assert!(output.error.is_none());
+        assert!(output.tls_error.is_none());
+    }
+
+    #[test]
+    fn test_from_detection_with_tls_error() {
+        let d = Detection::DetectionWithTls(DetectionWithTls {
+            detection: detection("ssl", "tls"),
+            tls_wrapped_result: Err(RadarError::NoDetection(b"unknown".to_vec())),
+            tls_handshake: true,
+        });
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+
+        assert_eq!(output.tls, Some(true));
+        assert!(output.response.is_none());
+        assert_eq!(output.tls_records, Some(encode("tls")));
+        assert_eq!(output.service_match.unwrap().service, "ssl");
+        assert_eq!(output.tls_response, Some(encode("unknown")));
+        assert!(output.tls_service_match.is_none());
+        assert_eq!(output.tls_error, Some("No Detection".into()));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert!(output.error.is_none());
+
+        let d = Detection::DetectionWithTls(DetectionWithTls {
+            detection: detection("ssl", "tls"),
+            tls_wrapped_result: Err(RadarError::Io(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "reset",
+            ))),
+            tls_handshake: false,
+        });
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        assert_eq!(output.tls, Some(true));
+        assert!(output.tls_handshake.is_none());
+        assert_eq!(output.tls_records, Some(encode("tls")));
+        assert!(output.tls_response.is_none());
+
+        // reset after the handshake went through
+        let d = Detection::DetectionWithTls(DetectionWithTls {
+            detection: detection("ssl", "tls"),
+            tls_wrapped_result: Err(RadarError::Io(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "reset",
+            ))),
+            tls_handshake: true,
+        });
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert!(output.tls_error.is_some());
+    }
+
+    #[test]
+    fn test_from_detection_without_tls() {
+        let d = Detection::DetectionWithoutTls(detection("ftp", "220"));
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+
+        assert_eq!(output.tls, Some(false));
+        assert!(output.tls_handshake.is_none());
+        assert_eq!(output.response, Some(encode("220")));
+        assert!(output.tls_records.is_none());
+        assert_eq!(output.service_match.unwrap().service, "ftp");
+        assert!(output.tls_response.is_none());
+        assert!(output.tls_service_match.is_none());
+        assert!(output.error.is_none());
+        assert!(output.tls_error.is_none());
+    }
+
+    #[test]
+    fn test_from_detection_only_tls() {
+        let d = Detection::DetectionOnlyTls(detection("http", "HTTP"));
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+
+        assert_eq!(output.tls, Some(true));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert!(output.response.is_none());
+        assert!(output.tls_records.is_none());
+        assert!(output.service_match.is_none());
+        assert_eq!(output.tls_response, Some(encode("HTTP")));
+        assert_eq!(output.tls_service_match.unwrap().service, "http");
+        assert!(output.error.is_none());
+    }
+
+    #[test]
+    fn test_from_detection_error() {
+        let e = RadarError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        let output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Err(e));
+
+        assert_eq!(output.tls, Some(false));
+        assert!(output.response.is_none());
+        assert!(output.tls_records.is_none());
+        assert!(output.service_match.is_none());
+        assert_eq!(output.error, Some("refused".into()));
+    }
+
+    #[test]
+    fn test_from_detection_no_detection_keeps_response() {
+        let e = RadarError::NoDetection(b"banner".to_vec());
+        let output: RadarOutput = (Target::new("127.0.0.1", 443), Err(e)).into();
+
+        assert_eq!(output.response, Some(encode("banner")));
+        assert!(output.tls_records.is_none());
+        assert!(output.service_match.is_none());
+        assert_eq!(output.error, Some("No Detection".into()));
+    }
+
+    #[test]
+    fn test_truncate_responses() {
+        let e = RadarError::NoDetection(b"0123456789".to_vec());
+        let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Err(e));
+
+        output.truncate_responses(10);
+        assert_eq!(output.response, Some(encode("0123456789")));
+        assert!(output.response_truncated.is_none());
+
+        output.truncate_responses(4);
+        assert_eq!(output.response, Some(encode("0123")));
+        assert_eq!(output.response_truncated, Some(true));
+        assert!(output.tls_response_truncated.is_none());
+        assert!(output.tls_records_truncated.is_none());
+
+        // the tls records a tls service answered in plaintext
+        let d = Detection::DetectionWithoutTls(detection("ssl", "\x16\x03\x01\x00\x02ab"));
+        let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        output.truncate_responses(3);
+        assert_eq!(output.tls_records, Some(encode("\x16\x03\x01")));
+        assert_eq!(output.tls_records_truncated, Some(true));
+    }
+
+    #[test]
+    fn test_add_response_encodings() {
+        let e = RadarError::NoDetection(b"220 ok\r\n\x00".to_vec());
+        let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Err(e));
+
+        let json = serde_json::to_value(&output).unwrap();
+        assert!(json.get("response_ascii").is_none());
+
+        output.add_response_encodings(true, true);
+        assert_eq!(output.response_ascii, Some("220 ok\\r\\n\\x00".into()));
+        assert_eq!(output.response_hex, Some("323230206f6b0d0a00".into()));
+        assert!(output.tls_response_ascii.is_none());
+        assert!(output.tls_response_hex.is_none());
+        assert!(output.tls_records_ascii.is_none());
+
+        let d = Detection::DetectionWithoutTls(detection("ssl", "\x15\x03\x01"));
+        let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        output.add_response_encodings(true, true);
+        assert_eq!(output.tls_records_ascii, Some("\\x15\\x03\\x01".into()));
+        assert_eq!(output.tls_records_hex, Some("150301".into()));
+        assert!(output.response_ascii.is_none());
+    }
+
+    #[test]
+    fn test_output_format_encode() {
+        let output = RadarOutput::from_connect(Target::new("127.0.0.1", 443), Ok(()));
+
+        let json = OutputFormat::Json.encode(&output, false).unwrap();
+        assert_eq!(json.last(), Some(&b'\n'));
+        assert_eq!(json.iter().filter(|&&b| b == b'\n').count(), 1);
+
+        for format in [OutputFormat::Msgpack, OutputFormat::Cbor] {
+            let framed = format.encode(&output, false).unwrap();
+            let len = u32::from_be_bytes([framed[0], framed[1], framed[2], framed[3]]) as usize;
+            assert_eq!(len, framed.len() - 4);
+            let record: serde_json::Value = match format {
+                OutputFormat::Msgpack => rmp_serde::from_slice(&framed[4..]).unwrap(),
+                _ => ciborium::from_reader(&framed[4..]).unwrap(),
+            };
+            assert_eq!(record["target"]["ip"], "127.0.0.1");
+            assert_eq!(record["port_state"], "open");
+        }
+
+        let mut output = output;
+        output.timestamp = Timestamp {
+            millis: 1_700_000_000_250,
+            format: TimestampFormat::Rfc3339,
+        };
+        let hec = OutputFormat::Hec.encode(&output, true).unwrap();
+        assert_eq!(hec.iter().filter(|&&b| b == b'\n').count(), 1);
+        let event: serde_json::Value = serde_json::from_slice(&hec).unwrap();
+        assert_eq!(event["time"], 1_700_000_000.25);
+        assert_eq!(event["sourcetype"], "radar");
+        assert_eq!(event["event"]["target"]["ip"], "127.0.0.1");
+    }
+
+    #[test]
+    fn test_port_state_from_detection() {
+        let state = |r| RadarOutput::from_detection(Target::new("127.0.0.1", 443), r).port_state;
+        let io_error = |kind| Err(RadarError::Io(io::Error::new(kind, "error")));
+
+        let d = Detection::DetectionWithoutTls(detection("ftp", "220"));
+        assert_eq!(state(Ok(d)), Some(PortState::Open));
+        assert_eq!(
+            state(Err(RadarError::NoDetection(b"?".to_vec()))),
+            Some(PortState::Open)
+        );
+        assert_eq!(
+            state(Err(RadarError::NoDetection(vec![]))),
+            Some(PortState::OpenNoBanner)
+        );
+        assert_eq!(
+            state(io_error(io::ErrorKind::ConnectionRefused)),
+            Some(PortState::Closed)
+        );
+        assert_eq!(
+            state(io_error(io::ErrorKind::TimedOut)),
+            Some(PortState::Filtered)
+        );
+        assert_eq!(
+            state(io_error(io::ErrorKind::ConnectionReset)),
+            Some(PortState::Open)
+        );
+    }
+
+    #[test]
+    fn test_with_probe_attempt_records() {
+        let mut output = RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            Ok(Detection::DetectionWithoutTls(detection("ftp", "220"))),
+        );
+        output.probe_attempts = vec![
+            ProbeAttempt::new("NULL", false, &Err(RadarError::NoDetection(b"?".to_vec()))),
+            ProbeAttempt::new("GenericLines", false, &Ok(detection("ftp", "220"))),
+        ];
+
+        let records = output.with_probe_attempt_records();
+        assert_eq!(records.len(), 3);
+        let null = records[0].probe_attempt.as_ref().unwrap();
+        assert_eq!((null.bytes_read, null.matched), (1, false));
+        let generic = records[1].probe_attempt.as_ref().unwrap();
+        assert_eq!(generic.probe, "GenericLines");
+        assert_eq!((generic.bytes_read, generic.matched), (3, true));
+        assert!(records[1].service_match.is_none());
+        assert!(records[2].probe_attempt.is_none());
+        assert_eq!(records[2].service_match.as_ref().unwrap().service, "ftp");
+    }
+
+    #[test]
+    fn test_match_rank() {
+        let output = |d| RadarOutput::from_detection(Target::new("127.0.0.1", 443), Ok(d));
+        let versioned = |service, response| {
+            let mut d = detection(service, response);
+            d.service_match.version_info = "p/vsftpd/".into();
+            d
+        };
+        let soft = |service, response| DetectionInner {
+            soft: true,
+            ..detection(service, response)
+        };
+
+        let none = RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            Err(RadarError::NoDetection(vec![])),
+        );
+        assert_eq!(none.match_rank(), MatchRank::None);
+        let d = Detection::DetectionWithoutTls(soft("ftp", "220"));
+        assert_eq!(output(d).match_rank(), MatchRank::Soft);
+        let d = Detection::DetectionWithoutTls(detection("ftp", "220"));
+        assert_eq!(output(d).match_rank(), MatchRank::Hard);
+        let d = Detection::DetectionWithoutTls(versioned("ftp", "220"));
+        assert_eq!(output(d).match_rank(), MatchRank::HardWithVersion);
+        // fields the captures left empty say nothing
+        let mut d = detection("ftp", "220");
+        d.service_match.version_info = "p// v//".into();
+        let d = Detection::DetectionWithoutTls(d);
+        assert_eq!(output(d).match_rank(), MatchRank::Hard);
+
+        // a tls service is ranked by the service inside it
+        let d = Detection::DetectionWithTls(DetectionWithTls {
+            detection: versioned("ssl", "\x16"),
+            tls_wrapped_result: Ok(soft("http", "HTTP")),
+            tls_handshake: true,
+        });
+        assert_eq!(output(d).match_rank(), MatchRank::Soft);
+        assert!(MatchRank::HardWithVersion > MatchRank::Hard);
+        assert!(MatchRank::Hard > MatchRank::Soft);
+        assert!(MatchRank::Soft > MatchRank::None);
+    }
+
+    #[test]
+    fn test_best_match() {
+        let output = |service: &str, version_info: &str, soft: bool| {
+            let mut d = detection(service, "x");
+            d.service_match.version_info = version_info.into();
+            d.soft = soft;
+            RadarOutput::from_detection(
+                Target::new("127.0.0.1", 443),
+                Ok(Detection::DetectionWithoutTls(d)),
+            )
+        };
+        let service = |outputs: Vec<RadarOutput>| {
+            let best = RadarOutput::best_match(outputs);
+            assert_eq!(best.len(), 1);
+            best[0].service_match.as_ref().unwrap().service.clone()
+        };
+
+        // hard over soft, whatever the order
+        assert_eq!(
+            service(vec![output("smtp", "", true), output("ftp", "", false)]),
+            "ftp"
+        );
+        assert_eq!(
+            service(vec![output("ftp", "", false), output("smtp", "", true)]),
+            "ftp"
+        );
+        // version info over none, and more of it over less
+        let detailed = output("vsftpd", "p/vsftpd/ v/3.0.3/", false);
+        let bare = output("pure-ftpd", "p/Pure-FTPd/", false);
+        assert_eq!(
+            service(vec![output("ftp", "", false), bare.clone()]),
+            "pure-ftpd"
+        );
+        assert_eq!(service(vec![bare.clone(), detailed.clone()]), "vsftpd");
+        // by the fields named, not how much is written in them
+        let chatty = output(
+            "ftp",
+            "i/anonymous login allowed, see the banner for details/",
+            false,
+        );
+        assert_eq!(service(vec![chatty.clone(), detailed.clone()]), "vsftpd");
+        assert_eq!(service(vec![detailed.clone(), chatty]), "vsftpd");
+        // by the fields split before the captures went in, which may hold
+        // the delimiter, and only those that aren't empty
+        let m = parse_match_line("match ftp m|^220 (\\S+) (\\S+)| p/$1/ v/$2/").unwrap();
+        let mut d = detection("ftp", "x");
+        d.service_match = get_match(&m, b"220 ftp/x 1").unwrap();
+        let captured = RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            Ok(Detection::DetectionWithoutTls(d)),
+        );
+        assert_eq!(service(vec![bare.clone(), captured]), "ftp");
+        let empty = output("empty", "p// v// i//", false);
+        assert_eq!(service(vec![bare.clone(), empty]), "pure-ftpd");
+        // the first of equals
+        assert_eq!(
+            service(vec![output("ftp", "", false), output("smtp", "", false)]),
+            "ftp"
+        );
+        // a match over no match
+        let nothing = RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            Err(RadarError::NoDetection(vec![])),
+        );
+        assert_eq!(service(vec![nothing, output("ftp", "", true)]), "ftp");
+
+        // probe attempt records are kept ahead of the result
+        let mut traced = bare;
+        traced.probe_attempts = vec![ProbeAttempt::new(
+            "NULL",
+            false,
+            &Err(RadarError::NoDetection(b"?".to_vec())),
+        )];
+        let mut outputs = traced.with_probe_attempt_records();
+        outputs.push(output("ftp", "", true));
+        let best = RadarOutput::best_match(outputs);
+        assert_eq!(best.len(), 2);
+        assert!(best[0].probe_attempt.is_some());
+        assert_eq!(best[1].service_match.as_ref().unwrap().service, "pure-ftpd");
+    }
+
+    #[test]
+    fn test_scan_summary() {
+        let mut summary = ScanSummary::default();
+        let results = vec![
+            Ok(Detection::DetectionWithoutTls(detection("ftp", "220"))),
+            Ok(Detection::DetectionWithoutTls(detection("http", "HTTP"))),
+            Ok(Detection::DetectionWithoutTls(detection("http", "HTTP"))),
+            Err(RadarError::NoDetection(b"?".to_vec())),
+            Err(RadarError::Io(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "Connection refused",
+            ))),
+        ];
+        for r in results {
+            summary.add(&RadarOutput::from_detection(
+                Target::new("127.0.0.1", 443),
+                r,
+            ));
+        }
+        let mut attempt = RadarOutput::from_connect(Target::new("127.0.0.1", 443), Ok(()));
+        attempt.probe_attempt = Some(ProbeAttempt::new(
+            "NULL",
+            false,
+            &Ok(detection("ftp", "220")),
+        ));
+        summary.add(&attempt);
+
+        assert_eq!(summary.results, 5);
+        assert_eq!(summary.open, 4);
+        assert_eq!(summary.detected, 3);
+        assert_eq!(summary.detection_rate, 0.75);
+        assert_eq!(summary.top_services(1), vec![("http", 2)]);
+        assert_eq!(summary.errors["no-detection"], 1);
+        assert_eq!(summary.errors["refused"], 1);
+    }
+
+    #[test]
+    fn test_scan_summary_probe_sets() {
+        let mut summary = ScanSummary {
+            probe_set: Some("nmap-service-probes".into()),
+            ..Default::default()
+        };
+        // a target scanned with both sets, detected by the second only
+        let results = [
+            (
+                "nmap-service-probes",
+                Err(RadarError::NoDetection(b"?".to_vec())),
+            ),
+            (
+                "new-probes",
+                Ok(Detection::DetectionWithoutTls(detection("ftp", "220"))),
+            ),
+        ];
+        for (set, r) in results {
+            let mut output = RadarOutput::from_detection(Target::new("127.0.0.1", 443), r);
+            output.probe_set = Some(set.into());
+            summary.add(&output);
+        }
+        assert_eq!(summary.results, 1);
+        assert_eq!(summary.detected, 0);
+        assert_eq!(summary.errors["no-detection"], 1);
+    }
+
+    #[test]
+    fn test_live_stats() {
+        let live = Arc::new(LiveStats::default());
+        let mut summary = ScanSummary {
+            live: Some(live.clone()),
+            ..Default::default()
+        };
+        let results = vec![
+            Ok(Detection::DetectionWithoutTls(detection("ftp", "220"))),
+            Err(RadarError::NoDetection(b"?".to_vec())),
+            Err(RadarError::Io(io::ErrorKind::ConnectionRefused.into())),
+        ];
+        for r in results {
+            summary.add(&RadarOutput::from_detection(
+                Target::new("127.0.0.1", 443),
+                r,
+            ));
+        }
+
+        let snapshot = live.snapshot();
+        assert_eq!(
+            (snapshot.completed, snapshot.detected, snapshot.errors),
+            (3, 1, 2)
+        );
+        assert!(snapshot
+            .to_string()
+            .starts_with("3 completed, 1 detected, 2 errors"));
+    }
+
+    #[test]
+    fn test_signature_coverage() {
+        use crate::serviceprobes::parse::{read_service_probes, ParseOptions};
+
+        let probes = "Probe TCP NULL q||\n\
+                      match ftp m|^220 |\n\
+                      softmatch ssh m|^SSH-|\n\
+                      match smtp m|^220 .*SMTP|\n\
+                      Probe UDP NULL q||\n\
+                      match ftp m|^220 |\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let mut summary = ScanSummary {
+            coverage: Some(SignatureCoverage::new(&probes)),
+            ..Default::default()
+        };
+        let null = &probes.tcp_probes[0];
+        for response in [&b"220 ready"[..], b"220 ready", b"SSH-2.0-OpenSSH"] {
+            let detection = DetectionInner {
+                response: encode(response),
+                service_match: null.check_match(response).unwrap().service_match,
+                probe_ports: None,
+                probe_rarity: None,
+                soft: false,
+                probe_tls: false,
+            };
+            let r = Ok(Detection::DetectionWithoutTls(detection));
+            summary.add(&RadarOutput::from_detection(
+                Target::new("127.0.0.1", 443),
+                r,
+            ));
+        }
+        // matches not from the probes file aren't counted
+        let r = Ok(Detection::DetectionWithoutTls(detection("http", "HTTP")));
+        summary.add(&RadarOutput::from_detection(
+            Target::new("127.0.0.1", 443),
+            r,
+        ));
+
+        let coverage = summary.coverage.unwrap();
+        let counts: Vec<(&str, &str, bool, u64)> = coverage
+            .signatures
+            .iter()
+            .map(|s| (s.id.as_str(), s.service.as_str(), s.soft, s.matches))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("TCP/NULL/1", "ftp", false, 2),
+                ("TCP/NULL/2", "smtp", false, 0),
+                ("TCP/NULL/3", "ssh", true, 1),
+                // a udp probe of the same name is counted apart
+                ("UDP/NULL/1", "ftp", false, 0),
+            ]
+        );
+        let never: Vec<&str> = coverage.never_matched().map(|s| s.id.as_str()).collect();
+        assert_eq!(never, vec!["TCP/NULL/2", "UDP/NULL/1"]);
+    }
+}
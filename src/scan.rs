@@ -1,26 +1,117 @@
 use base64::encode;
-use futures::stream::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use pcre2::bytes::Regex;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
-use tokio::time::timeout;
-use tokio_native_tls::TlsConnector;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_native_tls::{TlsConnector, TlsStream};
+use tokio_socks::tcp::Socks5Stream;
 use tracing::{info, instrument};
 
+use crate::certificate::CertificateInfo;
 use crate::error::*;
 use crate::output::*;
+use crate::serviceprobes::parse::match_directive::parse_version_info;
 use crate::serviceprobes::*;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::marker::Unpin;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Bound;
+use std::sync::{Arc, LazyLock, Mutex};
 use std::time::Duration;
 
 const TIMEOUT: u64 = 5;
+// probes up to this rarity make up the quick first phase of a two phase scan
+const TWO_PHASE_MAX_RARITY: usize = 1;
+// native-tls backends that never resume a client session: openssl keeps no
+// client session cache unless asked to, and native-tls never asks
+const TLS_NEVER_RESUMES: bool = cfg!(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows"
+)));
+pub const DEFAULT_MAX_CONCURRENT_SCANS: usize = 1024;
+// cap on the concatenated responses kept for matching combined responses
+const MAX_COMBINED_RESPONSE: usize = 64 * 1024;
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 1600;
+// how long to wait for more of a response when matching incrementally
+const INCREMENTAL_READ_WAIT_MS: u64 = 500;
+pub const DEFAULT_UDP_RETRANSMITS: usize = 2;
+pub const DEFAULT_UDP_RETRANSMIT_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_RTT_MULTIPLIER: u32 = 20;
+pub const DEFAULT_MIN_READ_TIMEOUT_MS: u64 = 2000;
+pub const DEFAULT_MAX_READ_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_RAMP_UP_START: usize = 64;
+// how often a ramp up lets more scans in
+const RAMP_UP_STEP_MS: u64 = 100;
+// with a per host limit, how many times max_concurrent_scans targets are
+// taken ahead to find ones on hosts with a scan to spare
+const HOST_LIMIT_LOOKAHEAD: usize = 16;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct Target {
     pub ip: String,
     pub domain: Option<String>,
     pub port: u16,
+    /// Only send this probe, by name, rather than every probe
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<String>,
+    /// Name to present in the TLS handshake instead of the domain, to reach
+    /// a name the ip serves without it being the Host or connect address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+}
+
+impl Target {
+    /// A target by ip and port alone, with every probe sent
+    pub fn new(ip: &str, port: u16) -> Target {
+        Target {
+            ip: ip.to_string(),
+            domain: None,
+            port,
+            probe: None,
+            sni: None,
+        }
+    }
+
+    /// Order targets by parsed ip address then port, so 10.0.0.2 sorts before
+    /// 10.0.0.10. Targets whose ip doesn't parse sort last, by string.
+    pub fn cmp_by_address(&self, other: &Target) -> Ordering {
+        let ip = |t: &Target| t.ip.parse::<IpAddr>().ok();
+        let by_ip = match (ip(self), ip(other)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.ip.cmp(&other.ip),
+        };
+        by_ip.then(self.port.cmp(&other.port))
+    }
+
+    /// The server name for the TLS handshake: the sni if set, else the
+    /// domain, else the ip
+    pub fn tls_name(&self) -> &str {
+        self.sni
+            .as_deref()
+            .or(self.domain.as_deref())
+            .unwrap_or(&self.ip)
+    }
+
+    fn wants_probe(&self, service_probe: &ServiceProbe, config: &ScanConfig) -> bool {
+        let intensity = config.version_intensity;
+        self.probe
+            .as_ref()
+            .is_none_or(|name| *name == service_probe.probe.name)
+            && intensity.is_none_or(|i| service_probe.sent_at_intensity(i, self.port))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,21 +119,446 @@ pub struct ScanConfig {
     pub tcp: bool,
     pub udp: bool,
     pub max_concurrent_scans: usize,
+    /// Bytes to wait for before matching a response, 0 for the first read
+    pub min_banner_bytes: usize,
+    /// Times to read more of an unmatched response and match again, per probe
+    pub incremental_match_attempts: Option<usize>,
+    /// Add a Host header naming the target's domain to HTTP probes
+    pub http_host_header: bool,
+    /// Report unmatched HTTP responses as http, from their headers
+    pub http_header_fallback: bool,
+    /// Resends of a UDP probe that got no response
+    pub udp_retransmits: usize,
+    pub udp_retransmit_interval_ms: u64,
+    /// Record time spent connecting, negotiating tls and reading
+    pub timings: bool,
+    /// Rescan what the common probes find with every probe
+    pub two_phase: bool,
+    /// Send the next probe over the previous probe's connection when it's open
+    pub reuse_connections: bool,
+    /// Send every probe at once, each on its own connection
+    pub concurrent_probes: bool,
+    /// Also match other probes' responses against the NULL probe's matches
+    pub null_fallback: bool,
+    /// Local address, or on linux interface name, to scan from
+    pub interface: Option<String>,
+    pub timestamp_format: TimestampFormat,
+    /// Wait this long for the server to speak before writing a probe
+    pub pre_read_ms: Option<u64>,
+    /// Write tcp probe data in chunks of this many bytes
+    pub write_chunk_size: Option<usize>,
+    pub write_chunk_delay_ms: Option<u64>,
+    pub read_buffer_size: usize,
+    /// When no probe matches alone, match the responses concatenated
+    pub match_combined_responses: bool,
+    /// Skip probes rarer than this, the NULL probe always runs
+    pub max_rarity: Option<usize>,
+    /// Skip probes more common than this, the NULL probe always runs
+    pub min_rarity: Option<usize>,
+    /// Pick probes as nmap's `--version-intensity` does, 0 to 9
+    pub version_intensity: Option<usize>,
+    /// Proxies to connect through, picked per target by a hash of it
+    pub socks5_proxies: Vec<String>,
+    /// Add responses with non-printable bytes escaped to the output
+    pub response_ascii: bool,
+    /// Add hex encoded responses to the output
+    pub response_hex: bool,
+    /// Only connect and report the port state, sending no probes
+    pub tcp_connect_only: bool,
+    /// Include the ports the matching probe declares in the output
+    pub probe_expected_ports: bool,
+    /// Most scans in flight against any one ip
+    pub per_host_concurrency: Option<usize>,
+    /// Scan a tls port again over tls when plaintext finds nothing
+    pub tls_on_no_detection: bool,
+    pub tls_ports: Vec<u16>,
+    /// Only tls_ports are tls ports, the probes' sslports aren't
+    pub ignore_ssl_ports: bool,
+    /// Scan tls ports over tls first, and never try tls on other ports
+    pub port_aware_tls: bool,
+    /// Set on every output, to group results by campaign
+    pub label: Option<String>,
+    /// Set on every output, to tell runs apart
+    pub run_id: Option<String>,
+    /// Only handshake with tls services to read their certificate
+    pub tls_cert_only: bool,
+    /// Wait this long and probe again when an open tcp port matched nothing
+    pub no_detection_retry_ms: Option<u64>,
+    /// Base tcp read timeouts on the target's connect round trip time
+    pub adaptive_timeout: Option<AdaptiveTimeout>,
+    /// Grow to max_concurrent_scans rather than starting there
+    pub ramp_up: Option<RampUp>,
+    /// Cut stored responses to this many bytes, matching uses them whole
+    pub max_stored_response: Option<usize>,
+    /// Resolve target names with this DNS server instead of the system's
+    #[serde(skip)]
+    pub resolver: Option<Resolver>,
+    /// Told about each step of every scan
+    #[serde(skip)]
+    pub observer: Option<Arc<dyn ScanObserver>>,
+    /// Never try tls, not even on a service matched as ssl
+    pub no_tls: bool,
+    /// Make every tls handshake a full one, where the tls backend allows
+    pub no_tls_resumption: bool,
+    /// Emit a record for every probe sent ahead of each target's result
+    pub trace_probes: bool,
+    /// Emit only each target's best result, by RadarOutput::match_rank
+    pub best_match: bool,
+    /// Bounds the scans in flight across every start_scan call sharing it
+    #[serde(skip)]
+    pub scan_limit: Option<Arc<Semaphore>>,
+}
+
+/// Callbacks for the steps of a scan, for library users that want their own
+/// logging or metrics. Every method does nothing by default, so implement just
+/// the ones needed. They're called from the scan tasks, so keep them quick.
+pub trait ScanObserver: Send + Sync {
+    /// About to open a tcp connection to the target
+    fn connect_started(&self, _target: &Target) {}
+    /// A tcp connection is up, with tls negotiated when tls is set
+    fn connected(&self, _target: &Target, _tls: bool) {}
+    /// About to send the probe to the target
+    fn probe_sent(&self, _target: &Target, _probe: &Probe) {}
+    /// A response from the target matched a service
+    fn matched(&self, _target: &Target, _service_match: &Match) {}
+}
+
+impl std::fmt::Debug for dyn ScanObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("ScanObserver")
+    }
+}
+
+/// Read timeouts scaled to the network, as a multiple of the smoothed connect
+/// round trip time clamped between a floor and a ceiling. The floor leaves
+/// time for services that think before answering however close they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdaptiveTimeout {
+    pub rtt_multiplier: u32,
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Default for AdaptiveTimeout {
+    fn default() -> Self {
+        Self {
+            rtt_multiplier: DEFAULT_RTT_MULTIPLIER,
+            min_ms: DEFAULT_MIN_READ_TIMEOUT_MS,
+            max_ms: DEFAULT_MAX_READ_TIMEOUT_MS,
+        }
+    }
+}
+
+impl AdaptiveTimeout {
+    /// The ceiling when no round trip time was measured, as through a proxy
+    pub fn read_timeout(&self, rtt: Option<Duration>) -> Duration {
+        let (min, max) = (self.min_ms, self.max_ms);
+        let ms = match rtt {
+            Some(rtt) => (rtt.as_millis() as u64).saturating_mul(self.rtt_multiplier as u64),
+            None => max,
+        };
+        Duration::from_millis(ms.max(min).min(max))
+    }
+}
+
+/// Concurrency grown evenly from start to max_concurrent_scans over
+/// duration_ms, so a big scan's first connections don't all land at once
+/// on the local conntrack table and the network in front of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RampUp {
+    pub start: usize,
+    pub duration_ms: u64,
+}
+
+impl RampUp {
+    fn begin(self, max: usize) -> Ramp {
+        let start = self.start.clamp(1, max.max(1));
+        let semaphore = Arc::new(Semaphore::new(start));
+        let (steps, duration_ms) = (max.saturating_sub(start), self.duration_ms.max(1));
+        let released = semaphore.clone();
+        let task = tokio::spawn(async move {
+            let began = Instant::now();
+            let mut interval = tokio::time::interval(Duration::from_millis(RAMP_UP_STEP_MS));
+            let mut added = 0;
+            while added < steps {
+                interval.tick().await;
+                let elapsed = began.elapsed().as_millis().min(duration_ms as u128);
+                let due = (steps as u128 * elapsed / duration_ms as u128) as usize;
+                released.add_permits(due - added);
+                added = due;
+            }
+        });
+        Ramp { semaphore, task }
+    }
+}
+
+// The permits of a ramp up, added to by a task until there are max of them.
+// The task is stopped when the scan ends before the ramp does.
+struct Ramp {
+    semaphore: Arc<Semaphore>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for Ramp {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl ScanConfig {
+    // Tell the observer, if there is one
+    fn observe(&self, f: impl FnOnce(&dyn ScanObserver)) {
+        if let Some(observer) = &self.observer {
+            f(observer.as_ref())
+        }
+    }
+}
+
+/// A DNS resolver using one name server, cheap to clone and share between
+/// scans
+#[derive(Clone)]
+pub struct Resolver(Arc<TokioAsyncResolver>);
+
+impl Resolver {
+    pub fn new(name_server: SocketAddr) -> Self {
+        Resolver::with_options(name_server, ResolverOpts::default())
+    }
+
+    fn with_options(name_server: SocketAddr, options: ResolverOpts) -> Self {
+        let name_servers =
+            NameServerConfigGroup::from_ips_clear(&[name_server.ip()], name_server.port(), true);
+        let config = ResolverConfig::from_parts(None, vec![], name_servers);
+        Resolver(Arc::new(TokioAsyncResolver::tokio(config, options)))
+    }
+
+    async fn lookup(&self, name: &str) -> io::Result<IpAddr> {
+        let ips = self.0.lookup_ip(name).await.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("failed to resolve {}: {}", name, e),
+            )
+        })?;
+        ips.iter().next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no addresses for {}", name),
+            )
+        })
+    }
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+// The host:port to connect to for a target. A name in the ip column is
+// resolved with the configured resolver, without one it's left for the
+// system resolver.
+async fn target_host(target: &Target, config: &ScanConfig) -> io::Result<String> {
+    match &config.resolver {
+        Some(resolver) if target.ip.parse::<IpAddr>().is_err() => {
+            let ip = resolver.lookup(&target.ip).await?;
+            Ok(SocketAddr::new(ip, target.port).to_string())
+        }
+        _ => Ok(format!("{}:{}", target.ip, target.port)),
+    }
+}
+
+impl ScanConfig {
+    /// The transports to scan each target over, tcp first
+    pub fn transports(&self) -> Vec<TransportProtocol> {
+        let mut transports = vec![];
+        if self.tcp {
+            transports.push(TransportProtocol::TCP);
+        }
+        if self.udp {
+            transports.push(TransportProtocol::UDP);
+        }
+        transports
+    }
+
+    // How long to wait for a response on a connection made with these timings
+    fn read_timeout(&self, timings: &Timings) -> Duration {
+        match &self.adaptive_timeout {
+            Some(adaptive) => adaptive.read_timeout(timings.connect_rtt()),
+            None => Duration::from_secs(TIMEOUT),
+        }
+    }
+
+    fn transport(&self) -> TransportProtocol {
+        if self.udp && !self.tcp {
+            TransportProtocol::UDP
+        } else {
+            TransportProtocol::TCP
+        }
+    }
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            udp: false,
+            max_concurrent_scans: DEFAULT_MAX_CONCURRENT_SCANS,
+            min_banner_bytes: 0,
+            incremental_match_attempts: None,
+            http_host_header: false,
+            http_header_fallback: false,
+            concurrent_probes: false,
+            udp_retransmits: DEFAULT_UDP_RETRANSMITS,
+            udp_retransmit_interval_ms: DEFAULT_UDP_RETRANSMIT_INTERVAL_MS,
+            timings: false,
+            two_phase: false,
+            reuse_connections: false,
+            null_fallback: false,
+            interface: None,
+            timestamp_format: TimestampFormat::default(),
+            pre_read_ms: None,
+            write_chunk_size: None,
+            write_chunk_delay_ms: None,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            match_combined_responses: false,
+            max_rarity: None,
+            min_rarity: None,
+            version_intensity: None,
+            socks5_proxies: vec![],
+            response_ascii: false,
+            response_hex: false,
+            tcp_connect_only: false,
+            probe_expected_ports: false,
+            per_host_concurrency: None,
+            tls_on_no_detection: false,
+            tls_ports: vec![],
+            ignore_ssl_ports: false,
+            port_aware_tls: false,
+            tls_cert_only: false,
+            label: None,
+            run_id: None,
+            no_detection_retry_ms: None,
+            adaptive_timeout: None,
+            ramp_up: None,
+            max_stored_response: None,
+            resolver: None,
+            observer: None,
+            no_tls: false,
+            no_tls_resumption: false,
+            trace_probes: false,
+            best_match: false,
+            scan_limit: None,
+        }
+    }
+}
+
+/// Caps the scans in flight against each host. A host's semaphore is dropped
+/// once no scan holds or waits on it, so the map only holds hosts currently
+/// being scanned.
+struct HostLimiter {
+    limit: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+struct HostPermit {
+    ip: String,
+    semaphore: Arc<Semaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimiter {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    async fn acquire(&self, ip: &str) -> HostPermit {
+        let semaphore = {
+            let mut hosts = self.hosts.lock().expect("host limiter lock poisoned");
+            hosts
+                .entry(ip.to_string())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+                .clone()
+        };
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("host semaphore closed");
+        HostPermit {
+            ip: ip.to_string(),
+            semaphore,
+            permit: Some(permit),
+            hosts: self.hosts.clone(),
+        }
+    }
+
+    #[cfg(test)]
+    fn hosts(&self) -> usize {
+        self.hosts.lock().unwrap().len()
+    }
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let mut hosts = self.hosts.lock().expect("host limiter lock poisoned");
+        self.permit.take();
+        // the map's reference and ours are the last, nobody else is waiting.
+        // Clones are only made under the lock, so this can't race.
+        if Arc::strong_count(&self.semaphore) == 2 {
+            hosts.remove(&self.ip);
+        }
+    }
 }
 
 pub enum Detection {
     DetectionWithoutTls(DetectionInner),
     DetectionWithTls(DetectionWithTls),
+    /// Nothing matched in plaintext, but a tls service was found by
+    /// connecting with tls directly
+    DetectionOnlyTls(DetectionInner),
+    /// A tls service whose certificate was read without probing the service
+    /// inside it
+    DetectionCertificate(DetectionCertificate),
 }
 
 pub struct DetectionInner {
     pub response: String,
     pub service_match: Match,
+    /// ports and sslports of the probe whose match was found, when they're
+    /// output
+    pub probe_ports: Option<Vec<u16>>,
+    /// rarity line of the probe whose match was found
+    pub probe_rarity: Option<usize>,
+    /// found by a softmatch, which names the service but not its version
+    pub soft: bool,
+    /// found over a tls connection its probe required, while scanning in
+    /// plaintext
+    pub probe_tls: bool,
 }
 
 pub struct DetectionWithTls {
     pub detection: DetectionInner,
     pub tls_wrapped_result: Result<DetectionInner, RadarError>,
+    /// a tls handshake went through while scanning for the wrapped service,
+    /// whatever error came after it
+    pub tls_handshake: bool,
+}
+
+pub struct DetectionCertificate {
+    /// The plaintext detection of the tls service, None when a tls port was
+    /// handshaken with directly
+    pub detection: Option<DetectionInner>,
+    pub certificate: Result<CertificateInfo, RadarError>,
+}
+
+/// Probes to scan with, named in the results when each target is scanned
+/// with several sets
+pub struct ProbeSet {
+    pub name: String,
+    pub probes: ServiceProbes,
 }
 
 pub async fn start_scan<S>(
@@ -53,165 +569,3213 @@ pub async fn start_scan<S>(
 ) where
     S: futures::Stream<Item = Target>,
 {
-    let cx = native_tls::TlsConnector::builder()
-        .danger_accept_invalid_certs(true)
-        .danger_accept_invalid_hostnames(true)
-        .use_sni(false)
-        .build()
-        .expect("failed to build tls connector");
-    let cx = tokio_native_tls::TlsConnector::from(cx);
+    scan_targets(targets, vec![(None, probes)], tx, config).await
+}
+
+/// Scan each target with every probe set, to compare one set's detections
+/// with another's. The sets are scanned one after another, each on
+/// connections of its own as if it were the only one, and the results are
+/// tagged with the set's name.
+pub async fn start_scan_probe_sets<S>(
+    targets: S,
+    probe_sets: Vec<ProbeSet>,
+    tx: mpsc::Sender<RadarOutput>,
+    config: ScanConfig,
+) where
+    S: futures::Stream<Item = Target>,
+{
+    let probe_sets = probe_sets
+        .into_iter()
+        .map(|set| (Some(set.name), set.probes))
+        .collect();
+    scan_targets(targets, probe_sets, tx, config).await
+}
+
+async fn scan_targets<S>(
+    targets: S,
+    probe_sets: Vec<(Option<String>, ServiceProbes)>,
+    tx: mpsc::Sender<RadarOutput>,
+    config: ScanConfig,
+) where
+    S: futures::Stream<Item = Target>,
+{
+    let cx = if config.no_tls {
+        None
+    } else {
+        if config.no_tls_resumption && !TLS_NEVER_RESUMES {
+            tracing::warn!(
+                "the tls backend on this platform resumes sessions and can't be told not to, \
+                 ignoring no_tls_resumption"
+            );
+        }
+        Some(scan_tls_connector())
+    };
+
+    // each set with its quick probes, for a two phase scan
+    let probe_sets: Vec<(Option<String>, ServiceProbes, Option<ServiceProbes>)> = probe_sets
+        .into_iter()
+        .map(|(name, probes)| {
+            let probes = if config.min_rarity.is_some() || config.max_rarity.is_some() {
+                let min = config.min_rarity.map_or(Bound::Unbounded, Bound::Included);
+                let max = config.max_rarity.map_or(Bound::Unbounded, Bound::Included);
+                probes.with_rarity((min, max))
+            } else {
+                probes
+            };
+            let quick_probes = if config.two_phase {
+                Some(probes.with_rarity(..=TWO_PHASE_MAX_RARITY))
+            } else {
+                None
+            };
+            (name, probes, quick_probes)
+        })
+        .collect();
+
+    // scan each target once per transport, with a config for just that one
+    let transports: Vec<(TransportProtocol, ScanConfig)> = config
+        .transports()
+        .into_iter()
+        .map(|protocol| {
+            let config = ScanConfig {
+                tcp: protocol == TransportProtocol::TCP,
+                udp: protocol == TransportProtocol::UDP,
+                ..config.clone()
+            };
+            (protocol, config)
+        })
+        .collect();
+    if transports.is_empty() {
+        tracing::error!("neither tcp nor udp scanning is enabled, nothing to scan");
+    }
 
+    let host_limiter = config.per_host_concurrency.map(HostLimiter::new);
+    // targets waiting on their host don't hold one of the scan slots, which
+    // other hosts' targets can run in
+    let slots = host_limiter
+        .as_ref()
+        .map(|_| Arc::new(Semaphore::new(config.max_concurrent_scans)));
+    let slots = &slots;
+    let (transports, probe_sets, cx) = (&transports, &probe_sets, cx.as_ref());
+    let host_limiter = &host_limiter;
+    let ramp = config
+        .ramp_up
+        .map(|ramp_up| ramp_up.begin(config.max_concurrent_scans));
+    let ramp = &ramp;
+    let scan_limit = &config.scan_limit;
+    let best_match = config.best_match;
     let detections = targets
-        .map(|target| async { scan(target, &probes, &cx).await })
-        .buffered(config.max_concurrent_scans);
+        .filter(|target| futures::future::ready(has_valid_port(target)))
+        .map(|target| async move {
+            let _permit = match host_limiter {
+                Some(limiter) => Some(limiter.acquire(&target.ip).await),
+                None => None,
+            };
+            // taken after the host permit, so targets queued behind others
+            // on their host don't hold a slot
+            let _ramp_permit = match ramp {
+                Some(ramp) => Some(
+                    ramp.semaphore
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("ramp up semaphore closed"),
+                ),
+                None => None,
+            };
+            let _slot = match slots {
+                Some(slots) => Some(
+                    slots
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("scan slot semaphore closed"),
+                ),
+                None => None,
+            };
+            let _scan_permit = match scan_limit {
+                Some(limit) => Some(
+                    limit
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("scan limit semaphore closed"),
+                ),
+                None => None,
+            };
+            let mut outputs = vec![];
+            for (name, probes, quick_probes) in probe_sets {
+                let mut set_outputs = vec![];
+                for (protocol, config) in transports {
+                    if probes.excludes(protocol, target.port) {
+                        info!(
+                            "skipping {}:{} {:?}, port is excluded",
+                            target.ip, target.port, protocol
+                        );
+                        continue;
+                    }
+                    let target = target.clone();
+                    match quick_probes {
+                        _ if config.tcp_connect_only && *protocol == TransportProtocol::TCP => {
+                            set_outputs.push(connect_scan(target, config).await)
+                        }
+                        Some(quick_probes) => set_outputs
+                            .extend(scan_two_phase(target, quick_probes, probes, cx, config).await),
+                        None => set_outputs.push(scan(target, probes, cx, config).await),
+                    }
+                }
+                // probe attempt records go ahead of the result they led to
+                let set_outputs = set_outputs
+                    .into_iter()
+                    .flat_map(RadarOutput::with_probe_attempt_records)
+                    .collect();
+                // the best of each set, so the sets can still be compared
+                let set_outputs = match best_match {
+                    true => RadarOutput::best_match(set_outputs),
+                    false => set_outputs,
+                };
+                outputs.extend(set_outputs.into_iter().map(|mut output| {
+                    output.probe_set = name.clone();
+                    output
+                }));
+            }
+            outputs
+        });
+    // results come in the order the targets did, unless a per host limit
+    // has targets waiting on their host while later ones finish
+    let detections = match host_limiter {
+        Some(_) => detections
+            .buffer_unordered(
+                config
+                    .max_concurrent_scans
+                    .saturating_mul(HOST_LIMIT_LOOKAHEAD),
+            )
+            .left_stream(),
+        None => detections
+            .buffered(config.max_concurrent_scans)
+            .right_stream(),
+    };
 
+    let (label, run_id) = (&config.label, &config.run_id);
     detections
-        .for_each(|d| async {
-            tx.send(d).await.expect("failed to send");
+        .for_each(|outputs| async {
+            for mut d in outputs {
+                d.label = label.clone();
+                d.run_id = run_id.clone();
+                tx.send(d).await.expect("failed to send");
+            }
         })
         .await;
 }
 
+// Port 0 can't be connected to, a target with it is skipped before it takes
+// a scan slot
+fn has_valid_port(target: &Target) -> bool {
+    if target.port == 0 {
+        tracing::warn!("skipping {}, port 0 can't be scanned", target.ip);
+        return false;
+    }
+    true
+}
+
+/// Detect the service on the target. Without a tls connector nothing is tried
+/// over tls.
 pub async fn scan(
     target: Target,
     service_probes: &ServiceProbes,
-    tls_connector: &TlsConnector,
+    tls_connector: Option<&TlsConnector>,
+    config: &ScanConfig,
 ) -> RadarOutput {
-    match run_scan(&target, service_probes, false, tls_connector).await {
-        Ok(detection) => {
-            if detection.service_match.service.starts_with("ssl") {
-                let tls_wrapped_result =
-                    run_scan(&target, service_probes, true, tls_connector).await;
-
-                (
-                    target,
-                    Ok(Detection::DetectionWithTls(DetectionWithTls {
-                        detection,
-                        tls_wrapped_result,
-                    })),
-                )
-                    .into()
-            } else {
-                (target, Ok(Detection::DetectionWithoutTls(detection))).into()
+    let start = Instant::now();
+    let mut timings = Timings::default();
+    let mut notes = ScanNotes::default();
+    let transport = config.transport();
+    let r = if transport == TransportProtocol::UDP {
+        run_udp_scan(&target, service_probes, config, &mut timings, &mut notes)
+            .await
+            .map(Detection::DetectionWithoutTls)
+    } else {
+        let mut r = scan_tcp(
+            &target,
+            service_probes,
+            tls_connector,
+            config,
+            &mut timings,
+            &mut notes,
+        )
+        .await;
+        if let (Some(delay_ms), true) = (config.no_detection_retry_ms, open_without_detection(&r)) {
+            info!("open with nothing detected, retrying in {}ms", delay_ms);
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            notes.no_detection_retried = true;
+            let retry = scan_tcp(
+                &target,
+                service_probes,
+                tls_connector,
+                config,
+                &mut timings,
+                &mut notes,
+            );
+            // a retry that also fails says no more than the first attempt's
+            // response did
+            if let Ok(d) = retry.await {
+                r = Ok(d);
+            }
+        }
+        r
+    };
+
+    let unmatched = matches!(&r, Err(RadarError::NoDetection(response)) if !response.is_empty());
+    let mut output = RadarOutput::from_detection(target, r);
+    if unmatched {
+        output.response_probe = notes.response_probe.take();
+    }
+    if transport == TransportProtocol::UDP {
+        // no response can't tell open from filtered for udp
+        output.port_state = None;
+    }
+    if transport == TransportProtocol::TCP && tls_connector.is_none() {
+        output.tls = None;
+        output.tls_disabled = Some(true);
+    }
+    output.transport = Some(transport);
+    output.timestamp.format = config.timestamp_format;
+    if let Some(max) = config.max_stored_response {
+        output.truncate_responses(max);
+    }
+    output.add_response_encodings(config.response_ascii, config.response_hex);
+    if config.timings {
+        timings.total_ms = start.elapsed().as_millis() as u64;
+        output.timings = Some(timings);
+    }
+    if notes.closed_without_banner {
+        output.closed_without_banner = Some(true);
+    }
+    if notes.no_detection_retried {
+        output.no_detection_retried = Some(true);
+    }
+    output.probe_attempts = notes.attempts;
+    output
+}
+
+/// Only connect to the target to find whether the port is open, closed or
+/// filtered, without sending or reading anything
+pub async fn connect_scan(target: Target, config: &ScanConfig) -> RadarOutput {
+    let start = Instant::now();
+    let mut timings = Timings::default();
+    let r = connect_stream(&target, None, config, &mut timings)
+        .await
+        .map(|_| ());
+
+    let mut output = RadarOutput::from_connect(target, r);
+    output.transport = Some(TransportProtocol::TCP);
+    output.timestamp.format = config.timestamp_format;
+    if config.timings {
+        timings.total_ms = start.elapsed().as_millis() as u64;
+        output.timings = Some(timings);
+    }
+    output
+}
+
+// Whether to connect with tls when the plaintext probes found nothing, which
+// is what a tls server with no plaintext hint looks like
+fn try_tls_directly(target: &Target, service_probes: &ServiceProbes, config: &ScanConfig) -> bool {
+    config.tls_on_no_detection && is_tls_port(target, service_probes, config)
+}
+
+// Ports services usually run tls on
+fn is_tls_port(target: &Target, service_probes: &ServiceProbes, config: &ScanConfig) -> bool {
+    config.tls_ports.contains(&target.port)
+        || (!config.ignore_ssl_ports && service_probes.is_ssl_port(target.port))
+}
+
+// What was seen along the way of a scan, beyond its result
+#[derive(Default)]
+struct ScanNotes {
+    // every probe sent, kept when tracing probes
+    attempts: Vec<ProbeAttempt>,
+    // a connection was closed by the peer before it sent anything or was
+    // sent anything
+    closed_without_banner: bool,
+    // the probes were run a second time after finding nothing
+    no_detection_retried: bool,
+    // the probe whose response a scan that matched nothing returns
+    response_probe: Option<String>,
+    // tls handshakes that went through
+    tls_handshakes: usize,
+}
+
+// The port accepted the connection but no probe matched what it sent, if
+// anything
+fn open_without_detection(r: &Result<Detection, RadarError>) -> bool {
+    matches!(
+        r,
+        Err(RadarError::NoDetection(_)) | Err(RadarError::Elapsed(_))
+    )
+}
+
+// Run the plaintext probes, and if they find a tls service, run them again
+// over tls to detect the wrapped service. Port aware, tls ports are scanned
+// over tls first, and other ports never over tls.
+async fn scan_tcp(
+    target: &Target,
+    service_probes: &ServiceProbes,
+    tls_connector: Option<&TlsConnector>,
+    config: &ScanConfig,
+    timings: &mut Timings,
+    notes: &mut ScanNotes,
+) -> Result<Detection, RadarError> {
+    if let (true, Some(cx)) = (config.tls_cert_only, tls_connector) {
+        return scan_certificate(target, service_probes, cx, config, timings, notes).await;
+    }
+    let mut tls_connector = tls_connector;
+    // the result of scanning over tls first, kept for the wrapped service
+    // along with whether it got through a handshake
+    let mut tls_first = None;
+    if config.port_aware_tls && tls_connector.is_some() {
+        if is_tls_port(target, service_probes, config) {
+            info!("tls port, trying tls first");
+            let handshakes = notes.tls_handshakes;
+            match run_scan(
+                target,
+                service_probes,
+                tls_connector,
+                None,
+                config,
+                timings,
+                notes,
+            )
+            .await
+            {
+                Ok(d) => return Ok(Detection::DetectionOnlyTls(d)),
+                Err(e) => tls_first = Some((Err(e), notes.tls_handshakes > handshakes)),
+            }
+        } else {
+            tls_connector = None;
+        }
+    }
+
+    let detection = run_scan(
+        target,
+        service_probes,
+        None,
+        tls_connector,
+        config,
+        timings,
+        notes,
+    )
+    .await;
+    if tls_connector.is_none() {
+        return detection.map(plaintext_detection);
+    }
+    let detection = match detection {
+        Err(e @ RadarError::NoDetection(_)) | Err(e @ RadarError::Elapsed(_))
+            if tls_first.is_none() && try_tls_directly(target, service_probes, config) =>
+        {
+            info!("no plaintext detection, trying tls");
+            return run_scan(
+                target,
+                service_probes,
+                tls_connector,
+                None,
+                config,
+                timings,
+                notes,
+            )
+            .await
+            .map(Detection::DetectionOnlyTls)
+            .map_err(|_| e);
+        }
+        detection => detection?,
+    };
+    if detection.probe_tls || !detection.service_match.service.starts_with("ssl") {
+        return Ok(plaintext_detection(detection));
+    }
+
+    let (tls_wrapped_result, tls_handshake) = match tls_first {
+        Some(r) => r,
+        None => {
+            let handshakes = notes.tls_handshakes;
+            let r = run_scan(
+                target,
+                service_probes,
+                tls_connector,
+                None,
+                config,
+                timings,
+                notes,
+            )
+            .await;
+            (r, notes.tls_handshakes > handshakes)
+        }
+    };
+    Ok(Detection::DetectionWithTls(DetectionWithTls {
+        detection,
+        tls_wrapped_result,
+        tls_handshake,
+    }))
+}
+
+// The detection of a scan in plaintext, which a probe that requires tls made
+// over tls of its own
+fn plaintext_detection(d: DetectionInner) -> Detection {
+    if d.probe_tls {
+        Detection::DetectionOnlyTls(d)
+    } else {
+        Detection::DetectionWithoutTls(d)
+    }
+}
+
+// Find the tls service and read its certificate, without sending any probes
+// over tls
+async fn scan_certificate(
+    target: &Target,
+    service_probes: &ServiceProbes,
+    tls_connector: &TlsConnector,
+    config: &ScanConfig,
+    timings: &mut Timings,
+    notes: &mut ScanNotes,
+) -> Result<Detection, RadarError> {
+    if is_tls_port(target, service_probes, config) {
+        info!("tls port, reading the certificate");
+        match read_certificate(target, tls_connector, config, timings).await {
+            // not tls after all, see what the plaintext probes find
+            Err(RadarError::Tls(e)) => info!("tls handshake failed: {}", e),
+            Err(e) => return Err(e),
+            certificate => {
+                return Ok(Detection::DetectionCertificate(DetectionCertificate {
+                    detection: None,
+                    certificate,
+                }))
             }
         }
-        Err(e) => (target, Err(e)).into(),
     }
+
+    let detection = run_scan(target, service_probes, None, None, config, timings, notes).await?;
+    if !detection.service_match.service.starts_with("ssl") {
+        return Ok(Detection::DetectionWithoutTls(detection));
+    }
+    let certificate = read_certificate(target, tls_connector, config, timings).await;
+    Ok(Detection::DetectionCertificate(DetectionCertificate {
+        detection: Some(detection),
+        certificate,
+    }))
+}
+
+// Scan with the quick probes, then scan targets that turned out to be open
+// again with the full probe set
+async fn scan_two_phase(
+    target: Target,
+    quick_probes: &ServiceProbes,
+    service_probes: &ServiceProbes,
+    tls_connector: Option<&TlsConnector>,
+    config: &ScanConfig,
+) -> Vec<RadarOutput> {
+    let mut detect = scan(target.clone(), quick_probes, tls_connector, config).await;
+    detect.phase = Some(ScanPhase::Detect);
+    // a tls service found on a tls port has only its tls fields set
+    if detect.match_rank() == MatchRank::None
+        && detect.response.is_none()
+        && detect.tls_response.is_none()
+    {
+        return vec![detect];
+    }
+
+    let mut version = scan(target, service_probes, tls_connector, config).await;
+    version.phase = Some(ScanPhase::Version);
+    vec![detect, version]
 }
 
 trait AsyncReadWrite: AsyncRead + AsyncReadExt + AsyncWrite + AsyncWriteExt + Unpin {}
 impl<T: AsyncRead + AsyncReadExt + AsyncWrite + AsyncWriteExt + Unpin> AsyncReadWrite for T {}
 
-#[instrument(skip(service_probes, tls_connector))]
+// The connector to send a probe with when scanning over tls or not, None to
+// send it in plaintext, or None at all when it's not sent
+fn probe_connector<'a>(
+    probe: &ServiceProbe,
+    tls: Option<&'a TlsConnector>,
+    tls_required: Option<&'a TlsConnector>,
+) -> Option<Option<&'a TlsConnector>> {
+    match (probe.tls(), tls) {
+        (Some(ProbeTls::Never), Some(_)) => None,
+        (Some(ProbeTls::Required), None) => tls_required.map(Some),
+        _ => Some(tls),
+    }
+}
+
+// Run the probes until one matches, over tls when given a tls connector. A
+// softmatch is only returned once no probe after it finds a hard match.
+// Probes that require tls are sent over tls_required when scanning in
+// plaintext, and left out without it.
+#[instrument(skip(service_probes, tls, tls_required, config, timings, notes))]
 async fn run_scan(
     target: &Target,
     service_probes: &ServiceProbes,
-    tls: bool,
-    tls_connector: &TlsConnector,
+    tls: Option<&TlsConnector>,
+    tls_required: Option<&TlsConnector>,
+    config: &ScanConfig,
+    timings: &mut Timings,
+    notes: &mut ScanNotes,
 ) -> Result<DetectionInner, RadarError> {
-    let mut buf = vec![0u8; 1600];
-    // If we receive any data at any point, we want to return it, rather than an io error
-    let prev_response: Option<Vec<u8>> = None;
-    for probe in &service_probes.tcp_probes {
-        let host = format!("{}:{}", target.ip, target.port);
-        info!("attempting to connect");
-        let mut stream = connect_with_timeout(&host).await.map_err(|e| {
-            if prev_response.is_some() {
-                info!(
-                    "error connecting to host {}, previous probe returned data",
-                    e.to_string()
-                );
-                RadarError::NoDetection(prev_response.clone().unwrap())
-            } else {
-                e
+    if config.concurrent_probes {
+        let scan = run_scan_concurrent(
+            target,
+            service_probes,
+            tls,
+            tls_required,
+            config,
+            timings,
+            notes,
+        );
+        return scan.await;
+    }
+    let mut buf = vec![0u8; config.read_buffer_size];
+    // A softmatch, returned when no later probe finds a hard match
+    let mut soft_match = None;
+    // The result of a probe that stopped the scan early
+    let mut stopped = None;
+    // With connection reuse, a stream the previous probe left open
+    let mut open_stream: Option<BoxedStream> = None;
+    // The most recent data any probe got back, and the probe that did
+    let mut last_response = vec![];
+    let mut last_response_probe = None;
+    // Every probe's response, in the order the probes were sent
+    let mut combined_response = vec![];
+    // The first unmatched HTTP response with a product in its headers
+    let mut http_fallback = None;
+    let null_probe = if config.null_fallback {
+        service_probes
+            .tcp_probes
+            .iter()
+            .find(|p| p.probe.name == "NULL")
+    } else {
+        None
+    };
+    for (probe, probe_tls) in service_probes
+        .tcp_probes
+        .iter()
+        .filter(|p| target.wants_probe(p, config))
+        .filter_map(|p| Some((p, probe_connector(p, tls, tls_required)?)))
+    {
+        // a probe's own tls connection isn't shared with the plaintext probes
+        let own_tls = probe_tls.is_some() && tls.is_none();
+        // the NULL probe's banner signatures can also match data volunteered
+        // in response to other probes
+        let fallback = null_probe.filter(|null| null.probe.name != probe.probe.name);
+        let reusable = if own_tls { None } else { open_stream.take() };
+        let (mut stream, reused) = match reusable {
+            Some(mut stream) => {
+                info!("reusing connection");
+                discard_pending(&mut stream, &mut buf).await;
+                (stream, true)
             }
-        })?;
-        info!("successfully connected");
-
-        let r = if tls {
-            info!("attempting to negotiate tls");
-            let mut stream = tls_connector.connect(&target.ip, stream).await?;
-            info!("successfully negotiated tls");
-            run_service_probe_and_match(&mut stream, &mut buf, &probe).await
-        } else {
-            run_service_probe_and_match(&mut stream, &mut buf, &probe).await
+            None => match connect_stream(target, probe_tls, config, timings).await {
+                Ok(stream) => {
+                    if probe_tls.is_some() {
+                        notes.tls_handshakes += 1;
+                    }
+                    (stream, false)
+                }
+                // the port may not speak tls at all, the plaintext probes
+                // still get their turn
+                Err(e) if own_tls => {
+                    info!("skipping probe, couldn't connect over tls: {}", e);
+                    if config.trace_probes {
+                        let attempt = ProbeAttempt::new(&probe.probe.name, true, &Err(e));
+                        notes.attempts.push(attempt);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    let (http, response) = (&mut http_fallback, &mut last_response);
+                    stopped = Some(cut_short(e, http, response, last_response_probe, notes));
+                    break;
+                }
+            },
         };
+        let mut connected_at = Instant::now();
+
+        let request = probe_request(target, &probe.probe, config);
+        config.observe(|o| o.probe_sent(target, &probe.probe));
+        let mut r = run_service_probe_and_match(
+            &mut stream,
+            &mut buf,
+            probe,
+            fallback,
+            &request,
+            config,
+            timings,
+        )
+        .await;
+        if reused && connection_lost(&r) {
+            info!("reused connection was closed, reconnecting");
+            stream = match connect_stream(target, probe_tls, config, timings).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let (http, response) = (&mut http_fallback, &mut last_response);
+                    stopped = Some(cut_short(e, http, response, last_response_probe, notes));
+                    break;
+                }
+            };
+            if probe_tls.is_some() {
+                notes.tls_handshakes += 1;
+            }
+            connected_at = Instant::now();
+            r = run_service_probe_and_match(
+                &mut stream,
+                &mut buf,
+                probe,
+                fallback,
+                &request,
+                config,
+                timings,
+            )
+            .await;
+        }
+        if probe_tls.is_none() && tcp_wrapped(probe, &r, connected_at.elapsed()) {
+            info!("closed by the peer without a banner, tcpwrapped");
+            r = Ok(tcp_wrapped_detection(probe, config));
+        }
+        if config.reuse_connections && !own_tls && connection_open(&r) {
+            open_stream = Some(stream);
+        }
+        if config.trace_probes {
+            let attempt = ProbeAttempt::new(&probe.probe.name, probe_tls.is_some(), &r);
+            notes.attempts.push(attempt);
+        }
+        // the NULL probe sends nothing, so an empty response means the peer
+        // hung up as soon as it accepted the connection
+        if request.is_empty() && matches!(&r, Err(RadarError::NoDetection(r)) if r.is_empty()) {
+            notes.closed_without_banner = true;
+        }
 
         match r {
-            Ok(d) => return Ok(d),
+            Ok(mut d) => {
+                d.probe_tls = own_tls;
+                if !d.soft {
+                    config.observe(|o| o.matched(target, &d.service_match));
+                    return Ok(d);
+                }
+                // the probes left may still find a hard match
+                soft_match.get_or_insert(d);
+            }
             Err(RadarError::NoDetection(r)) => {
                 info!("no match found for given probe, attempting fallback");
+                if config.match_combined_responses {
+                    let room = MAX_COMBINED_RESPONSE.saturating_sub(combined_response.len());
+                    combined_response.extend_from_slice(&r[..r.len().min(room)]);
+                }
+                if config.http_header_fallback && http_fallback.is_none() {
+                    http_fallback = http_header_detection(&r);
+                }
+                if !r.is_empty() {
+                    last_response = r;
+                    last_response_probe = Some(&probe.probe.name);
+                }
             }
             Err(RadarError::Elapsed(e)) => {
                 if probe.probe.name != "NULL" {
-                    return Err(RadarError::Elapsed(e));
+                    stopped = Some(http_fallback.take().ok_or(RadarError::Elapsed(e)));
+                    break;
                 }
             }
-            Err(e) => return Err(e),
+            Err(e) => {
+                stopped = Some(http_fallback.take().ok_or(e));
+                break;
+            }
         }
     }
-    unreachable!();
-}
 
-async fn connect_with_timeout(host: &str) -> Result<TcpStream, RadarError> {
-    let stream = timeout(Duration::from_secs(TIMEOUT), async {
-        TcpStream::connect(&host).await
-    })
-    .await?;
-    stream.map_err(|e| e.into())
+    if let Some(d) = soft_match {
+        config.observe(|o| o.matched(target, &d.service_match));
+        return Ok(d);
+    }
+    if let Some(r) = stopped {
+        return r;
+    }
+    if let Some(d) = match_combined_response(target, &combined_response, service_probes, config) {
+        return Ok(d);
+    }
+    notes.response_probe = last_response_probe.cloned();
+    http_fallback.ok_or(RadarError::NoDetection(last_response))
 }
 
-#[instrument(skip_all, fields(probe.name = service_probe.probe.name))]
-async fn run_service_probe_and_match<S>(
-    stream: &mut S,
-    buf: &mut [u8],
-    service_probe: &ServiceProbe,
-) -> Result<DetectionInner, RadarError>
-where
-    S: AsyncReadWrite,
-{
-    let bytes_read = run_service_probe(stream, buf, service_probe).await?;
-    let response = &buf[..bytes_read];
-
-    info!("checking for matches");
-    match service_probe.check_match(response) {
-        Some(service_match) => {
-            info!("found match");
-            return Ok(DetectionInner {
-                response: encode(&buf[..bytes_read]),
-                service_match,
-            });
-        }
-        None => {
-            info!("no match");
-            return Err(RadarError::NoDetection(response.into()));
-        }
+// The result of a scan an error stopped before its last probe: the http
+// fallback, or what earlier probes got back, over the error itself
+fn cut_short(
+    e: RadarError,
+    http_fallback: &mut Option<DetectionInner>,
+    last_response: &mut Vec<u8>,
+    last_response_probe: Option<&String>,
+    notes: &mut ScanNotes,
+) -> Result<DetectionInner, RadarError> {
+    if http_fallback.is_some() || last_response.is_empty() {
+        return http_fallback.take().ok_or(e);
     }
+    info!(
+        "error connecting to host {}, previous probe returned data",
+        e
+    );
+    notes.response_probe = last_response_probe.cloned();
+    Err(RadarError::NoDetection(std::mem::take(last_response)))
 }
 
-#[instrument(skip_all, fields(probe.name = service_probe.probe.name))]
-async fn run_service_probe<S>(
-    stream: &mut S,
-    mut buf: &mut [u8],
-    service_probe: &ServiceProbe,
-) -> Result<usize, RadarError>
-where
-    S: AsyncReadWrite,
-{
-    let request = &service_probe.probe.data;
-    if request.len() > 0 {
-        info!("writing");
-        stream.write_all(&request).await?;
-        info!("finished writing");
+fn match_combined_response(
+    target: &Target,
+    combined_response: &[u8],
+    service_probes: &ServiceProbes,
+    config: &ScanConfig,
+) -> Option<DetectionInner> {
+    if combined_response.is_empty() {
+        return None;
     }
+    info!("checking for matches in the combined responses");
+    let d = service_probes
+        .tcp_probes
+        .iter()
+        .find_map(|probe| match_response(combined_response, probe, None, config).ok())?;
+    config.observe(|o| o.matched(target, &d.service_match));
+    Some(d)
+}
 
-    info!("reading");
-    let bytes_read = timeout(Duration::from_secs(TIMEOUT), async {
-        stream.read(&mut buf).await
-    })
-    .await??;
+// Run every probe at once, each over its own connection. The first hard match
+// is returned and the probes still running are dropped, which closes their
+// connections. A softmatch is only returned once every probe has finished
+// without a hard match.
+async fn run_scan_concurrent(
+    target: &Target,
+    service_probes: &ServiceProbes,
+    tls: Option<&TlsConnector>,
+    tls_required: Option<&TlsConnector>,
+    config: &ScanConfig,
+    timings: &mut Timings,
+    notes: &mut ScanNotes,
+) -> Result<DetectionInner, RadarError> {
+    let null_probe = service_probes
+        .tcp_probes
+        .iter()
+        .find(|p| config.null_fallback && p.is_null());
+    let mut probes: FuturesUnordered<_> = service_probes
+        .tcp_probes
+        .iter()
+        .filter(|p| target.wants_probe(p, config))
+        .filter_map(|p| Some((p, probe_connector(p, tls, tls_required)?)))
+        .enumerate()
+        .map(|(i, (probe, probe_tls))| async move {
+            let fallback = null_probe.filter(|null| null.probe.name != probe.probe.name);
+            let mut probe_timings = Timings::default();
+            let mut buf = vec![0u8; config.read_buffer_size];
+            let request = probe_request(target, &probe.probe, config);
+            let mut handshake = false;
+            let r = match connect_stream(target, probe_tls, config, &mut probe_timings).await {
+                Ok(mut stream) => {
+                    handshake = probe_tls.is_some();
+                    let connected_at = Instant::now();
+                    config.observe(|o| o.probe_sent(target, &probe.probe));
+                    let r = run_service_probe_and_match(
+                        &mut stream,
+                        &mut buf,
+                        probe,
+                        fallback,
+                        &request,
+                        config,
+                        &mut probe_timings,
+                    )
+                    .await;
+                    if probe_tls.is_none() && tcp_wrapped(probe, &r, connected_at.elapsed()) {
+                        Ok(tcp_wrapped_detection(probe, config))
+                    } else {
+                        r
+                    }
+                }
+                Err(e) => Err(e),
+            };
+            let own_tls = probe_tls.is_some() && tls.is_none();
+            let r = r.map(|d| DetectionInner {
+                probe_tls: own_tls,
+                ..d
+            });
+            (
+                i,
+                probe,
+                probe_tls.is_some(),
+                request.is_empty(),
+                handshake,
+                r,
+                probe_timings,
+            )
+        })
+        .collect();
+
+    let mut soft_match = None;
+    let mut responses = vec![];
+    let mut http_fallback = None;
+    let mut first_error = None;
+    while let Some((i, probe, over_tls, null_request, handshake, r, probe_timings)) =
+        probes.next().await
+    {
+        if handshake {
+            notes.tls_handshakes += 1;
+        }
+        timings.connect_ms += probe_timings.connect_ms;
+        timings.tls_ms += probe_timings.tls_ms;
+        timings.read_ms += probe_timings.read_ms;
+        if let Some(rtt) = probe_timings.connect_rtt() {
+            timings.observe_connect_rtt(rtt);
+        }
+        if config.trace_probes {
+            let attempt = ProbeAttempt::new(&probe.probe.name, over_tls, &r);
+            notes.attempts.push(attempt);
+        }
+        if null_request && matches!(&r, Err(RadarError::NoDetection(r)) if r.is_empty()) {
+            notes.closed_without_banner = true;
+        }
+        match r {
+            Ok(d) if !d.soft => {
+                config.observe(|o| o.matched(target, &d.service_match));
+                return Ok(d);
+            }
+            Ok(d) => {
+                soft_match.get_or_insert(d);
+            }
+            Err(RadarError::NoDetection(r)) => {
+                if config.http_header_fallback && http_fallback.is_none() {
+                    http_fallback = http_header_detection(&r);
+                }
+                responses.push((i, &probe.probe.name, r));
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(d) = soft_match {
+        config.observe(|o| o.matched(target, &d.service_match));
+        return Ok(d);
+    }
+
+    // responses in the order the probes were sent, as when run one by one
+    responses.sort_by_key(|(i, _, _)| *i);
+    if config.match_combined_responses {
+        let mut combined_response = vec![];
+        for (_, _, r) in &responses {
+            let room = MAX_COMBINED_RESPONSE.saturating_sub(combined_response.len());
+            combined_response.extend_from_slice(&r[..r.len().min(room)]);
+        }
+        if let Some(d) = match_combined_response(target, &combined_response, service_probes, config)
+        {
+            return Ok(d);
+        }
+    }
+    if let Some(d) = http_fallback {
+        return Ok(d);
+    }
+    match responses.into_iter().rev().find(|(_, _, r)| !r.is_empty()) {
+        Some((_, name, r)) => {
+            notes.response_probe = Some(name.clone());
+            Err(RadarError::NoDetection(r))
+        }
+        None => Err(first_error.unwrap_or_else(|| RadarError::NoDetection(vec![]))),
+    }
+}
+
+// A detection of http from an HTTP response's Server and X-Powered-By headers,
+// for web servers no signature knows. None when the response isn't HTTP or
+// names no product.
+fn http_header_detection(response: &[u8]) -> Option<DetectionInner> {
+    if !response.starts_with(b"HTTP/1.") {
+        return None;
+    }
+    let head = String::from_utf8_lossy(response);
+    let header = |name: &str| {
+        head.lines()
+            .skip(1)
+            .take_while(|line| !line.trim().is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let server = header("Server");
+    let powered_by = header("X-Powered-By");
+    if server.is_none() && powered_by.is_none() {
+        return None;
+    }
+
+    // nmap's version info fields, Server: nginx/1.18.0 becomes p/nginx/ v/1.18.0/
+    let mut version_info = vec![];
+    if let Some(server) = &server {
+        let product = server.split_whitespace().next().unwrap_or(server);
+        match product.split_once('/') {
+            Some((name, version)) => {
+                version_info.push(version_field('p', name));
+                version_info.push(version_field('v', version));
+            }
+            None => version_info.push(version_field('p', product)),
+        }
+    }
+    if let Some(powered_by) = &powered_by {
+        version_info.push(version_field('i', &format!("X-Powered-By: {}", powered_by)));
+    }
+
+    let version_info = version_info.join(" ");
+    Some(DetectionInner {
+        response: encode(response),
+        service_match: Match {
+            service: "http".into(),
+            pattern: HTTP_FALLBACK_PATTERN.into(),
+            re: HTTP_FALLBACK_RE.clone(),
+            pattern_options: String::new(),
+            fields: parse_version_info(&version_info),
+            version_info,
+            id: None,
+        },
+        probe_ports: None,
+        probe_rarity: None,
+        soft: false,
+        probe_tls: false,
+    })
+}
+
+// A plaintext connection the peer closed or reset without sending anything,
+// within the probe's tcpwrappedms of accepting it. Probes without the
+// directive never find a tcp wrapper.
+fn tcp_wrapped(
+    probe: &ServiceProbe,
+    r: &Result<DetectionInner, RadarError>,
+    elapsed: Duration,
+) -> bool {
+    let closed = match r {
+        Err(RadarError::NoDetection(response)) => response.is_empty(),
+        Err(RadarError::Io(e)) => e.kind() == io::ErrorKind::ConnectionReset,
+        _ => false,
+    };
+    match probe.tcp_wrapped_ms() {
+        Some(ms) => closed && elapsed < Duration::from_millis(ms as u64),
+        None => false,
+    }
+}
+
+// Reported as nmap does for a port behind a tcp wrapper, which accepts the
+// connection and hangs up on hosts it doesn't allow
+fn tcp_wrapped_detection(probe: &ServiceProbe, config: &ScanConfig) -> DetectionInner {
+    DetectionInner {
+        response: String::new(),
+        service_match: Match {
+            service: "tcpwrapped".into(),
+            pattern: TCP_WRAPPED_PATTERN.into(),
+            re: TCP_WRAPPED_RE.clone(),
+            pattern_options: String::new(),
+            version_info: String::new(),
+            id: None,
+            fields: None,
+        },
+        probe_ports: probe_ports(probe, config),
+        probe_rarity: probe.declared_rarity(),
+        soft: false,
+        probe_tls: false,
+    }
+}
+
+// The pattern reported for tcpwrapped detections, an empty response
+const TCP_WRAPPED_PATTERN: &str = "^$";
+static TCP_WRAPPED_RE: LazyLock<Arc<Regex>> = LazyLock::new(|| {
+    Arc::new(Regex::new(TCP_WRAPPED_PATTERN).expect("failed to compile tcpwrapped pattern"))
+});
+
+// The pattern reported for detections by http_header_detection
+const HTTP_FALLBACK_PATTERN: &str = r"^HTTP/1\.[01] \d\d\d";
+static HTTP_FALLBACK_RE: LazyLock<Arc<Regex>> = LazyLock::new(|| {
+    Arc::new(Regex::new(HTTP_FALLBACK_PATTERN).expect("failed to compile http fallback pattern"))
+});
+
+// A version info field like p/nginx/, with a delimiter the value doesn't contain
+fn version_field(tag: char, value: &str) -> String {
+    let delimiter = ['/', '|', '%', '=', '@']
+        .iter()
+        .copied()
+        .find(|&d| !value.contains(d))
+        .unwrap_or('/');
+    format!("{}{}{}{}", tag, delimiter, value, delimiter)
+}
+
+#[instrument(skip(service_probes, config, timings, notes))]
+async fn run_udp_scan(
+    target: &Target,
+    service_probes: &ServiceProbes,
+    config: &ScanConfig,
+    timings: &mut Timings,
+    notes: &mut ScanNotes,
+) -> Result<DetectionInner, RadarError> {
+    let mut buf = vec![0u8; config.read_buffer_size];
+    let host = target_host(target, config).await?;
+    let addr = lookup_host(&host)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses for host"))?;
+    let mut last_err = None;
+    for probe in service_probes
+        .udp_probes
+        .iter()
+        .filter(|p| target.wants_probe(p, config))
+    {
+        let socket = bound_udp_socket(addr, config.interface.as_deref()).await?;
+        socket.connect(addr).await?;
+
+        let request = probe_request(target, &probe.probe, config);
+        // a no-payload probe only listens, not even an empty datagram goes out
+        let request = (!probe.probe.no_payload).then_some(&request[..]);
+        config.observe(|o| o.probe_sent(target, &probe.probe));
+        let read_start = Instant::now();
+        let bytes_read = run_udp_service_probe(&socket, &mut buf, request, config).await;
+        timings.read_ms += read_start.elapsed().as_millis() as u64;
+        let r = match bytes_read {
+            Ok(bytes_read) => match_response(&buf[..bytes_read], probe, None, config),
+            Err(e) => Err(e),
+        };
+        if config.trace_probes {
+            notes
+                .attempts
+                .push(ProbeAttempt::new(&probe.probe.name, false, &r));
+        }
+
+        match r {
+            Ok(d) => {
+                config.observe(|o| o.matched(target, &d.service_match));
+                return Ok(d);
+            }
+            // no response is normal for udp, move on to the next probe
+            Err(e @ RadarError::NoDetection(_)) | Err(e @ RadarError::Elapsed(_)) => {
+                info!("no match found for given probe, attempting fallback");
+                if !matches!(last_err, Some(RadarError::NoDetection(_))) {
+                    if matches!(&e, RadarError::NoDetection(r) if !r.is_empty()) {
+                        notes.response_probe = Some(probe.probe.name.clone());
+                    }
+                    last_err = Some(e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| RadarError::NoDetection(vec![])))
+}
+
+// A udp socket to send to addr from, bound to the interface like the tcp
+// connects when there is one
+async fn bound_udp_socket(addr: SocketAddr, interface: Option<&str>) -> io::Result<UdpSocket> {
+    let unspecified: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let interface = match interface {
+        Some(interface) => interface,
+        None => return UdpSocket::bind(SocketAddr::new(unspecified, 0)).await,
+    };
+    let bound = match interface.parse::<IpAddr>() {
+        Ok(ip) => UdpSocket::bind(SocketAddr::new(ip, 0)).await,
+        Err(_) => match UdpSocket::bind(SocketAddr::new(unspecified, 0)).await {
+            Ok(socket) => bind_udp_device(&socket, interface).map(|_| socket),
+            Err(e) => Err(e),
+        },
+    };
+    bound.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to bind to interface {}: {}", interface, e),
+        )
+    })
+}
+
+// Send a udp probe and wait for a response, resending the probe up to
+// config.udp_retransmits times since a single datagram is easily lost. With
+// no request, only wait.
+#[instrument(skip_all)]
+async fn run_udp_service_probe(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    request: Option<&[u8]>,
+    config: &ScanConfig,
+) -> Result<usize, RadarError> {
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+    let interval = Duration::from_millis(config.udp_retransmit_interval_ms);
+    let mut attempt = 0;
+    loop {
+        if let Some(request) = request {
+            info!("writing");
+            socket.send(request).await?;
+        }
+
+        let last_attempt = request.is_none() || attempt >= config.udp_retransmits;
+        let wait_until = if last_attempt {
+            deadline
+        } else {
+            std::cmp::min(deadline, Instant::now() + interval)
+        };
+        match timeout_at(wait_until, socket.recv(buf)).await {
+            Ok(bytes_read) => {
+                let bytes_read = bytes_read?;
+                info!("read {} bytes", bytes_read);
+                return Ok(bytes_read);
+            }
+            Err(e) if last_attempt || Instant::now() >= deadline => return Err(e.into()),
+            Err(_) => {
+                info!("no response, retransmitting");
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// The bytes to send for a probe against the given target. HTTP probes get a
+// Host header for the target's domain when enabled, so virtual hosts answer
+// with the site we're interested in rather than the default one. Probes
+// marked no-payload send nothing, whatever their data.
+fn probe_request<'a>(target: &Target, probe: &'a Probe, config: &ScanConfig) -> Cow<'a, [u8]> {
+    if probe.no_payload {
+        return Cow::Borrowed(&[]);
+    }
+    if config.http_host_header {
+        if let Some(domain) = &target.domain {
+            if let Some(request) = with_host_header(&probe.data, domain) {
+                return Cow::Owned(request);
+            }
+        }
+    }
+    Cow::Borrowed(&probe.data)
+}
+
+// Insert a Host header after the request line of a plaintext HTTP request.
+// Returns None for binary payloads, non-HTTP payloads, and requests that
+// already carry a Host header.
+fn with_host_header(data: &[u8], domain: &str) -> Option<Vec<u8>> {
+    if !data
+        .iter()
+        .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+    {
+        return None;
+    }
+    let request = std::str::from_utf8(data).ok()?;
+    let line_end = request.find("\r\n")?;
+    let (request_line, headers) = request.split_at(line_end);
+    if !request_line.contains(" HTTP/1.") {
+        return None;
+    }
+    if headers
+        .lines()
+        .any(|h| h.to_ascii_lowercase().starts_with("host:"))
+    {
+        return None;
+    }
+    Some(format!("{}\r\nHost: {}{}", request_line, domain, headers).into_bytes())
+}
+
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+type BoxedStream = Box<dyn AsyncStream>;
+
+// Connect to the target, negotiating tls when given a tls connector
+async fn connect_stream(
+    target: &Target,
+    tls: Option<&TlsConnector>,
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<BoxedStream, RadarError> {
+    let stream = connect_tcp(target, config, timings).await?;
+    match tls {
+        Some(cx) => Ok(Box::new(
+            negotiate_tls(target, stream, cx, config, timings).await?,
+        )),
+        None => {
+            config.observe(|o| o.connected(target, false));
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+// Handshake with the target and read its certificate, then hang up without
+// sending anything
+async fn read_certificate(
+    target: &Target,
+    tls: &TlsConnector,
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<CertificateInfo, RadarError> {
+    let stream = connect_tcp(target, config, timings).await?;
+    let stream = negotiate_tls(target, stream, tls, config, timings).await?;
+    let der = match stream.get_ref().peer_certificate()? {
+        Some(certificate) => certificate.to_der()?,
+        None => return Err(RadarError::Certificate("no certificate sent".into())),
+    };
+    CertificateInfo::from_der(&der).map_err(RadarError::Certificate)
+}
+
+async fn connect_tcp(
+    target: &Target,
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<TcpStream, RadarError> {
+    info!("attempting to connect");
+    config.observe(|o| o.connect_started(target));
+    let connect_start = Instant::now();
+    let stream = match proxy_for(target, &config.socks5_proxies) {
+        Some(proxy) => connect_through_proxy(target, proxy, config.interface.as_deref()).await,
+        None => match target_host(target, config).await {
+            Ok(host) => {
+                // through a proxy the time would be to the proxy, so only
+                // direct connects give the target's round trip time
+                let rtt_start = Instant::now();
+                let stream = connect_with_timeout(&host, config.interface.as_deref()).await;
+                if stream.is_ok() {
+                    timings.observe_connect_rtt(rtt_start.elapsed());
+                }
+                stream
+            }
+            Err(e) => Err(e.into()),
+        },
+    };
+    timings.connect_ms += connect_start.elapsed().as_millis() as u64;
+    let stream = stream?;
+    info!("successfully connected");
+    if config.write_chunk_size.is_some() {
+        stream.set_nodelay(true)?;
+    }
+    Ok(stream)
+}
+
+// The connector scans negotiate tls with. Any certificate is accepted, since
+// services are scanned whatever they present, and the target's tls_name is
+// sent as the sni, which native-tls leaves out when it's an ip.
+fn scan_tls_connector() -> TlsConnector {
+    let cx = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()
+        .expect("failed to build tls connector");
+    TlsConnector::from(cx)
+}
+
+async fn negotiate_tls(
+    target: &Target,
+    stream: TcpStream,
+    tls_connector: &TlsConnector,
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<TlsStream<TcpStream>, RadarError> {
+    info!("attempting to negotiate tls");
+    let tls_start = Instant::now();
+    let stream = tls_connector.connect(target.tls_name(), stream).await;
+    timings.tls_ms += tls_start.elapsed().as_millis() as u64;
+    let stream = stream?;
+    info!("successfully negotiated tls");
+    config.observe(|o| o.connected(target, true));
+    Ok(stream)
+}
+
+// The peer may still be listening after a probe that read data without
+// matching. After a timeout its answer may still be on the way, and would be
+// read as the next probe's.
+fn connection_open(r: &Result<DetectionInner, RadarError>) -> bool {
+    matches!(r, Err(RadarError::NoDetection(response)) if !response.is_empty())
+}
+
+// Throw away what the previous probe left unread on a reused connection
+async fn discard_pending(stream: &mut BoxedStream, buf: &mut [u8]) {
+    while let Ok(Ok(n)) = timeout(Duration::ZERO, stream.read(buf)).await {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+// The peer closed or reset a connection we tried to reuse
+fn connection_lost(r: &Result<DetectionInner, RadarError>) -> bool {
+    match r {
+        Err(RadarError::NoDetection(response)) => response.is_empty(),
+        Err(RadarError::Io(_)) => true,
+        _ => false,
+    }
+}
+
+async fn connect_with_timeout(
+    host: &str,
+    interface: Option<&str>,
+) -> Result<TcpStream, RadarError> {
+    // a timeout here is an io error, to tell a filtered port from one that
+    // accepted the connection then timed out on a read
+    let stream = timeout(Duration::from_secs(TIMEOUT), async {
+        match interface {
+            Some(interface) => connect_from_interface(host, interface).await,
+            None => TcpStream::connect(&host).await,
+        }
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))?;
+    stream.map_err(|e| e.into())
+}
+
+// Spread targets over the proxies, always sending the same target through the
+// same proxy so every probe to it comes from one address
+fn proxy_for<'a>(target: &Target, proxies: &'a [String]) -> Option<&'a str> {
+    if proxies.is_empty() {
+        return None;
+    }
+    let mut hasher = DefaultHasher::new();
+    (&target.ip, target.port).hash(&mut hasher);
+    let i = hasher.finish() as usize % proxies.len();
+    Some(&proxies[i])
+}
+
+// Connect to the proxy, then have it connect to the target. A proxy that is
+// down or refuses the target fails this target's scan, not the whole run.
+async fn connect_through_proxy(
+    target: &Target,
+    proxy: &str,
+    interface: Option<&str>,
+) -> Result<TcpStream, RadarError> {
+    let proxy_error = |error| RadarError::Proxy {
+        proxy: proxy.to_string(),
+        error,
+    };
+    let stream = connect_with_timeout(proxy, interface)
+        .await
+        .map_err(|e| match e {
+            RadarError::Io(e) => proxy_error(tokio_socks::Error::Io(e)),
+            e => e,
+        })?;
+    let stream = timeout(
+        Duration::from_secs(TIMEOUT),
+        Socks5Stream::connect_with_socket(stream, (target.ip.as_str(), target.port)),
+    )
+    .await?
+    .map_err(proxy_error)?;
+    Ok(stream.into_inner())
+}
+
+// Connect from a local address, or on linux a named network interface
+async fn connect_from_interface(host: &str, interface: &str) -> io::Result<TcpStream> {
+    let addr = lookup_host(host)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses for host"))?;
+    let socket = bound_socket(addr.is_ipv4(), interface)?;
+    socket.connect(addr).await
+}
+
+fn bound_socket(ipv4: bool, interface: &str) -> io::Result<TcpSocket> {
+    let socket = if ipv4 {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    let bound = match interface.parse::<IpAddr>() {
+        Ok(ip) => socket.bind(SocketAddr::new(ip, 0)),
+        Err(_) => bind_device(&socket, interface),
+    };
+    bound.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to bind to interface {}: {}", interface, e),
+        )
+    })?;
+    Ok(socket)
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(bind_device_unsupported())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_udp_device(socket: &UdpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_udp_device(_socket: &UdpSocket, _interface: &str) -> io::Result<()> {
+    Err(bind_device_unsupported())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface name is only supported on linux, use a local address",
+    )
+}
+
+/// Check that scans can bind to the interface, a local ip address or on
+/// linux an interface name, so a bad --interface fails up front rather than
+/// on every target
+pub fn check_interface(interface: &str) -> io::Result<()> {
+    let ipv4 = !matches!(interface.parse::<IpAddr>(), Ok(IpAddr::V6(_)));
+    bound_socket(ipv4, interface).map(|_| ())
+}
+
+#[instrument(skip_all, fields(probe.name = service_probe.probe.name))]
+async fn run_service_probe_and_match<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    service_probe: &ServiceProbe,
+    fallback: Option<&ServiceProbe>,
+    request: &[u8],
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<DetectionInner, RadarError>
+where
+    S: AsyncReadWrite,
+{
+    let mut bytes_read = run_service_probe(stream, buf, request, config, timings).await?;
+    let mut r = match_response(&buf[..bytes_read], service_probe, fallback, config);
+    let max_attempts = config.incremental_match_attempts.unwrap_or(1);
+    let mut attempts = 1;
+    // the response may still be arriving, read more and match again
+    while r.is_err() && attempts < max_attempts && bytes_read > 0 && bytes_read < buf.len() {
+        let read_start = Instant::now();
+        let wait = Duration::from_millis(INCREMENTAL_READ_WAIT_MS);
+        let read = timeout(wait, stream.read(&mut buf[bytes_read..])).await;
+        timings.read_ms += read_start.elapsed().as_millis() as u64;
+        match read {
+            Ok(Ok(n)) if n > 0 => bytes_read += n,
+            _ => break,
+        }
+        info!("read more, matching {} bytes", bytes_read);
+        attempts += 1;
+        r = match_response(&buf[..bytes_read], service_probe, fallback, config);
+    }
+    r
+}
+
+// Ports and sslports of the probe whose match was found, when they're output
+fn probe_ports(probe: &ServiceProbe, config: &ScanConfig) -> Option<Vec<u16>> {
+    config.probe_expected_ports.then(|| probe.expected_ports())
+}
+
+// Match the response against the probe's signatures, then the fallback
+// probe's if the probe's own signatures don't match
+fn match_response(
+    response: &[u8],
+    service_probe: &ServiceProbe,
+    fallback: Option<&ServiceProbe>,
+    config: &ScanConfig,
+) -> Result<DetectionInner, RadarError> {
+    info!("checking for matches");
+    let outcome = service_probe
+        .check_match(response)
+        .map(|m| (m, service_probe))
+        .or_else(|| {
+            let fallback = fallback?;
+            info!("checking for matches from {} probe", fallback.probe.name);
+            fallback.check_match(response).map(|m| (m, fallback))
+        });
+    match outcome {
+        Some((outcome, matched_probe)) => {
+            info!(soft = outcome.soft, "found match");
+            Ok(DetectionInner {
+                response: encode(response),
+                service_match: outcome.service_match,
+                probe_ports: probe_ports(matched_probe, config),
+                probe_rarity: matched_probe.declared_rarity(),
+                soft: outcome.soft,
+                probe_tls: false,
+            })
+        }
+        None => {
+            info!("no match");
+            Err(RadarError::NoDetection(response.into()))
+        }
+    }
+}
+
+#[instrument(skip_all)]
+async fn run_service_probe<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    request: &[u8],
+    config: &ScanConfig,
+    timings: &mut Timings,
+) -> Result<usize, RadarError>
+where
+    S: AsyncReadWrite,
+{
+    if !request.is_empty() {
+        // Some servers push a banner as soon as we connect, take that rather
+        // than burying it under the probe's response
+        if let Some(pre_read_ms) = config.pre_read_ms {
+            let read_start = Instant::now();
+            let pre_read = timeout(Duration::from_millis(pre_read_ms), stream.read(buf)).await;
+            timings.read_ms += read_start.elapsed().as_millis() as u64;
+            if let Ok(bytes_read) = pre_read {
+                let bytes_read = bytes_read?;
+                info!("read {} bytes before writing", bytes_read);
+                // the rest of a banner pushed in pieces
+                let deadline = Instant::now() + config.read_timeout(timings);
+                let read_start = Instant::now();
+                let bytes_read = read_min_banner(stream, buf, bytes_read, config, deadline).await;
+                timings.read_ms += read_start.elapsed().as_millis() as u64;
+                return bytes_read;
+            }
+        }
+
+        info!("writing");
+        write_request(stream, request, config).await?;
+        info!("finished writing");
+    }
+
+    info!("reading");
+    let read_timeout = config.read_timeout(timings);
+    let read_start = Instant::now();
+    let bytes_read = read_response(stream, buf, config, read_timeout).await;
+    timings.read_ms += read_start.elapsed().as_millis() as u64;
+
+    // a connection kept open for the next probe can't have its write side closed
+    if !config.reuse_connections {
+        let _ = stream.shutdown().await;
+    }
+    bytes_read
+}
+
+// Write the request in one go, or in chunks when the config asks for them
+async fn write_request<S>(
+    stream: &mut S,
+    request: &[u8],
+    config: &ScanConfig,
+) -> Result<(), RadarError>
+where
+    S: AsyncReadWrite,
+{
+    let chunk_size = match config.write_chunk_size {
+        Some(size) => size.max(1),
+        None => return Ok(stream.write_all(request).await?),
+    };
+    for (i, chunk) in request.chunks(chunk_size).enumerate() {
+        if let (true, Some(delay_ms)) = (i > 0, config.write_chunk_delay_ms) {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+        }
+        stream.write_all(chunk).await?;
+        stream.flush().await?;
+    }
+    Ok(())
+}
+
+async fn read_response<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    config: &ScanConfig,
+    read_timeout: Duration,
+) -> Result<usize, RadarError>
+where
+    S: AsyncReadWrite,
+{
+    let deadline = Instant::now() + read_timeout;
+    let bytes_read = timeout_at(deadline, stream.read(buf)).await??;
     info!("read {} bytes", bytes_read);
+    read_min_banner(stream, buf, bytes_read, config, deadline).await
+}
 
-    let _ = stream.shutdown();
+// Some servers trickle the banner out, keep reading after the bytes_read
+// already in buf until we have enough to match on, the peer closes, or the
+// read deadline passes.
+async fn read_min_banner<S>(
+    stream: &mut S,
+    buf: &mut [u8],
+    mut bytes_read: usize,
+    config: &ScanConfig,
+    deadline: Instant,
+) -> Result<usize, RadarError>
+where
+    S: AsyncReadWrite,
+{
+    while bytes_read > 0 && bytes_read < config.min_banner_bytes && bytes_read < buf.len() {
+        match timeout_at(deadline, stream.read(&mut buf[bytes_read..])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => {
+                bytes_read += n;
+                info!("read {} bytes, {} total", n, bytes_read);
+            }
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
     Ok(bytes_read)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{mock_server, Behavior};
+    use crate::serviceprobes::parse::{read_service_probes, ParseOptions};
+    use std::sync::atomic::AtomicUsize;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_cmp_by_address() {
+        let mut targets = vec![
+            Target::new("example.com", 80),
+            Target::new("10.0.0.10", 80),
+            Target::new("10.0.0.2", 443),
+            Target::new("10.0.0.2", 22),
+            Target::new("9.0.0.1", 80),
+        ];
+        targets.sort_by(|a, b| a.cmp_by_address(b));
+        assert_eq!(
+            targets,
+            vec![
+                Target::new("9.0.0.1", 80),
+                Target::new("10.0.0.2", 22),
+                Target::new("10.0.0.2", 443),
+                Target::new("10.0.0.10", 80),
+                Target::new("example.com", 80),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_with_host_header() {
+        let request = with_host_header(b"GET / HTTP/1.0\r\n\r\n", "example.com");
+        assert_eq!(
+            request.unwrap(),
+            b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_with_host_header_skips_existing_host() {
+        let request = with_host_header(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", "example.com");
+        assert!(request.is_none());
+    }
+
+    #[test]
+    fn test_with_host_header_skips_non_http() {
+        assert!(with_host_header(b"\r\n\r\n", "example.com").is_none());
+        assert!(with_host_header(b"\x16\x03\x01\r\n", "example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_udp_retransmit() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            // drop the first datagram, answer the retransmission
+            server.recv_from(&mut buf).await.unwrap();
+            let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(b"pong", peer).await.unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        let config = ScanConfig {
+            udp_retransmit_interval_ms: 50,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; 64];
+        let bytes_read = run_udp_service_probe(&client, &mut buf, Some(b"ping"), &config)
+            .await
+            .unwrap();
+        assert_eq!(&buf[..bytes_read], b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_udp_from_interface() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (_, peer) = server.recv_from(&mut buf).await.unwrap();
+            server.send_to(b"pong", peer).await.unwrap();
+            peer.ip()
+        });
+
+        let probes = "Probe UDP Ping q|ping|\nmatch echo m/^pong/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let config = ScanConfig {
+            tcp: false,
+            udp: true,
+            interface: Some("127.0.0.2".into()),
+            ..Default::default()
+        };
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        assert_eq!(server.await.unwrap().to_string(), "127.0.0.2");
+    }
+
+    #[tokio::test]
+    async fn test_udp_no_payload() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(server.local_addr().unwrap()).await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            // several retransmit intervals go by without a datagram, then the
+            // service speaks first
+            let mut buf = [0u8; 64];
+            let received = timeout(Duration::from_millis(300), server.recv_from(&mut buf)).await;
+            server.send_to(b"banner", client_addr).await.unwrap();
+            received.is_err()
+        });
+
+        let config = ScanConfig {
+            udp_retransmit_interval_ms: 50,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; 64];
+        let bytes_read = run_udp_service_probe(&client, &mut buf, None, &config)
+            .await
+            .unwrap();
+        assert_eq!(&buf[..bytes_read], b"banner");
+        assert!(server.await.unwrap(), "a datagram was sent");
+    }
+
+    const FALLBACK_PROBES: &str = "\
+Probe TCP NULL q||
+match ftp m/^220/
+Probe TCP Generic q|generic|
+rarity 3
+ports 80,21
+sslports 443
+match generic m/^generic/
+";
+
+    #[test]
+    fn test_match_response_null_fallback() {
+        let probes =
+            read_service_probes(FALLBACK_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let null = &probes.tcp_probes[0];
+        let generic = &probes.tcp_probes[1];
+
+        let config = &ScanConfig {
+            probe_expected_ports: true,
+            ..Default::default()
+        };
+        assert!(match_response(b"220 ftp", generic, None, config).is_err());
+        let d = match_response(b"220 ftp", generic, Some(null), config).unwrap();
+        assert_eq!(d.service_match.service, "ftp");
+        // the ports and rarity come from the probe whose match was found
+        assert_eq!(d.probe_ports, Some(vec![]));
+        assert_eq!(d.probe_rarity, None);
+        // the probe's own matches take priority over the fallback
+        let d = match_response(b"generic", generic, Some(null), config).unwrap();
+        assert_eq!(d.service_match.service, "generic");
+        assert_eq!(d.probe_ports, Some(vec![21, 80, 443]));
+        // and are only looked up when they're output
+        let d = match_response(b"generic", generic, None, &ScanConfig::default()).unwrap();
+        assert_eq!(d.probe_ports, None);
+        assert_eq!(d.probe_rarity, Some(3));
+    }
+
+    #[test]
+    fn test_match_response_strip_prefix() {
+        let probes = "Probe TCP Framed q|hello|\nstripprefix length 2\nmatch framed m/^hello/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let framed = &probes.tcp_probes[0];
+        let config = &ScanConfig::default();
+
+        // matched behind the framing, reported with it
+        let d = match_response(b"\x00\x05hello", framed, None, config).unwrap();
+        assert_eq!(d.service_match.service, "framed");
+        assert_eq!(d.response, encode(b"\x00\x05hello"));
+        assert!(match_response(b"hello", framed, None, config).is_err());
+    }
+
+    const ECHO_PROBES: &str = "\
+Probe TCP One q|one|
+match nope m/^nope/
+Probe TCP Two q|two|
+match nope m/^nope/
+Probe TCP Three q|three|
+match echo m/^three-ok/
+";
+
+    // Accepts tls with the test certificate and echoes back what it's sent
+    // with -ok appended, counting the bytes it's sent
+    async fn tls_echo_server() -> (u16, Arc<AtomicUsize>) {
+        use crate::certificate::test_certificate;
+
+        let identity = native_tls::Identity::from_pkcs8(
+            test_certificate::cert_pem().as_bytes(),
+            test_certificate::KEY.as_bytes(),
+        )
+        .unwrap();
+        let acceptor: tokio_native_tls::TlsAcceptor =
+            native_tls::TlsAcceptor::new(identity).unwrap().into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (acceptor, counter) = (acceptor.clone(), counter.clone());
+                tokio::spawn(async move {
+                    let mut stream = match acceptor.accept(socket).await {
+                        Ok(stream) => stream,
+                        Err(_) => return,
+                    };
+                    let mut buf = [0u8; 64];
+                    loop {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => {
+                                counter.fetch_add(n, std::sync::atomic::Ordering::SeqCst);
+                                let mut response = buf[..n].to_vec();
+                                response.extend_from_slice(b"-ok");
+                                if stream.write_all(&response).await.is_err() {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (port, received)
+    }
+
+    // Trusts the test certificate, along with any other
+    fn test_tls_connector() -> TlsConnector {
+        native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    async fn scan_echo_server(reuse_connections: bool) -> (RadarOutput, usize) {
+        let (port, connections) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let target = Target::new("127.0.0.1", port);
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+        let config = ScanConfig {
+            reuse_connections,
+            ..Default::default()
+        };
+        let output = scan(target, &probes, Some(&cx), &config).await;
+        (
+            output,
+            connections.load(std::sync::atomic::Ordering::SeqCst),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_connect_from_interface() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let host = format!("127.0.0.1:{}", port);
+        let stream = connect_with_timeout(&host, Some("127.0.0.1"))
+            .await
+            .unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip().to_string(), "127.0.0.1");
+
+        let e = check_interface("192.0.2.1").unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("failed to bind to interface 192.0.2.1"));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_down() {
+        // nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let proxies = vec!["127.0.0.1:1".to_string(), proxy.clone()];
+        let t = Target::new("127.0.0.1", 80);
+        assert_eq!(proxy_for(&t, &proxies), proxy_for(&t, &proxies));
+
+        let e = connect_through_proxy(&t, &proxy, None).await.unwrap_err();
+        assert!(matches!(e, RadarError::Proxy { .. }));
+        assert!(e.to_string().starts_with(&format!("proxy {}: ", proxy)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_scan() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let config = ScanConfig::default();
+        let output = connect_scan(Target::new("127.0.0.1", port), &config).await;
+        assert_eq!(output.port_state, Some(PortState::Open));
+        assert!(output.response.is_none());
+        assert!(output.error.is_none());
+
+        // nothing listens on the port once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let output = connect_scan(Target::new("127.0.0.1", port), &config).await;
+        assert_eq!(output.port_state, Some(PortState::Closed));
+        assert!(output.error.is_some());
+    }
+
+    #[test]
+    fn test_tls_on_no_detection() {
+        let port = 8443;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let t = Target::new("127.0.0.1", port);
+        let config = ScanConfig {
+            tls_on_no_detection: true,
+            tls_ports: vec![port],
+            ..Default::default()
+        };
+        assert!(try_tls_directly(&t, &probes, &config));
+        assert!(!try_tls_directly(
+            &Target::new("127.0.0.1", 1),
+            &probes,
+            &config
+        ));
+        assert!(!try_tls_directly(
+            &t,
+            &probes,
+            &ScanConfig {
+                tls_on_no_detection: false,
+                ..config.clone()
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pre_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"220 banner").await.unwrap();
+            let mut buf = [0u8; 64];
+            socket.read(&mut buf).await.unwrap()
+        });
+
+        let mut stream = TcpStream::connect(&host).await.unwrap();
+        let config = ScanConfig {
+            pre_read_ms: Some(1000),
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; 64];
+        let bytes_read = run_service_probe(
+            &mut stream,
+            &mut buf,
+            b"probe",
+            &config,
+            &mut Timings::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(&buf[..bytes_read], b"220 banner");
+
+        // the probe was never sent
+        drop(stream);
+        assert_eq!(server.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pre_read_min_banner_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"220 ").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            socket.write_all(b"banner").await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = socket.read(&mut buf).await;
+        });
+
+        let mut stream = TcpStream::connect(&host).await.unwrap();
+        let config = ScanConfig {
+            pre_read_ms: Some(1000),
+            min_banner_bytes: 10,
+            ..Default::default()
+        };
+        let mut buf = vec![0u8; 64];
+        let bytes_read = run_service_probe(
+            &mut stream,
+            &mut buf,
+            b"probe",
+            &config,
+            &mut Timings::default(),
+        )
+        .await
+        .unwrap();
+        // both writes, not just the first
+        assert_eq!(&buf[..bytes_read], b"220 banner");
+    }
+
+    #[tokio::test]
+    async fn test_write_chunks() {
+        // answers with the number of reads it took to get the request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 64];
+                let (mut total, mut reads) = (0, 0);
+                while total < 12 {
+                    total += socket.read(&mut buf[total..]).await.unwrap();
+                    reads += 1;
+                }
+                let _ = socket
+                    .write_all(format!("reads {}", reads).as_bytes())
+                    .await;
+            }
+        });
+        let probes = "Probe TCP Chunks q|hello world!|\nmatch chunked m/^reads 3$/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert!(output.service_match.is_none());
+
+        let config = ScanConfig {
+            write_chunk_size: Some(4),
+            write_chunk_delay_ms: Some(50),
+            ..Default::default()
+        };
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "chunked");
+    }
+
+    #[tokio::test]
+    async fn test_no_payload_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"220 ready\r\n").await.unwrap();
+            let mut received = vec![];
+            socket.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let probes = "Probe TCP Quiet q|hello| no-payload\nmatch ftp m/^220 /\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "ftp");
+        assert!(server.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_match_combined_responses() {
+        let probes = "\
+Probe TCP One q|one|
+match nope m/^nope/
+Probe TCP Two q|two|
+match nope m/^nope/
+softmatch combined m/^one-oktwo-ok$/
+";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            Some(&cx),
+            &Default::default(),
+        )
+        .await;
+        assert!(output.service_match.is_none());
+        assert_eq!(output.response, Some(encode("two-ok")));
+
+        let config = ScanConfig {
+            match_combined_responses: true,
+            ..Default::default()
+        };
+        let output = scan(Target::new("127.0.0.1", port), &probes, Some(&cx), &config).await;
+        assert_eq!(output.service_match.unwrap().service, "combined");
+        assert_eq!(output.response, Some(encode("one-oktwo-ok")));
+    }
+
+    #[tokio::test]
+    async fn test_host_limiter() {
+        let limiter = HostLimiter::new(1);
+        let a = limiter.acquire("10.0.0.1").await;
+        let _b = limiter.acquire("10.0.0.2").await;
+        assert_eq!(limiter.hosts(), 2);
+
+        // a second scan of 10.0.0.1 waits for the first to finish
+        let waiting = timeout(Duration::from_millis(50), limiter.acquire("10.0.0.1")).await;
+        assert!(waiting.is_err());
+        drop(a);
+        let a = limiter.acquire("10.0.0.1").await;
+
+        drop(a);
+        assert_eq!(limiter.hosts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_host_limit_other_hosts_run() {
+        let slow_port = slow_and_fast_server().await;
+        let listener = TcpListener::bind("127.0.0.2:0").await.unwrap();
+        let fast_port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 64];
+                let n = socket.read(&mut buf).await.unwrap_or(0);
+                let mut response = buf[..n].to_vec();
+                response.extend_from_slice(b"-ok");
+                let _ = socket.write_all(&response).await;
+            }
+        });
+        let probes = "Probe TCP Slow q|slow|\nmatch echo m/^slow-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        let config = ScanConfig {
+            per_host_concurrency: Some(1),
+            max_concurrent_scans: 2,
+            ..Default::default()
+        };
+        // the slow host's targets wait on each other, not the fast host's
+        let mut targets = vec![Target::new("127.0.0.1", slow_port); 3];
+        targets.push(Target::new("127.0.0.2", fast_port));
+        let scan = tokio::spawn(start_scan(
+            futures::stream::iter(targets),
+            probes,
+            tx,
+            config,
+        ));
+
+        let first = rx.recv().await.unwrap();
+        assert_eq!(first.target.ip, "127.0.0.2");
+        scan.abort();
+    }
+
+    #[tokio::test]
+    async fn test_target_host_resolver_failure() {
+        // a name server that never answers, so resolving fails once the one
+        // short attempt times out
+        let name_server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut options = ResolverOpts::default();
+        options.timeout = Duration::from_millis(100);
+        options.attempts = 1;
+        let resolver = Resolver::with_options(name_server.local_addr().unwrap(), options);
+        let config = ScanConfig {
+            resolver: Some(resolver),
+            ..Default::default()
+        };
+        assert_eq!(
+            target_host(&Target::new("10.0.0.1", 80), &config)
+                .await
+                .unwrap(),
+            "10.0.0.1:80"
+        );
+        let e = target_host(&Target::new("host.invalid", 80), &config)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("host.invalid"));
+    }
+
+    #[test]
+    fn test_transports() {
+        let config = |tcp, udp| ScanConfig {
+            tcp,
+            udp,
+            ..Default::default()
+        };
+        assert_eq!(
+            config(true, false).transports(),
+            vec![TransportProtocol::TCP]
+        );
+        assert_eq!(
+            config(false, true).transports(),
+            vec![TransportProtocol::UDP]
+        );
+        assert_eq!(
+            config(true, true).transports(),
+            vec![TransportProtocol::TCP, TransportProtocol::UDP]
+        );
+        assert!(config(false, false).transports().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_start_scan_no_transport() {
+        let (port, connections) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        let config = ScanConfig {
+            tcp: false,
+            udp: false,
+            ..Default::default()
+        };
+        let targets = futures::stream::iter(vec![Target::new("127.0.0.1", port)]);
+        start_scan(targets, probes, tx, config).await;
+
+        assert!(rx.recv().await.is_none());
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_only_with_udp() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        let config = ScanConfig {
+            tcp: true,
+            udp: true,
+            tcp_connect_only: true,
+            ..Default::default()
+        };
+        let targets = futures::stream::iter(vec![Target::new("127.0.0.1", port)]);
+        start_scan(targets, probes, tx, config).await;
+
+        let mut outputs = vec![];
+        while let Some(output) = rx.recv().await {
+            outputs.push(output);
+        }
+        // one connect record for tcp, udp is scanned as usual
+        let tcp: Vec<_> = outputs.iter().filter(|o| o.port_state.is_some()).collect();
+        assert_eq!(tcp.len(), 1);
+        assert_eq!(tcp[0].transport, Some(TransportProtocol::TCP));
+        assert!(outputs
+            .iter()
+            .all(|o| o.port_state.is_some() || o.transport == Some(TransportProtocol::UDP)));
+    }
+
+    // Collects formatted log lines
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_scan_skips_port_zero() {
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let rows = format!("10.0.0.9,,0\n127.0.0.1,,{}\n", port);
+        let targets: Vec<Target> = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(rows.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        start_scan(
+            futures::stream::iter(targets),
+            probes,
+            tx,
+            Default::default(),
+        )
+        .await;
+
+        let output = rx.recv().await.unwrap();
+        assert_eq!(output.target.port, port);
+        assert!(rx.recv().await.is_none());
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("WARN"));
+        assert!(logs.contains("skipping 10.0.0.9, port 0 can't be scanned"));
+    }
+
+    #[tokio::test]
+    async fn test_start_scan_run_label() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+        let config = ScanConfig {
+            label: Some("weekly".into()),
+            run_id: Some("run-1".into()),
+            ..Default::default()
+        };
+        start_scan(
+            futures::stream::iter(vec![Target::new("127.0.0.1", port)]),
+            probes,
+            tx,
+            config,
+        )
+        .await;
+
+        let output = rx.recv().await.unwrap();
+        assert_eq!(output.label.as_deref(), Some("weekly"));
+        assert_eq!(output.run_id.as_deref(), Some("run-1"));
+    }
+
+    #[tokio::test]
+    async fn test_scan_limit() {
+        let (port, connections) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let limit = Arc::new(Semaphore::new(0));
+        let config = ScanConfig {
+            scan_limit: Some(limit.clone()),
+            ..Default::default()
+        };
+        let (tx, mut rx) = mpsc::channel(4);
+        let scans = (0..2).map(|_| {
+            let targets = futures::stream::iter(vec![Target::new("127.0.0.1", port)]);
+            tokio::spawn(start_scan(
+                targets,
+                probes.clone(),
+                tx.clone(),
+                config.clone(),
+            ))
+        });
+        let scans: Vec<_> = scans.collect();
+        drop(tx);
+
+        // no scan starts without a permit
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        limit.add_permits(1);
+        for scan in scans {
+            scan.await.unwrap();
+        }
+        assert!(rx.recv().await.is_some());
+        assert!(rx.recv().await.is_some());
+        assert_eq!(limit.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ramp_up() {
+        // the clock only moves when told to, so the ramp is where it should
+        // be however slow the machine running the test
+        tokio::time::pause();
+        let ramp = RampUp {
+            start: 2,
+            duration_ms: 1000,
+        }
+        .begin(10);
+        // let the ramp's task start its clock
+        tokio::task::yield_now().await;
+        assert_eq!(ramp.semaphore.available_permits(), 2);
+        tokio::time::advance(Duration::from_millis(300)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(ramp.semaphore.available_permits(), 4);
+        tokio::time::advance(Duration::from_millis(1000)).await;
+        tokio::task::yield_now().await;
+        assert_eq!(ramp.semaphore.available_permits(), 10);
+        assert!(ramp.task.is_finished());
+
+        // a start above the max starts at the max
+        let ramp = RampUp {
+            start: 64,
+            duration_ms: 300,
+        }
+        .begin(10);
+        assert_eq!(ramp.semaphore.available_permits(), 10);
+    }
+
+    #[test]
+    fn test_target_probe_column() {
+        let rows = "10.0.0.1,,80\n10.0.0.2,example.com,443,GetRequest\n";
+        let targets: Vec<Target> = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(rows.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(targets[0], Target::new("10.0.0.1", 80));
+        assert_eq!(targets[1].domain.as_deref(), Some("example.com"));
+        assert_eq!(targets[1].probe.as_deref(), Some("GetRequest"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_override() {
+        let (port, connections) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let target = Target {
+            probe: Some("Three".into()),
+            ..Target::new("127.0.0.1", port)
+        };
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+        let output = scan(target, &probes, Some(&cx), &ScanConfig::default()).await;
+
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_scan_timings() {
+        // answers the probe after a pause, so reading takes a while
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 64];
+                    let _ = socket.read(&mut buf).await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let _ = socket.write_all(b"three-ok").await;
+                });
+            }
+        });
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let target = Target {
+            probe: Some("Three".into()),
+            ..Target::new("127.0.0.1", port)
+        };
+
+        let output = scan(target.clone(), &probes, None, &ScanConfig::default()).await;
+        assert!(output.timings.is_none());
+
+        let config = ScanConfig {
+            timings: true,
+            ..Default::default()
+        };
+        let output = scan(target, &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        let timings = output.timings.unwrap();
+        assert!(timings.read_ms >= 90, "{:?}", timings);
+        assert_eq!(timings.tls_ms, 0);
+        assert!(timings.connect_rtt_us.is_some());
+        // the phases fit in the whole scan
+        assert!(
+            timings.connect_ms + timings.read_ms <= timings.total_ms,
+            "{:?}",
+            timings
+        );
+        assert!(timings.total_ms < 5_000, "{:?}", timings);
+    }
+
+    #[tokio::test]
+    async fn test_scan_two_phase() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = "\
+Probe TCP Common q|one|
+rarity 1
+match nope m/^nope/
+Probe TCP Rare q|two|
+rarity 8
+match echo m/^two-ok/
+";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let quick_probes = probes.with_rarity(..=TWO_PHASE_MAX_RARITY);
+        let config = ScanConfig {
+            trace_probes: true,
+            ..Default::default()
+        };
+        let target = Target::new("127.0.0.1", port);
+
+        let outputs = scan_two_phase(target.clone(), &quick_probes, &probes, None, &config).await;
+        let sent = |output: &RadarOutput| -> Vec<String> {
+            output
+                .probe_attempts
+                .iter()
+                .map(|a| a.probe.clone())
+                .collect()
+        };
+        // the open port answered the quick probes, so it's scanned again
+        // with all of them
+        assert_eq!(outputs.len(), 2);
+        let (detect, version) = (&outputs[0], &outputs[1]);
+        assert_eq!(detect.phase, Some(ScanPhase::Detect));
+        assert_eq!(sent(detect), vec!["Common"]);
+        assert!(detect.service_match.is_none());
+        assert_eq!(version.phase, Some(ScanPhase::Version));
+        assert_eq!(sent(version), vec!["Common", "Rare"]);
+        assert_eq!(version.service_match.as_ref().unwrap().service, "echo");
+
+        // nothing listening, nothing to scan again
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap().port();
+        drop(listener);
+        let outputs = scan_two_phase(
+            Target {
+                port: closed,
+                ..target
+            },
+            &quick_probes,
+            &probes,
+            None,
+            &config,
+        )
+        .await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].phase, Some(ScanPhase::Detect));
+    }
+
+    #[tokio::test]
+    async fn test_scan_two_phase_tls() {
+        let (port, _) = tls_echo_server().await;
+        let probes = "\
+Probe TCP Common q|one|
+rarity 1
+match echo m/^one-ok/
+Probe TCP Rare q|two|
+rarity 8
+match echo m/^two-ok/ v/2/
+";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let quick_probes = probes.with_rarity(..=TWO_PHASE_MAX_RARITY);
+        let cx = test_tls_connector();
+        let config = ScanConfig {
+            port_aware_tls: true,
+            tls_ports: vec![port],
+            ..Default::default()
+        };
+
+        // found only inside tls, still scanned again with all the probes
+        let target = Target::new("127.0.0.1", port);
+        let outputs = scan_two_phase(target, &quick_probes, &probes, Some(&cx), &config).await;
+        assert_eq!(outputs.len(), 2);
+        let (detect, version) = (&outputs[0], &outputs[1]);
+        assert!(detect.service_match.is_none() && detect.response.is_none());
+        assert_eq!(detect.tls_service_match.as_ref().unwrap().service, "echo");
+        assert_eq!(version.phase, Some(ScanPhase::Version));
+        assert_eq!(version.tls_service_match.as_ref().unwrap().service, "echo");
+    }
+
+    // Records the events of a scan in order
+    #[derive(Default)]
+    struct RecordingObserver(Mutex<Vec<String>>);
+
+    impl ScanObserver for RecordingObserver {
+        fn connect_started(&self, _: &Target) {
+            self.0.lock().unwrap().push("connect".into());
+        }
+
+        fn connected(&self, _: &Target, tls: bool) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("connected tls={}", tls));
+        }
+
+        fn probe_sent(&self, _: &Target, probe: &Probe) {
+            self.0.lock().unwrap().push(format!("sent {}", probe.name));
+        }
+
+        fn matched(&self, _: &Target, service_match: &Match) {
+            let event = format!("matched {}", service_match.service);
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_observer() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = read_service_probes(ECHO_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let target = Target {
+            probe: Some("Three".into()),
+            ..Target::new("127.0.0.1", port)
+        };
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+        let observer = Arc::new(RecordingObserver::default());
+        let config = ScanConfig {
+            observer: Some(observer.clone()),
+            ..Default::default()
+        };
+        scan(target, &probes, Some(&cx), &config).await;
+
+        assert_eq!(
+            *observer.0.lock().unwrap(),
+            vec![
+                "connect",
+                "connected tls=false",
+                "sent Three",
+                "matched echo"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_without_tls() {
+        let (port, connections) = mock_server(Behavior::Hangup(b"\x16\x03\x01")).await;
+        let probes = "Probe TCP NULL q||\nmatch ssl m/^\\x16\\x03/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "ssl");
+        assert_eq!(output.tls, None);
+        assert_eq!(output.tls_disabled, Some(true));
+        assert!(output.tls_error.is_none());
+        // only the plaintext connection was made
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_only() {
+        let (port, sent) = tls_echo_server().await;
+        let probes = "Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|\n\
+                      match ssl m/^\\x16\\x03/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = test_tls_connector();
+        let config = ScanConfig {
+            tls_cert_only: true,
+            tls_ports: vec![port],
+            ..Default::default()
+        };
+
+        let output = scan(Target::new("127.0.0.1", port), &probes, Some(&cx), &config).await;
+        assert_eq!(output.tls, Some(true));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert_eq!(output.tls_inner_attempted, Some(false));
+        assert!(output.service_match.is_none());
+        assert!(output.tls_service_match.is_none());
+        let certificate = output.tls_certificate.unwrap();
+        assert_eq!(certificate.subject, "CN=radar.test, O=Radar");
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_probe_connector() {
+        let probes = "Probe TCP Any q|any|\n\
+                      Probe TCP Secure q|secure|\ntls required\n\
+                      Probe TCP Plain q|plain|\ntls never\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = test_tls_connector();
+        let over_tls = |i: usize, tls, tls_required| {
+            probe_connector(&probes.tcp_probes[i], tls, tls_required).map(|c| c.is_some())
+        };
+
+        // scanning in plaintext
+        assert_eq!(over_tls(0, None, Some(&cx)), Some(false));
+        assert_eq!(over_tls(1, None, Some(&cx)), Some(true));
+        assert_eq!(over_tls(1, None, None), None);
+        assert_eq!(over_tls(2, None, Some(&cx)), Some(false));
+        // scanning over tls
+        assert_eq!(over_tls(0, Some(&cx), None), Some(true));
+        assert_eq!(over_tls(1, Some(&cx), None), Some(true));
+        assert_eq!(over_tls(2, Some(&cx), None), None);
+    }
+
+    #[tokio::test]
+    async fn test_probe_tls_required() {
+        let probes = "Probe TCP Secure q|secure|\ntls required\nmatch secure m/^secure-ok/\n\
+                      Probe TCP Plain q|plain|\nmatch plain m/^plain-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = test_tls_connector();
+
+        // sent over its own tls while scanning in plaintext
+        let (port, sent) = tls_echo_server().await;
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            Some(&cx),
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.tls, Some(true));
+        assert_eq!(output.tls_handshake, Some(true));
+        assert_eq!(output.tls_service_match.unwrap().service, "secure");
+        assert!(output.service_match.is_none());
+        assert_eq!(sent.load(std::sync::atomic::Ordering::SeqCst), 6);
+
+        // left out with tls off
+        let (port, connections) = mock_server(Behavior::Echo).await;
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "plain");
+        assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_probe_tls_required_plaintext_port() {
+        let probes = "Probe TCP Secure q|secure|\ntls required\nmatch secure m/^secure-ok/\n\
+                      Probe TCP Plain q|plain|\nmatch plain m/^plain-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = test_tls_connector();
+
+        // the handshake fails, the plain probe still runs
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            Some(&cx),
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "plain");
+        assert!(output.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_port_aware_tls() {
+        let (port, connections) = mock_server(Behavior::Hangup(b"\x16\x03\x01")).await;
+        let probes = "Probe TCP NULL q||\nmatch ssl m/^\\x16\\x03/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+        let count = || connections.swap(0, std::sync::atomic::Ordering::SeqCst);
+
+        // not a tls port, tls is never tried
+        let config = ScanConfig {
+            port_aware_tls: true,
+            ..Default::default()
+        };
+        let output = scan(Target::new("127.0.0.1", port), &probes, Some(&cx), &config).await;
+        assert_eq!(output.service_match.unwrap().service, "ssl");
+        assert_eq!(output.tls, Some(false));
+        assert_eq!(count(), 1);
+
+        // a tls port is tried over tls first, and that attempt stands in for
+        // the wrapped scan after plaintext finds ssl
+        let config = ScanConfig {
+            tls_ports: vec![port],
+            ..config
+        };
+        let output = scan(Target::new("127.0.0.1", port), &probes, Some(&cx), &config).await;
+        assert_eq!(output.service_match.unwrap().service, "ssl");
+        assert_eq!(output.tls, Some(true));
+        assert!(output.tls_error.is_some());
+        assert_eq!(count(), 2);
+    }
+
+    #[test]
+    fn test_http_header_detection() {
+        let response = b"HTTP/1.1 200 OK\r\nserver: Apache/2.4.41 (Ubuntu)\r\nX-Powered-By: PHP/7.4.3\r\n\r\n<html>";
+        let d = http_header_detection(response).unwrap();
+        assert_eq!(d.service_match.service, "http");
+        assert_eq!(
+            d.service_match.version_info,
+            "p/Apache/ v/2.4.41/ i|X-Powered-By: PHP/7.4.3|"
+        );
+        assert!(d.service_match.re.is_match(response).unwrap());
+
+        let d = http_header_detection(b"HTTP/1.0 404 Not Found\r\nServer: tinyhttpd\r\n\r\n");
+        assert_eq!(d.unwrap().service_match.version_info, "p/tinyhttpd/");
+
+        // no product, or not http
+        assert!(http_header_detection(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").is_none());
+        assert!(http_header_detection(b"220 Server: ftp\r\n").is_none());
+        // headers in the body don't count
+        assert!(http_header_detection(b"HTTP/1.1 200 OK\r\n\r\nServer: nginx\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_closed_without_banner() {
+        let (port, _) = mock_server(Behavior::Hangup(b"")).await;
+        let probes =
+            read_service_probes(FALLBACK_PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let cx = native_tls::TlsConnector::new().unwrap().into();
+
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            Some(&cx),
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.closed_without_banner, Some(true));
+
+        let (port, _) = mock_server(Behavior::Hangup(b"220 ftp")).await;
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            Some(&cx),
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "ftp");
+        assert_eq!(output.closed_without_banner, None);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_wrapped() {
+        let (port, _) = mock_server(Behavior::Hangup(b"")).await;
+        let probes = "Probe TCP NULL q||\ntcpwrappedms 3000\nmatch ftp m/^220/\n\
+                      Probe TCP Generic q|generic|\nmatch generic m/^generic/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        for concurrent_probes in [false, true] {
+            let config = ScanConfig {
+                concurrent_probes,
+                ..Default::default()
+            };
+            let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+            let service_match = output.service_match.unwrap();
+            assert_eq!(service_match.service, "tcpwrapped");
+            assert_eq!(output.port_state, Some(PortState::Open));
+        }
+
+        // closed later than tcpwrappedms, it's just a service with no banner
+        let probes = "Probe TCP NULL q||\ntcpwrappedms 0\nmatch ftp m/^220/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert!(output.service_match.is_none());
+        assert_eq!(output.closed_without_banner, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_tls_name() {
+        let mut t = Target::new("127.0.0.1", 443);
+        assert_eq!(t.tls_name(), "127.0.0.1");
+        t.domain = Some("example.com".into());
+        assert_eq!(t.tls_name(), "example.com");
+        t.sni = Some("front.example.net".into());
+        assert_eq!(t.tls_name(), "front.example.net");
+
+        // the sni goes in the client hello, the connection is to the ip
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        t.port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut hello = vec![0u8; 4096];
+            let n = socket.read(&mut hello).await.unwrap();
+            hello.truncate(n);
+            hello
+        });
+        let config = ScanConfig {
+            tls_cert_only: true,
+            tls_ports: vec![t.port],
+            ..Default::default()
+        };
+        let probes = read_service_probes(&b""[..], &ParseOptions::default()).unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        start_scan(futures::stream::iter(vec![t]), probes, tx, config).await;
+        assert!(rx.recv().await.is_some());
+        let hello = server.await.unwrap();
+        let has = |name: &[u8]| hello.windows(name.len()).any(|w| w == name);
+        assert!(has(b"front.example.net"));
+        assert!(!has(b"example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connections() {
+        let (output, connections) = scan_echo_server(true).await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        assert_eq!(connections, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connections_late_answer() {
+        // a banner that comes late on the first connection, an echo after
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    if i == 0 {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                        let _ = socket.write_all(b"220 late\r\n").await;
+                    }
+                    let mut buf = [0u8; 64];
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    let mut response = buf[..n].to_vec();
+                    response.extend_from_slice(b"-ok");
+                    let _ = socket.write_all(&response).await;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                });
+            }
+        });
+        let probes = "Probe TCP NULL q||\nmatch ftp m/^220 ready/\n\
+                      Probe TCP Two q|two|\nmatch wrong m/^220 late/\nmatch echo m/^two-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let config = ScanConfig {
+            reuse_connections: true,
+            adaptive_timeout: Some(AdaptiveTimeout {
+                rtt_multiplier: 1,
+                min_ms: 300,
+                max_ms: 300,
+            }),
+            ..Default::default()
+        };
+
+        // the banner comes after the NULL probe timed out, and isn't taken
+        // for the next probe's response
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+    }
+
+    #[tokio::test]
+    async fn test_reuse_connections_reconnect_refused() {
+        // answers once, then stops listening
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            drop(listener);
+            let mut buf = [0u8; 64];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.write_all(b"hello").await;
+        });
+        let probes = "Probe TCP One q|one|\nmatch nope m/^nope/\n\
+                      Probe TCP Two q|two|\nmatch nope m/^nope/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let config = ScanConfig {
+            reuse_connections: true,
+            ..Default::default()
+        };
+
+        // what the first probe got back outlives the failed reconnect
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.error.as_deref(), Some("No Detection"));
+        assert_eq!(output.response, Some(encode("hello")));
+        assert_eq!(output.response_probe.as_deref(), Some("One"));
+    }
+
+    #[tokio::test]
+    async fn test_without_reuse_connections() {
+        let (output, connections) = scan_echo_server(false).await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        assert_eq!(connections, 3);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_after_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let n = socket.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            socket.write_all(b"pong").await.unwrap();
+            // the client still holds the connection, only its write side is closed
+            let eof = timeout(Duration::from_secs(1), socket.read(&mut buf)).await;
+            matches!(eof, Ok(Ok(0)))
+        });
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = vec![0u8; 64];
+        let config = ScanConfig::default();
+        let bytes_read = run_service_probe(
+            &mut stream,
+            &mut buf,
+            b"ping",
+            &config,
+            &mut Timings::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(&buf[..bytes_read], b"pong");
+        assert!(server.await.unwrap(), "no eof after the probe");
+        drop(stream);
+    }
+
+    #[tokio::test]
+    async fn test_incremental_match() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let _ = socket.write_all(b"220-mail.example.com\r\n").await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let _ = socket.write_all(b"220 ESMTP ready\r\n").await;
+                    // held open, the match shouldn't wait on the peer
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                });
+            }
+        });
+        let probes = "Probe TCP NULL q||\nmatch smtp m|^220-[^\\r]*\\r\\n220 ESMTP|\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        // matched on the first read only, the greeting is cut short
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert!(output.service_match.is_none());
+
+        let config = ScanConfig {
+            incremental_match_attempts: Some(4),
+            ..Default::default()
+        };
+        let start = std::time::Instant::now();
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "smtp");
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_no_detection_retry() {
+        // hangs up on the first connection, warmed up after that
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                if i > 0 {
+                    let _ = socket.write_all(b"220 ready\r\n").await;
+                }
+            }
+        });
+        let probes = "Probe TCP NULL q||\nmatch ftp m|^220|\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let config = ScanConfig {
+            no_detection_retry_ms: Some(10),
+            ..Default::default()
+        };
+
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "ftp");
+        assert_eq!(output.no_detection_retried, Some(true));
+
+        // a detection the first time isn't retried
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "ftp");
+        assert_eq!(output.no_detection_retried, None);
+    }
+
+    #[test]
+    fn test_adaptive_read_timeout() {
+        let adaptive = AdaptiveTimeout {
+            rtt_multiplier: 10,
+            min_ms: 100,
+            max_ms: 1000,
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(adaptive.read_timeout(Some(ms(30))), ms(300));
+        assert_eq!(
+            adaptive.read_timeout(Some(Duration::from_micros(200))),
+            ms(100)
+        );
+        assert_eq!(adaptive.read_timeout(Some(ms(500))), ms(1000));
+        assert_eq!(adaptive.read_timeout(None), ms(1000));
+
+        let mut timings = Timings::default();
+        timings.observe_connect_rtt(ms(80));
+        assert_eq!(timings.connect_rtt(), Some(ms(80)));
+        timings.observe_connect_rtt(ms(160));
+        assert_eq!(timings.connect_rtt(), Some(ms(90)));
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_timeout_scan() {
+        let (port, _) = mock_server(Behavior::Silent).await;
+        let probes = "Probe TCP NULL q||\nmatch ftp m|^220|\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let config = ScanConfig {
+            adaptive_timeout: Some(AdaptiveTimeout {
+                rtt_multiplier: 10,
+                min_ms: 200,
+                max_ms: 5000,
+            }),
+            timings: true,
+            ..Default::default()
+        };
+
+        // a loopback round trip is next to nothing, so the read gives up at
+        // the floor rather than after five seconds
+        let start = std::time::Instant::now();
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(output.service_match.is_none());
+        assert!(output.timings.unwrap().connect_rtt_us.is_some());
+    }
+
+    // Answers fast right away, and slow only after a second
+    async fn slow_and_fast_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 64];
+                    let n = socket.read(&mut buf).await.unwrap_or(0);
+                    if &buf[..n] == b"slow" {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    let mut response = buf[..n].to_vec();
+                    response.extend_from_slice(b"-ok");
+                    let _ = socket.write_all(&response).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_probes() {
+        let port = slow_and_fast_server().await;
+        let config = ScanConfig {
+            concurrent_probes: true,
+            ..Default::default()
+        };
+        let probes = "Probe TCP Slow q|slow|\nmatch slow m/^slow-ok/\n\
+                      Probe TCP Fast q|fast|\nmatch fast m/^fast-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        // the fast match wins and the slow probe is dropped
+        let start = std::time::Instant::now();
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "fast");
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // a softmatch waits on the other probes for a hard match
+        let probes = "Probe TCP Slow q|slow|\nmatch slow m/^slow-ok/\n\
+                      Probe TCP Fast q|fast|\nsoftmatch fast m/^fast-ok/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+        assert_eq!(output.service_match.unwrap().service, "slow");
+    }
+
+    #[tokio::test]
+    async fn test_sequential_softmatch() {
+        let (port, _) = mock_server(Behavior::Echo).await;
+        let probes = "Probe TCP One q|one|\nsoftmatch echo m/^one-ok/\n\
+                      Probe TCP Two q|two|\nmatch echo m/^two-ok/ v/2/\n\
+                      Probe TCP Three q|three|\nmatch nope m/^nope/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+
+        // the probes after a softmatch are still sent for a hard match, as
+        // when they're sent at once
+        for concurrent_probes in [false, true] {
+            let config = ScanConfig {
+                concurrent_probes,
+                ..Default::default()
+            };
+            let output = scan(Target::new("127.0.0.1", port), &probes, None, &config).await;
+            assert_eq!(output.service_match.unwrap().version_info, "v/2/");
+            assert_eq!(output.soft_match, None);
+        }
+
+        // with no hard match the softmatch stands
+        let probes = "Probe TCP One q|one|\nsoftmatch echo m/^one-ok/\n\
+                      Probe TCP Three q|three|\nmatch nope m/^nope/\n";
+        let probes = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let output = scan(
+            Target::new("127.0.0.1", port),
+            &probes,
+            None,
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(output.service_match.unwrap().service, "echo");
+        assert_eq!(output.soft_match, Some(true));
+    }
+}
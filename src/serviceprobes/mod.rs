@@ -1,32 +1,121 @@
 use pcre2::bytes::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
 pub mod parse;
 
+use parse::match_directive::{parse_version_info, VersionInfo};
+
 #[derive(Clone, Debug, Serialize)]
 pub struct ServiceProbe {
     pub probe: Probe,
     pub directives: ProbeDirectives,
 }
+/// A match line that matched a probe's response
+#[derive(Clone, Debug)]
+pub struct MatchOutcome {
+    pub service_match: Match,
+    /// Whether it was a softmatch, which names the service but not the
+    /// version, so other probes may still find a hard match
+    pub soft: bool,
+    /// Name of the probe the match line belongs to
+    pub probe: String,
+}
+
 impl ServiceProbe {
-    pub fn check_match(&self, response: &[u8]) -> Option<Match> {
-        let empty: Vec<Match> = vec![];
-        let matches = self.directives.matches.as_ref().unwrap_or(&empty);
-        let soft_matches = self.directives.soft_matches.as_ref().unwrap_or(&empty);
-        for service_match in matches {
-            let service_match_result = get_match(&service_match, response);
-            if service_match_result.is_some() {
-                return service_match_result;
-            }
-        }
-        for service_match in soft_matches {
-            let service_match_result = get_match(&service_match, response);
-            if service_match_result.is_some() {
-                return service_match_result;
-            }
+    /// The first match, then the first softmatch, that matches the response,
+    /// after stripping any framing the probe's stripprefix directive names
+    pub fn check_match(&self, response: &[u8]) -> Option<MatchOutcome> {
+        let response = match self.directives.strip_prefix {
+            Some(strip_prefix) => strip_prefix.strip(response),
+            None => response,
+        };
+        let outcome = |service_match, soft| MatchOutcome {
+            service_match,
+            soft,
+            probe: self.probe.name.clone(),
+        };
+        let matches = self.directives.matches.iter().flatten();
+        let soft_matches = self.directives.soft_matches.iter().flatten();
+        matches
+            .filter_map(|m| get_match(m, response).map(|m| outcome(m, false)))
+            .chain(soft_matches.filter_map(|m| get_match(m, response).map(|m| outcome(m, true))))
+            .next()
+    }
+
+    // Give each match and softmatch its id, once all of them are read
+    fn tag_matches(&mut self) {
+        let (protocol, name) = (&self.probe.transport_protocol, &self.probe.name);
+        let matches = self.directives.matches.iter_mut().flatten();
+        let soft_matches = self.directives.soft_matches.iter_mut().flatten();
+        for (i, m) in matches.chain(soft_matches).enumerate() {
+            m.id = Some(format!("{:?}/{}/{}", protocol, name, i + 1));
         }
+    }
+
+    /// Every match and softmatch, matches first, with whether it's soft
+    pub fn signatures(&self) -> impl Iterator<Item = (&Match, bool)> {
+        let matches = self.directives.matches.iter().flatten();
+        let soft_matches = self.directives.soft_matches.iter().flatten();
+        matches
+            .map(|m| (m, false))
+            .chain(soft_matches.map(|m| (m, true)))
+    }
+
+    /// The probe's rarity from 1, sent at every intensity, to 9, sent only at
+    /// the highest. A probe without a rarity line is taken as rarity 0 so it's
+    /// always sent.
+    pub fn rarity(&self) -> usize {
+        self.directives.rarity.unwrap_or(0)
+    }
+
+    /// The rarity from the probe's rarity line, None without one
+    pub fn declared_rarity(&self) -> Option<usize> {
+        self.directives.rarity
+    }
+
+    pub fn tls(&self) -> Option<ProbeTls> {
+        self.directives.tls
+    }
 
-        None
+    /// From the tcpwrappedms directive, a connection closed this soon after
+    /// it was accepted without sending anything is taken as a tcp wrapper
+    /// turning us away
+    pub fn tcp_wrapped_ms(&self) -> Option<usize> {
+        self.directives.tcp_wrapped_ms
+    }
+
+    /// Whether this is the NULL probe, which sends nothing and waits for a banner
+    pub fn is_null(&self) -> bool {
+        self.probe.name == "NULL"
+    }
+
+    /// Whether nmap sends this probe to the port at a version intensity, as
+    /// with `-sV --version-intensity`. The NULL probe is always sent, as is a
+    /// probe listing the port in its ports or sslports. Any other probe is
+    /// sent when its rarity is at most the intensity.
+    pub fn sent_at_intensity(&self, intensity: usize, port: u16) -> bool {
+        self.is_null() || self.rarity() <= intensity || self.expected_ports().contains(&port)
+    }
+
+    /// The probe's ports and sslports together, the ports its services are
+    /// usually found on
+    pub fn expected_ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self
+            .directives
+            .ports
+            .iter()
+            .chain(self.directives.ssl_ports.iter())
+            .flatten()
+            .copied()
+            .collect();
+        ports.sort_unstable();
+        ports.dedup();
+        ports
     }
 }
 
@@ -34,6 +123,67 @@ impl ServiceProbe {
 pub struct ServiceProbes {
     pub tcp_probes: Vec<ServiceProbe>,
     pub udp_probes: Vec<ServiceProbe>,
+    pub exclude: Option<Exclude>,
+    /// Match lines left out because their pattern didn't compile, when
+    /// loading leniently
+    pub skipped_matches: usize,
+    /// Every line the loader left out, and why
+    pub skipped_lines: Vec<SkippedLine>,
+}
+
+/// A line of the probes file that was left out when loading it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SkippedLine {
+    pub line: usize,
+    /// The line as written, or its start if it was too long to read
+    pub text: String,
+    pub reason: SkipReason,
+    /// The error behind the reason, such as why a pattern didn't compile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The included file the line is in, None for lines of the probes file
+    /// itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// longer than the max line length
+    LineTooLong,
+    /// an Exclude after the first Probe
+    MisplacedExclude,
+    MalformedExclude,
+    MalformedProbe,
+    MalformedPayloadfile,
+    /// a match or softmatch without a delimited pattern
+    MalformedMatch,
+    /// a match or softmatch whose pattern doesn't compile
+    BadPattern,
+    /// a directive without a value
+    TooShort,
+    /// a directive whose value isn't one it takes
+    MalformedDirective,
+    /// a directive that isn't one nmap or radar has
+    UnknownDirective,
+    /// a directive with no Probe to apply to, before the first or after one
+    /// that was skipped
+    NoProbe,
+}
+
+/// Ports the probes file says not to scan, from its Exclude directive
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Exclude {
+    pub tcp_ports: Vec<u16>,
+    pub udp_ports: Vec<u16>,
+}
+
+impl Exclude {
+    fn extend(&mut self, other: Exclude) {
+        self.tcp_ports.extend(other.tcp_ports);
+        self.udp_ports.extend(other.udp_ports);
+    }
 }
 
 impl ServiceProbes {
@@ -41,6 +191,58 @@ impl ServiceProbes {
         Self {
             tcp_probes: vec![],
             udp_probes: vec![],
+            exclude: None,
+            skipped_matches: 0,
+            skipped_lines: vec![],
+        }
+    }
+
+    /// Whether the Exclude directive rules out scanning the port
+    pub fn excludes(&self, protocol: &TransportProtocol, port: u16) -> bool {
+        match (&self.exclude, protocol) {
+            (Some(exclude), TransportProtocol::TCP) => exclude.tcp_ports.contains(&port),
+            (Some(exclude), TransportProtocol::UDP) => exclude.udp_ports.contains(&port),
+            (None, _) => false,
+        }
+    }
+
+    /// Whether any probe lists the port in its sslports
+    pub fn is_ssl_port(&self, port: u16) -> bool {
+        self.tcp_probes
+            .iter()
+            .filter_map(|p| p.directives.ssl_ports.as_ref())
+            .any(|ports| ports.contains(&port))
+    }
+
+    /// The distinct service names of every match and softmatch, tcp and udp
+    pub fn services(&self) -> BTreeSet<String> {
+        self.tcp_probes
+            .iter()
+            .chain(self.udp_probes.iter())
+            .flat_map(|p| {
+                let matches = p.directives.matches.iter().flatten();
+                matches.chain(p.directives.soft_matches.iter().flatten())
+            })
+            .map(|m| m.service.clone())
+            .collect()
+    }
+
+    /// The probes whose rarity falls in the given range, counting probes
+    /// without a rarity as rarity 0. The NULL probe is always included.
+    pub fn with_rarity<R: RangeBounds<usize>>(&self, rarity: R) -> ServiceProbes {
+        let keep = |probes: &Vec<ServiceProbe>| {
+            probes
+                .iter()
+                .filter(|p| p.is_null() || rarity.contains(&p.rarity()))
+                .cloned()
+                .collect()
+        };
+        ServiceProbes {
+            tcp_probes: keep(&self.tcp_probes),
+            udp_probes: keep(&self.udp_probes),
+            exclude: self.exclude.clone(),
+            skipped_matches: self.skipped_matches,
+            skipped_lines: self.skipped_lines.clone(),
         }
     }
 }
@@ -55,6 +257,65 @@ pub struct ProbeDirectives {
     tcp_wrapped_ms: Option<usize>,
     rarity: Option<usize>,
     fallback: Option<Vec<String>>,
+    tls: Option<ProbeTls>,
+    strip_prefix: Option<StripPrefix>,
+}
+
+/// How a probe is sent with regard to tls, from its tls directive, a radar
+/// extension to the probes file that nmap doesn't have. Without one a probe
+/// goes over whatever connection the scan is making, plaintext or tls.
+///
+/// This is separate from sslports, which only lists the ports the probe's
+/// services usually run tls on. Those make a port a tls port, deciding when
+/// a scan goes over tls, whatever the probe's tls directive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeTls {
+    /// `tls required`, only sent over tls. When scanning in plaintext the
+    /// probe gets a tls connection of its own, and when tls is off it isn't
+    /// sent.
+    Required,
+    /// `tls never`, left out when scanning over tls
+    Never,
+}
+
+/// Framing to strip from the front of a response before running a probe's
+/// matches on it, from its stripprefix directive, a radar extension to the
+/// probes file. Only matching sees the stripped response, the response
+/// reported is the one received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StripPrefix {
+    /// `stripprefix <n>`, a header of n bytes
+    Bytes(usize),
+    /// `stripprefix length <n>`, a big endian length of n bytes, 1 to 8,
+    /// followed by that many bytes of payload. Whatever follows the payload
+    /// is left out too.
+    Length(usize),
+}
+
+impl StripPrefix {
+    /// The response without its framing. A response too short to hold the
+    /// framing is matched as empty, and a payload cut short is matched as far
+    /// as it was received.
+    pub fn strip<'a>(&self, response: &'a [u8]) -> &'a [u8] {
+        match *self {
+            StripPrefix::Bytes(n) => response.get(n..).unwrap_or(&[]),
+            StripPrefix::Length(n) => {
+                let (length, payload) = match response.get(..n) {
+                    Some(length) => (length, &response[n..]),
+                    None => return &[],
+                };
+                let length = length
+                    .iter()
+                    .fold(0u64, |length, b| (length << 8) | u64::from(*b));
+                match usize::try_from(length) {
+                    Ok(length) if length < payload.len() => &payload[..length],
+                    _ => payload,
+                }
+            }
+        }
+    }
 }
 
 impl ProbeDirectives {
@@ -68,10 +329,12 @@ impl ProbeDirectives {
             tcp_wrapped_ms: None,
             rarity: None,
             fallback: None,
+            tls: None,
+            strip_prefix: None,
         }
     }
 }
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub enum TransportProtocol {
     TCP,
     UDP,
@@ -94,6 +357,7 @@ pub struct Probe {
     pub transport_protocol: TransportProtocol,
     pub name: String,
     pub data: Vec<u8>,
+    /// Marked no-payload, the probe's data is never sent
     pub no_payload: bool,
 }
 
@@ -101,32 +365,102 @@ pub struct Probe {
 pub struct Match {
     pub service: String,
     pub pattern: String,
+    /// Shared by every match with the same pattern and options
     #[serde(skip_serializing)]
-    pub re: Regex,
+    pub re: Arc<Regex>,
     pub pattern_options: String,
     pub version_info: String,
+    /// The probe's protocol and name and the line's position among the
+    /// probe's matches then softmatches, as in TCP/NULL/3, set when loaded
+    /// from a probes file.
+    /// It stays the same across scans with the same probes file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The version info split into its fields, set on the match found for a
+    /// response with its captures substituted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<VersionInfo>,
+}
+
+/// The outcome of a match line matching a response
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchResult {
+    pub service: String,
+    /// The version info with `$1`..`$9` replaced by the captured groups
+    pub version_info: String,
+    /// Every capture group, group 0 is the whole match
+    pub captures: Vec<Option<Vec<u8>>>,
+    /// The version info split into its fields, captures substituted into
+    /// each, None when the version info is malformed
+    pub fields: Option<VersionInfo>,
+}
+
+impl Match {
+    /// Run this match line against a response, returning None if it doesn't match.
+    ///
+    /// ```
+    /// use radar::serviceprobes::parse::match_directive::parse_match_line;
+    ///
+    /// let m = parse_match_line(r"match ftp m/^220 ([\w-]+) FTP/ p/$1/").unwrap();
+    /// let result = m.test(b"220 Pure-FTPd FTP server ready").unwrap();
+    /// assert_eq!(result.service, "ftp");
+    /// assert_eq!(result.version_info, "p/Pure-FTPd/");
+    /// assert_eq!(result.captures[1].as_deref(), Some(&b"Pure-FTPd"[..]));
+    ///
+    /// assert!(m.test(b"SSH-2.0-OpenSSH_8.9").is_none());
+    /// ```
+    pub fn test(&self, response: &[u8]) -> Option<MatchResult> {
+        let captures = self.re.captures(response).unwrap_or_else(|e| {
+            panic!(
+                "failed to run regex {} on response {:?} with error {}",
+                self.pattern, response, e
+            )
+        })?;
+        let captures: Vec<Option<Vec<u8>>> = (0..captures.len())
+            .map(|i| captures.get(i).map(|m| m.as_bytes().to_vec()))
+            .collect();
+
+        // split before substituting, so a captured delimiter can't end a
+        // field early
+        let fields = parse_version_info(&self.version_info)
+            .map(|fields| fields.map(|field| substitute_captures(field, &captures)));
+        Some(MatchResult {
+            service: self.service.clone(),
+            version_info: substitute_captures(&self.version_info, &captures),
+            captures,
+            fields,
+        })
+    }
+}
+
+// Replace $1..$9 in version info with the matching capture group, unmatched
+// groups are replaced with nothing
+fn substitute_captures(version_info: &str, captures: &[Option<Vec<u8>>]) -> String {
+    let mut substituted = String::with_capacity(version_info.len());
+    let mut chars = version_info.chars().peekable();
+    while let Some(c) = chars.next() {
+        let group = match (c, chars.peek().and_then(|d| d.to_digit(10))) {
+            ('$', Some(group)) if group > 0 => group as usize,
+            _ => {
+                substituted.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        if let Some(Some(capture)) = captures.get(group) {
+            substituted.push_str(&String::from_utf8_lossy(capture));
+        }
+    }
+    substituted
 }
 
-// if the regex in the service_match matches the response,
-// return a new Match with the version_info field replaced by the capture groups
+// if the regex in the service_match matches the response, return a new Match
+// with the capture groups substituted into version_info, as Match::test does
 pub fn get_match(service_match: &Match, response: &[u8]) -> Option<Match> {
-    if !service_match.re.is_match(response).unwrap_or_else(|e| {
-        panic!(
-            "failed to run regex {} on response {:?} with error {}",
-            service_match.pattern,
-            response,
-            e.to_string()
-        )
-    }) {
-        return None;
-    }
-
-    /*
-    let version_info = service_match
-        .re
-        .replace(response, &service_match.version_info);
-    */
+    let result = service_match.test(response)?;
     Some(Match {
+        version_info: result.version_info,
+        fields: result.fields,
         ..service_match.clone()
     })
 }
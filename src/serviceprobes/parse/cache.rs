@@ -0,0 +1,351 @@
+// A binary cache of a parsed probes file, so startup can skip parsing it.
+// Patterns are stored as written and compiled again on load, the compiled
+// regexes can't be stored.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::time::SystemTime;
+
+use crate::serviceprobes::{
+    parse::{
+        match_directive::RegexCache, read_service_probes_file_with_options, regex_cache,
+        ParseError, ParseOptions,
+    },
+    Exclude, Match, Probe, ProbeDirectives, ProbeTls, ServiceProbe, ServiceProbes, SkippedLine,
+    StripPrefix,
+};
+
+// Bumped whenever the cache layout changes, older caches are rebuilt
+const CACHE_VERSION: u32 = 7;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Io(io::Error),
+    Parse(ParseError),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    /// A cached pattern doesn't compile
+    Regex(pcre2::Error),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CacheError::Io(ref err) => err.fmt(f),
+            CacheError::Parse(ref err) => write!(f, "failed to parse probes file: {}", err),
+            CacheError::Encode(ref err) => write!(f, "failed to write cache: {}", err),
+            CacheError::Decode(ref err) => write!(f, "failed to read cache: {}", err),
+            CacheError::Regex(ref err) => write!(f, "failed to compile cached pattern: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<io::Error> for CacheError {
+    fn from(err: io::Error) -> CacheError {
+        CacheError::Io(err)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProbeCache {
+    version: u32,
+    // modification time of the probes file when the cache was built
+    modified: SystemTime,
+    // the parse options that change what's loaded
+    strict: bool,
+    strict_escapes: bool,
+    max_line_length: usize,
+    ignore_case: bool,
+    anchored: bool,
+    tcp_probes: Vec<CachedProbe>,
+    udp_probes: Vec<CachedProbe>,
+    exclude: Option<Exclude>,
+    skipped_matches: usize,
+    skipped_lines: Vec<SkippedLine>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedProbe {
+    probe: Probe,
+    matches: Option<Vec<CachedMatch>>,
+    soft_matches: Option<Vec<CachedMatch>>,
+    ports: Option<Vec<u16>>,
+    ssl_ports: Option<Vec<u16>>,
+    total_wait_ms: Option<usize>,
+    tcp_wrapped_ms: Option<usize>,
+    rarity: Option<usize>,
+    fallback: Option<Vec<String>>,
+    tls: Option<ProbeTls>,
+    strip_prefix: Option<StripPrefix>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedMatch {
+    service: String,
+    pattern: String,
+    pattern_options: String,
+    version_info: String,
+    id: Option<String>,
+}
+
+impl ProbeCache {
+    fn new(service_probes: &ServiceProbes, modified: SystemTime, options: &ParseOptions) -> Self {
+        let probes = |probes: &[ServiceProbe]| probes.iter().map(CachedProbe::new).collect();
+        ProbeCache {
+            version: CACHE_VERSION,
+            modified,
+            strict: options.strict,
+            strict_escapes: options.strict_escapes,
+            max_line_length: options.max_line_length,
+            ignore_case: options.ignore_case,
+            anchored: options.anchored,
+            tcp_probes: probes(&service_probes.tcp_probes),
+            udp_probes: probes(&service_probes.udp_probes),
+            exclude: service_probes.exclude.clone(),
+            skipped_matches: service_probes.skipped_matches,
+            skipped_lines: service_probes.skipped_lines.clone(),
+        }
+    }
+
+    // Built from the probes file as it is now, with the same options
+    fn is_fresh(&self, modified: SystemTime, options: &ParseOptions) -> bool {
+        self.version == CACHE_VERSION
+            && self.modified == modified
+            && self.strict == options.strict
+            && self.strict_escapes == options.strict_escapes
+            && self.max_line_length == options.max_line_length
+            && self.ignore_case == options.ignore_case
+            && self.anchored == options.anchored
+    }
+
+    fn into_service_probes(self, options: &ParseOptions) -> Result<ServiceProbes, CacheError> {
+        let mut regexes = regex_cache(options);
+        let mut probes = |cached: Vec<CachedProbe>| {
+            cached
+                .into_iter()
+                .map(|p| p.into_service_probe(&mut regexes))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(ServiceProbes {
+            tcp_probes: probes(self.tcp_probes)?,
+            udp_probes: probes(self.udp_probes)?,
+            exclude: self.exclude,
+            skipped_matches: self.skipped_matches,
+            skipped_lines: self.skipped_lines,
+        })
+    }
+}
+
+impl CachedProbe {
+    fn new(service_probe: &ServiceProbe) -> Self {
+        let matches = |matches: &Option<Vec<Match>>| {
+            matches
+                .as_ref()
+                .map(|matches| matches.iter().map(CachedMatch::new).collect())
+        };
+        let directives = &service_probe.directives;
+        CachedProbe {
+            probe: service_probe.probe.clone(),
+            matches: matches(&directives.matches),
+            soft_matches: matches(&directives.soft_matches),
+            ports: directives.ports.clone(),
+            ssl_ports: directives.ssl_ports.clone(),
+            total_wait_ms: directives.total_wait_ms,
+            tcp_wrapped_ms: directives.tcp_wrapped_ms,
+            rarity: directives.rarity,
+            fallback: directives.fallback.clone(),
+            tls: directives.tls,
+            strip_prefix: directives.strip_prefix,
+        }
+    }
+
+    fn into_service_probe(self, regexes: &mut RegexCache) -> Result<ServiceProbe, CacheError> {
+        let mut matches = |matches: Option<Vec<CachedMatch>>| {
+            matches
+                .map(|matches| {
+                    matches
+                        .into_iter()
+                        .map(|m| m.into_match(regexes))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()
+        };
+        let directives = ProbeDirectives {
+            matches: matches(self.matches)?,
+            soft_matches: matches(self.soft_matches)?,
+            ports: self.ports,
+            ssl_ports: self.ssl_ports,
+            total_wait_ms: self.total_wait_ms,
+            tcp_wrapped_ms: self.tcp_wrapped_ms,
+            rarity: self.rarity,
+            fallback: self.fallback,
+            tls: self.tls,
+            strip_prefix: self.strip_prefix,
+        };
+        Ok(ServiceProbe {
+            probe: self.probe,
+            directives,
+        })
+    }
+}
+
+impl CachedMatch {
+    fn new(m: &Match) -> Self {
+        CachedMatch {
+            service: m.service.clone(),
+            pattern: m.pattern.clone(),
+            pattern_options: m.pattern_options.clone(),
+            version_info: m.version_info.clone(),
+            id: m.id.clone(),
+        }
+    }
+
+    fn into_match(self, regexes: &mut RegexCache) -> Result<Match, CacheError> {
+        let re = regexes
+            .get_or_compile(&self.pattern, &self.pattern_options)
+            .map_err(CacheError::Regex)?;
+        Ok(Match {
+            service: self.service,
+            pattern: self.pattern,
+            re,
+            pattern_options: self.pattern_options,
+            version_info: self.version_info,
+            id: self.id,
+            fields: None,
+        })
+    }
+}
+
+/// Parse the probes file and write a cache of it
+pub fn build_cache(
+    probes_file: &str,
+    cache_file: &str,
+    options: &ParseOptions,
+) -> Result<ServiceProbes, CacheError> {
+    let modified = fs::metadata(probes_file)?.modified()?;
+    let service_probes =
+        read_service_probes_file_with_options(probes_file, options).map_err(CacheError::Parse)?;
+    let cache = ProbeCache::new(&service_probes, modified, options);
+    let mut writer = BufWriter::new(File::create(cache_file)?);
+    // by field name, so optional fields left out of the encoding don't shift
+    // the ones after them
+    rmp_serde::encode::write_named(&mut writer, &cache).map_err(CacheError::Encode)?;
+    Ok(service_probes)
+}
+
+/// The probes from the cache if it was built from the probes file as it is
+/// now with the same options, None if the probes file has changed since.
+/// Payload and included files aren't checked, rebuild the cache after
+/// changing one.
+pub fn read_cache(
+    probes_file: &str,
+    cache_file: &str,
+    options: &ParseOptions,
+) -> Result<Option<ServiceProbes>, CacheError> {
+    let modified = fs::metadata(probes_file)?.modified()?;
+    let reader = BufReader::new(File::open(cache_file)?);
+    let cache: ProbeCache = rmp_serde::decode::from_read(reader).map_err(CacheError::Decode)?;
+    if !cache.is_fresh(modified, options) {
+        return Ok(None);
+    }
+    cache.into_service_probes(options).map(Some)
+}
+
+/// Load the probes from the cache, or parse the probes file when the cache is
+/// stale or can't be read
+pub fn read_service_probes_cached(
+    probes_file: &str,
+    cache_file: &str,
+    options: &ParseOptions,
+) -> Result<ServiceProbes, ParseError> {
+    match read_cache(probes_file, cache_file, options) {
+        Ok(Some(service_probes)) => return Ok(service_probes),
+        Ok(None) => tracing::warn!(
+            "probes cache {} is out of date with {}, parsing the probes file",
+            cache_file,
+            probes_file
+        ),
+        Err(e) => tracing::warn!(
+            "failed to load probes cache {}, parsing the probes file: {}",
+            cache_file,
+            e
+        ),
+    }
+    read_service_probes_file_with_options(probes_file, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_cache() {
+        let dir = std::env::temp_dir().join(format!("radar-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let probes_file = dir.join("probes");
+        let cache_file = dir.join("probes.cache");
+        let (probes_path, cache_path) =
+            (probes_file.to_str().unwrap(), cache_file.to_str().unwrap());
+        fs::write(
+            &probes_file,
+            "Exclude T:9100\n\
+             Probe TCP NULL q||\n\
+             match ftp m|^220 ([\\w-]+) FTP|i p/$1/\n\
+             softmatch ssh m|^SSH-|\n\
+             Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|\n\
+             rarity 1\n\
+             ports 80\n\
+             match http m|^HTTP/1\\.[01]|\n",
+        )
+        .unwrap();
+        let options = ParseOptions::default();
+
+        let built = build_cache(probes_path, cache_path, &options).unwrap();
+        let cached = read_cache(probes_path, cache_path, &options)
+            .unwrap()
+            .unwrap();
+        // the same probes as parsing, and patterns compile the same
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::to_value(&built).unwrap()
+        );
+        let outcome = cached.tcp_probes[0]
+            .check_match(b"220 pure-ftpd ftp server")
+            .unwrap();
+        assert_eq!(outcome.service_match.service, "ftp");
+        assert!(cached.excludes(&crate::serviceprobes::TransportProtocol::TCP, 9100));
+
+        // other options don't use the cache
+        let ignore_case = ParseOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        assert!(read_cache(probes_path, cache_path, &ignore_case)
+            .unwrap()
+            .is_none());
+        // a shorter limit could leave out lines the cache has
+        let max_line_length = ParseOptions {
+            max_line_length: 16,
+            ..Default::default()
+        };
+        assert!(read_cache(probes_path, cache_path, &max_line_length)
+            .unwrap()
+            .is_none());
+
+        // nor does a changed probes file
+        let file = File::options().write(true).open(&probes_file).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(read_cache(probes_path, cache_path, &options)
+            .unwrap()
+            .is_none());
+        let parsed = read_service_probes_cached(probes_path, cache_path, &options).unwrap();
+        assert_eq!(parsed.tcp_probes.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+// Loading a probes file from a url, for teams that publish their signatures
+// on a server
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::http::{self, HttpError};
+use crate::serviceprobes::{
+    parse::{probes_reader, read_service_probes, ParseError, ParseOptions},
+    ServiceProbes,
+};
+
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The download failed
+    Http(HttpError),
+    /// Reading the saved copy failed
+    Io(io::Error),
+    /// The file downloaded isn't a probes file
+    Parse(ParseError),
+    /// The file downloaded has no probes, as an error page wouldn't
+    NoProbes,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FetchError::Http(ref err) => err.fmt(f),
+            FetchError::Io(ref err) => err.fmt(f),
+            FetchError::Parse(ref err) => write!(f, "failed to parse probes: {}", err),
+            FetchError::NoProbes => write!(f, "no probes in the file"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> FetchError {
+        FetchError::Io(err)
+    }
+}
+
+impl From<HttpError> for FetchError {
+    fn from(err: HttpError) -> FetchError {
+        FetchError::Http(err)
+    }
+}
+
+fn parse(probes: Vec<u8>, options: &ParseOptions) -> Result<ServiceProbes, FetchError> {
+    let reader = probes_reader(Cursor::new(probes))?;
+    let service_probes = read_service_probes(reader, options).map_err(FetchError::Parse)?;
+    if service_probes.tcp_probes.is_empty() && service_probes.udp_probes.is_empty() {
+        return Err(FetchError::NoProbes);
+    }
+    Ok(service_probes)
+}
+
+/// Download and parse a probes file, gzipped or not. With a cache, a probes
+/// file that parses is saved to it, and when the download or parse fails
+/// the probes saved by an earlier run are loaded instead, with a warning.
+/// Whoever serves the file doesn't get to read local files, so probes with
+/// a payloadfile or `@include` fail to parse.
+pub async fn read_service_probes_url(
+    url: &str,
+    timeout: Duration,
+    cache: Option<&str>,
+    options: &ParseOptions,
+) -> Result<ServiceProbes, FetchError> {
+    let options = &ParseOptions {
+        local_files: false,
+        ..options.clone()
+    };
+    let probes = http::get(url, timeout).await.map_err(FetchError::Http);
+    let e = match probes.map(|probes| (parse(probes.clone(), options), probes)) {
+        Ok((Ok(service_probes), probes)) => {
+            if let Some(cache) = cache {
+                if let Err(e) = save(cache, &probes) {
+                    tracing::warn!("failed to save probes to {}: {}", cache, e);
+                }
+            }
+            return Ok(service_probes);
+        }
+        Ok((Err(e), _)) | Err(e) => e,
+    };
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return Err(e),
+    };
+    tracing::warn!(
+        "failed to load probes from {}, loading the copy saved in {}: {}",
+        url,
+        cache,
+        e
+    );
+    let cached = fs::read(cache).map_err(FetchError::Io);
+    match cached.and_then(|probes| parse(probes, options)) {
+        Ok(service_probes) => Ok(service_probes),
+        Err(cache_error) => {
+            tracing::error!("failed to load probes from {}: {}", cache, cache_error);
+            Err(e)
+        }
+    }
+}
+
+// Replace the cached copy whole, so a failed write leaves the old one
+fn save(cache: &str, probes: &[u8]) -> io::Result<()> {
+    let partial = format!("{}.partial", cache);
+    fs::write(&partial, probes)?;
+    fs::rename(&partial, Path::new(cache))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const PROBES: &str = "Probe TCP NULL q||\nmatch ftp m|^220 |\n";
+
+    fn ok(body: &str) -> String {
+        format!(
+            "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+    // Answers every request with the response, returning the url to fetch
+    async fn http_server(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://127.0.0.1:{}/nmap-service-probes", port)
+    }
+
+    #[tokio::test]
+    async fn test_read_service_probes_url() {
+        let dir = std::env::temp_dir().join(format!("radar-fetch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache = dir.join("probes").to_string_lossy().into_owned();
+        let options = ParseOptions::default();
+
+        let url = http_server(ok(PROBES)).await;
+        let service_probes =
+            read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, Some(&cache), &options)
+                .await
+                .unwrap();
+        assert_eq!(service_probes.tcp_probes.len(), 1);
+        assert_eq!(fs::read_to_string(&cache).unwrap(), PROBES);
+
+        // a failed download falls back to the saved copy, if there is one
+        let url = http_server("HTTP/1.0 503 Service Unavailable\r\n\r\n".into()).await;
+        let service_probes =
+            read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, Some(&cache), &options)
+                .await
+                .unwrap();
+        assert_eq!(service_probes.tcp_probes.len(), 1);
+        let e = read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, None, &options)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, FetchError::Http(HttpError::Status(ref s)) if s.contains("503")),
+            "{}",
+            e
+        );
+
+        // as does a download that isn't a probes file, which isn't saved
+        // over it
+        let url = http_server(ok("<html>login</html>")).await;
+        read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, Some(&cache), &options)
+            .await
+            .unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap(), PROBES);
+
+        // or a download cut off at a line boundary, which would parse
+        let whole = format!("{}Probe TCP GenericLines q|\\r\\n\\r\\n|\n", PROBES);
+        let cut = format!(
+            "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            whole.len(),
+            PROBES
+        );
+        let url = http_server(cut).await;
+        let service_probes =
+            read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, Some(&cache), &options)
+                .await
+                .unwrap();
+        assert_eq!(service_probes.tcp_probes.len(), 1);
+        assert_eq!(fs::read_to_string(&cache).unwrap(), PROBES);
+        let e = read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, None, &options)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, FetchError::Http(HttpError::Incomplete(_))),
+            "{}",
+            e
+        );
+
+        // or a download that would read local files
+        let url = http_server(ok("Probe TCP Key q||\npayloadfile /etc/hostname\n")).await;
+        let e = read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, None, &options)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, FetchError::Parse(ParseError::LocalFile { .. })),
+            "{}",
+            e
+        );
+        let url = http_server(ok("Probe TCP NULL q||\n@include /etc/passwd\n")).await;
+        let e = read_service_probes_url(&url, DEFAULT_FETCH_TIMEOUT, None, &options)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(e, FetchError::Parse(ParseError::LocalFile { .. })),
+            "{}",
+            e
+        );
+
+        // and a server that never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let e = read_service_probes_url(&url, Duration::from_millis(200), None, &options)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, FetchError::Http(HttpError::Timeout)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
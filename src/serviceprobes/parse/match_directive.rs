@@ -1,7 +1,136 @@
 use crate::serviceprobes::Match;
-use pcre2::bytes::RegexBuilder;
+use pcre2::bytes::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Once};
 
+// Warns once that pcre2 has no JIT, not once per pattern
+static JIT_UNAVAILABLE_WARNING: Once = Once::new();
+
+#[derive(Debug)]
+pub enum MatchLineError {
+    /// The line isn't a match or softmatch with a delimited pattern
+    Malformed,
+    /// The pattern doesn't compile
+    Regex(pcre2::Error),
+}
+
+impl fmt::Display for MatchLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchLineError::Malformed => write!(f, "malformed match line"),
+            MatchLineError::Regex(ref err) => write!(f, "failed to compile pattern: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MatchLineError {}
+
+/// Parse a match or softmatch line, None if it's malformed or its pattern
+/// doesn't compile
 pub fn parse_match_line(line: &str) -> Option<Match> {
+    try_parse_match_line(line).ok()
+}
+
+pub fn try_parse_match_line(line: &str) -> Result<Match, MatchLineError> {
+    try_parse_match_line_cached(line, &mut RegexCache::default())
+}
+
+/// Compiled patterns keyed by pattern and options, so match lines repeated
+/// across probes share one regex
+#[derive(Default)]
+pub struct RegexCache {
+    regexes: HashMap<(String, String), Arc<Regex>>,
+    lookups: usize,
+    ignore_case: bool,
+    anchored: bool,
+}
+
+impl RegexCache {
+    /// A cache that compiles every pattern caseless, as if it had the i
+    /// option
+    pub fn ignoring_case() -> Self {
+        RegexCache {
+            ignore_case: true,
+            ..Default::default()
+        }
+    }
+
+    /// Have every pattern match only at the start of the response, as if it
+    /// began with \A. Patterns are otherwise unanchored like nmap's, free to
+    /// match anywhere unless they start with ^, and with the m option a ^
+    /// can match at the start of any line. The pattern kept on each Match is
+    /// still the one written.
+    pub fn anchored(mut self) -> Self {
+        self.anchored = true;
+        self
+    }
+
+    pub(crate) fn get_or_compile(
+        &mut self,
+        pattern: &str,
+        options: &str,
+    ) -> Result<Arc<Regex>, pcre2::Error> {
+        self.lookups += 1;
+        let key = (pattern.to_string(), options.to_string());
+        if let Some(re) = self.regexes.get(&key) {
+            return Ok(re.clone());
+        }
+        if !pcre2::is_jit_available() {
+            JIT_UNAVAILABLE_WARNING.call_once(|| {
+                tracing::warn!("pcre2 JIT unavailable, matching with the interpreter")
+            });
+        }
+        let mut builder = RegexBuilder::new();
+        builder
+            .caseless(self.ignore_case || options.contains('i'))
+            .dotall(options.contains('s'))
+            .multi_line(options.contains('m'))
+            // with the JIT where pcre2 has one, a pattern it can't compile
+            // falls back to the interpreter without affecting the others
+            .jit_if_available(true);
+        let re = if self.anchored {
+            builder.build(&format!("\\A(?:{})", pattern))?
+        } else {
+            builder.build(pattern)?
+        };
+        let re = Arc::new(re);
+        self.regexes.insert(key, re.clone());
+        Ok(re)
+    }
+
+    /// Number of patterns looked up
+    pub fn lookups(&self) -> usize {
+        self.lookups
+    }
+
+    /// Number of distinct patterns compiled
+    pub fn len(&self) -> usize {
+        self.regexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regexes.is_empty()
+    }
+}
+
+/// Like try_parse_match_line, but reusing the cache's regex for a pattern
+/// that's been compiled before
+pub fn try_parse_match_line_cached(
+    line: &str,
+    cache: &mut RegexCache,
+) -> Result<Match, MatchLineError> {
+    parse_match_parts(line, cache)
+        .ok_or(MatchLineError::Malformed)?
+        .map_err(MatchLineError::Regex)
+}
+
+// nmap documents i and s, m is accepted for signatures that anchor on lines
+// within a multi-line banner
+const PATTERN_OPTIONS: &[char] = &['i', 's', 'm'];
+
+fn parse_match_parts(line: &str, cache: &mut RegexCache) -> Option<Result<Match, pcre2::Error>> {
     let parts: Vec<&str> = line.split_whitespace().collect();
     if parts.len() < 3 || (parts[0] != "match" && parts[0] != "softmatch") {
         return None;
@@ -19,44 +148,107 @@ pub fn parse_match_line(line: &str) -> Option<Match> {
     let pattern_end_index = remainder.find(delimiter)?;
     let pattern = &remainder[..pattern_end_index];
 
-    // Extract pattern options and version info, if present
-    let mut pattern_options = "";
-    let mut version_info = "";
-    if remainder.len() > pattern_end_index + 1 {
-        if !remainder
-            .chars()
-            .nth(pattern_end_index + 1)
-            .unwrap()
-            .is_whitespace()
-        {
-            let parts: Vec<&str> = remainder[pattern_end_index + 1..]
-                .split_whitespace()
-                .collect();
-            pattern_options = parts[0]
-        }
-
-        version_info = remainder[pattern_end_index + pattern_options.len() + 1..].trim();
-    }
+    // Options are the run of flag letters right after the closing delimiter,
+    // anything else is version info
+    let after_pattern = &remainder[pattern_end_index + 1..];
+    let options_len = after_pattern
+        .find(|c: char| !PATTERN_OPTIONS.contains(&c))
+        .unwrap_or(after_pattern.len());
+    let pattern_options = &after_pattern[..options_len];
+    let version_info = after_pattern[options_len..].trim();
 
-    let re = RegexBuilder::new()
-        .caseless(pattern_options.contains("i"))
-        .dotall(pattern_options.contains("s"))
-        .build(&pattern)
-        .unwrap_or_else(|e| {
-            panic!(
-                "failed to create regex for match line {} with error {}",
-                line,
-                e.to_string()
-            )
-        });
+    let re = cache.get_or_compile(pattern, pattern_options);
 
-    Some(Match {
+    Some(re.map(|re| Match {
         service,
         pattern: pattern.into(),
         re,
         pattern_options: pattern_options.into(),
         version_info: version_info.into(),
-    })
+        id: None,
+        fields: None,
+    }))
+}
+
+/// The fields of a match line's version info: `p/product/ v/version/
+/// i/info/ h/hostname/ o/os/ d/device type/` and any number of
+/// `cpe:/.../`. Each field picks its own delimiter, the character right
+/// after its name, so `v|1/2|` is version 1/2 whatever delimits the pattern
+/// or the other fields.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpe: Vec<String>,
+}
+
+impl VersionInfo {
+    /// Every field with f applied, as to substitute captures into each field
+    /// on its own once the fields are split
+    pub fn map<F: Fn(&str) -> String>(&self, f: F) -> VersionInfo {
+        let field = |value: &Option<String>| value.as_deref().map(&f);
+        VersionInfo {
+            product: field(&self.product),
+            version: field(&self.version),
+            info: field(&self.info),
+            hostname: field(&self.hostname),
+            os: field(&self.os),
+            device_type: field(&self.device_type),
+            cpe: self.cpe.iter().map(|cpe| f(cpe)).collect(),
+        }
+    }
+}
+
+/// Split version info into its fields, None if a field is unterminated or
+/// isn't one of p, v, i, h, o, d or cpe:. A cpe's trailing a flag is
+/// dropped.
+pub fn parse_version_info(version_info: &str) -> Option<VersionInfo> {
+    let mut fields = VersionInfo::default();
+    let mut rest = version_info.trim_start();
+    while !rest.is_empty() {
+        let (name, after_name) = match rest.strip_prefix("cpe:") {
+            Some(after_name) => ("cpe", after_name),
+            None => {
+                let name = rest.chars().next()?;
+                (&rest[..name.len_utf8()], &rest[name.len_utf8()..])
+            }
+        };
+        let delimiter = after_name.chars().next()?;
+        let value_start = &after_name[delimiter.len_utf8()..];
+        let value_end = value_start.find(delimiter)?;
+        let value = value_start[..value_end].to_string();
+        rest = &value_start[value_end + delimiter.len_utf8()..];
+        let slot = match name {
+            "p" => &mut fields.product,
+            "v" => &mut fields.version,
+            "i" => &mut fields.info,
+            "h" => &mut fields.hostname,
+            "o" => &mut fields.os,
+            "d" => &mut fields.device_type,
+            "cpe" => {
+                fields.cpe.push(value);
+                // a for a cpe naming an application
+                rest = rest.strip_prefix('a').unwrap_or(rest);
+                rest = rest.trim_start();
+                continue;
+            }
+            _ => return None,
+        };
+        *slot = Some(value);
+        rest = rest.trim_start();
+    }
+    Some(fields)
 }
 
 #[cfg(test)]
@@ -111,6 +303,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_match_line_version_info_after_delimiter() {
+        let parsed_line = parse_match_line("match ftp m/^220/p/ftp/ v/1/").unwrap();
+        assert_eq!(parsed_line.pattern, "^220");
+        assert_eq!(parsed_line.pattern_options, "");
+        assert_eq!(parsed_line.version_info, "p/ftp/ v/1/");
+
+        let parsed_line = parse_match_line("match ftp m/^220/ip/ftp/").unwrap();
+        assert_eq!(parsed_line.pattern_options, "i");
+        assert_eq!(parsed_line.version_info, "p/ftp/");
+    }
+
     #[test]
     fn test_parse_match_line_with_no_version_info() {
         let line = r#"match sharp-remote m|^(?!x)x|"#;
@@ -124,4 +328,141 @@ mod tests {
         assert_eq!(parsed_line.pattern_options, "");
         assert_eq!(parsed_line.version_info, "");
     }
+
+    #[test]
+    fn test_match_test_unmatched_group() {
+        let line = r#"match http m|^HTTP/1\.[01] (\d+)(?: Server: (\S+))?| p/$2/ i/status $1/"#;
+        let parsed_match = parse_match_line(line).unwrap();
+
+        let result = parsed_match.test(b"HTTP/1.1 200").unwrap();
+        assert_eq!(result.version_info, "p// i/status 200/");
+        assert_eq!(result.captures[2], None);
+    }
+
+    #[test]
+    fn test_try_parse_match_line_bad_regex() {
+        let e = try_parse_match_line("match ftp m/^220 (unclosed/").unwrap_err();
+        assert!(matches!(e, MatchLineError::Regex(_)));
+
+        let e = try_parse_match_line("match ftp").unwrap_err();
+        assert!(matches!(e, MatchLineError::Malformed));
+    }
+
+    #[test]
+    fn test_regex_cache() {
+        let mut cache = RegexCache::default();
+        let a = try_parse_match_line_cached("match ftp m/^220/", &mut cache).unwrap();
+        let b = try_parse_match_line_cached("softmatch ftp m/^220/ p/ftp/", &mut cache).unwrap();
+        let c = try_parse_match_line_cached("match ftp m/^220/i", &mut cache).unwrap();
+
+        assert!(Arc::ptr_eq(&a.re, &b.re));
+        assert!(!Arc::ptr_eq(&a.re, &c.re));
+        assert_eq!(cache.lookups(), 3);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_regex_cache_jit() {
+        // patterns of every kind compile and match whether or not the JIT
+        // takes them, the ones after as well as the ones before
+        let mut cache = RegexCache::default();
+        let lines = [
+            r"match ssh m/^SSH-([\d.]+)-/ p/OpenSSH/",
+            r"match echo m/^(\w+) \1$/",
+            r"match http m/(?<=Server: )nginx/i",
+            r"match ftp m/^220 .*FTP/s",
+        ];
+        let responses: [&[u8]; 4] = [
+            b"SSH-2.0-OpenSSH_9.6",
+            b"ping ping",
+            b"server: NGINX",
+            b"220 \nFTP",
+        ];
+        let matches: Vec<Match> = lines
+            .iter()
+            .map(|line| try_parse_match_line_cached(line, &mut cache).unwrap())
+            .collect();
+        for (m, response) in matches.iter().zip(responses) {
+            assert!(
+                m.test(response).is_some(),
+                "{} on {:?}",
+                m.pattern,
+                response
+            );
+        }
+        assert!(matches[1].test(b"ping pong").is_none());
+    }
+
+    #[test]
+    fn test_regex_cache_anchored() {
+        let response = b"HTTP/1.0 200 OK\r\nServer: SSH-2.0 proxy\r\n";
+        let line = r"match ssh m/SSH-([\d.]+)/ p/OpenSSH/ v/$1/";
+
+        let unanchored = parse_match_line(line).unwrap();
+        assert!(unanchored.test(response).is_some());
+
+        let mut cache = RegexCache::default().anchored();
+        let anchored = try_parse_match_line_cached(line, &mut cache).unwrap();
+        assert_eq!(anchored.pattern, r"SSH-([\d.]+)");
+        assert!(anchored.test(response).is_none());
+        let result = anchored.test(b"SSH-2.0-OpenSSH_8.9").unwrap();
+        assert_eq!(result.version_info, "p/OpenSSH/ v/2.0/");
+
+        // nor can the m option let ^ match a later line
+        let line = r"match ftp m/^220 ProFTPD/m";
+        let anchored = try_parse_match_line_cached(line, &mut cache).unwrap();
+        assert!(anchored
+            .test(b"220-welcome\r\n220 ProFTPD ready\r\n")
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_version_info() {
+        // fields delimited like the pattern, and fields holding the
+        // pattern's delimiter
+        let line =
+            r"match http m|^HTTP/1\.[01] 200| p|Web/Admin| v/1.2/ i#a|b/c# cpe:|a:x/y:z|a o/Linux/";
+        let m = parse_match_line(line).unwrap();
+        assert_eq!(m.pattern, r"^HTTP/1\.[01] 200");
+        let fields = parse_version_info(&m.version_info).unwrap();
+        assert_eq!(
+            fields,
+            VersionInfo {
+                product: Some("Web/Admin".into()),
+                version: Some("1.2".into()),
+                info: Some("a|b/c".into()),
+                os: Some("Linux".into()),
+                cpe: vec!["a:x/y:z".into()],
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(parse_version_info("").unwrap(), VersionInfo::default());
+        assert!(parse_version_info("p/unterminated").is_none());
+        assert!(parse_version_info("x/unknown/").is_none());
+    }
+
+    #[test]
+    fn test_match_test_version_fields() {
+        // a capture holding a delimiter stays inside its field
+        let line = r"match ftp m|^220 (\S+) FTP| p/$1/ v/1/";
+        let m = parse_match_line(line).unwrap();
+        let result = m.test(b"220 ftp/x FTP").unwrap();
+        assert_eq!(result.version_info, "p/ftp/x/ v/1/");
+        let fields = result.fields.unwrap();
+        assert_eq!(fields.product.as_deref(), Some("ftp/x"));
+        assert_eq!(fields.version.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn test_parse_match_line_multiline() {
+        let response = b"220-welcome\r\n220 ProFTPD ready\r\n";
+
+        let parsed_match = parse_match_line(r"match ftp m/^220 ProFTPD/m").unwrap();
+        assert_eq!(parsed_match.pattern_options, "m");
+        assert!(parsed_match.test(response).is_some());
+
+        let parsed_match = parse_match_line(r"match ftp m/^220 ProFTPD/").unwrap();
+        assert!(parsed_match.test(response).is_none());
+    }
 }
@@ -1,137 +1,753 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader, Lines};
-use std::iter::Peekable;
+use flate2::bufread::MultiGzDecoder;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 
+pub mod cache;
+pub mod fetch;
 pub mod match_directive;
 pub mod probe_directive;
 mod unescape;
 
+pub use unescape::UnescapeWarning;
+
 use crate::serviceprobes::{
-    parse::{match_directive::parse_match_line, probe_directive::parse_probe_line},
-    Match, ProbeDirectives, ServiceProbe, ServiceProbes, TransportProtocol,
+    parse::{
+        match_directive::{try_parse_match_line_cached, MatchLineError, RegexCache},
+        probe_directive::parse_probe_line_with_warnings,
+    },
+    Exclude, ProbeDirectives, ProbeTls, ServiceProbe, ServiceProbes, SkipReason, SkippedLine,
+    StripPrefix, TransportProtocol,
 };
 
+// Real probe lines run to a few kilobytes, anything much longer is corruption
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+// how much of an overlong line is kept to report it
+const SKIPPED_LINE_PREFIX: usize = 80;
+
+/// Options controlling how a probes file is loaded
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Fail on malformed lines rather than skipping them with a warning
+    pub strict: bool,
+    /// Fail on probe strings with escapes that don't decode cleanly rather
+    /// than logging a warning, strict or not
+    pub strict_escapes: bool,
+    /// Lines longer than this many bytes are malformed
+    pub max_line_length: usize,
+    /// Directory that relative payloadfile paths are resolved against,
+    /// the probes file's own directory when loading from a file. Those in
+    /// an included file are relative to the included file.
+    pub payload_dir: Option<PathBuf>,
+    /// Compile every pattern caseless, whatever its options. Patterns that
+    /// rely on case to tell services apart can then match the wrong service.
+    pub ignore_case: bool,
+    /// Only let patterns match at the start of a response, see
+    /// RegexCache::anchored
+    pub anchored: bool,
+    /// Let payloadfile and `@include` read files from disk. Off for probes
+    /// that didn't come from a local file, so they can't send local files to
+    /// the targets.
+    pub local_files: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            strict: true,
+            strict_escapes: false,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            payload_dir: None,
+            ignore_case: false,
+            anchored: false,
+            local_files: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    LineTooLong {
+        line: usize,
+        length: usize,
+    },
+    MisplacedExclude {
+        line: usize,
+    },
+    Malformed {
+        line: usize,
+        text: String,
+    },
+    SuspiciousProbe {
+        line: usize,
+        warning: UnescapeWarning,
+    },
+    Match {
+        line: usize,
+        error: MatchLineError,
+    },
+    PayloadFile {
+        line: usize,
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// An included file couldn't be opened
+    IncludeFile {
+        line: usize,
+        path: PathBuf,
+        error: io::Error,
+    },
+    /// A file includes itself, directly or through other includes
+    IncludeCycle {
+        line: usize,
+        path: PathBuf,
+    },
+    /// A payloadfile or include in probes that can't read local files
+    LocalFile {
+        line: usize,
+        path: PathBuf,
+    },
+    /// An error in an included file
+    Included {
+        path: PathBuf,
+        error: Box<ParseError>,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Io(ref err) => err.fmt(f),
+            ParseError::LineTooLong { line, length } => {
+                write!(f, "line {} is too long ({} bytes)", line, length)
+            }
+            ParseError::MisplacedExclude { line } => {
+                write!(f, "line {}: Exclude must come before the first Probe", line)
+            }
+            ParseError::Malformed { line, ref text } => {
+                write!(f, "line {}: malformed line {}", line, text)
+            }
+            ParseError::SuspiciousProbe { line, ref warning } => {
+                write!(f, "line {}: probe string has {}", line, warning)
+            }
+            ParseError::Match { line, ref error } => write!(f, "line {}: {}", line, error),
+            ParseError::PayloadFile {
+                line,
+                ref path,
+                ref error,
+            } => write!(
+                f,
+                "line {}: failed to read payload file {}: {}",
+                line,
+                path.display(),
+                error
+            ),
+            ParseError::IncludeFile {
+                line,
+                ref path,
+                ref error,
+            } => write!(
+                f,
+                "line {}: failed to include {}: {}",
+                line,
+                path.display(),
+                error
+            ),
+            ParseError::IncludeCycle { line, ref path } => write!(
+                f,
+                "line {}: including {} again would loop forever",
+                line,
+                path.display()
+            ),
+            ParseError::LocalFile { line, ref path } => write!(
+                f,
+                "line {}: can't read {}, these probes aren't allowed local files",
+                line,
+                path.display()
+            ),
+            ParseError::Included {
+                ref path,
+                ref error,
+            } => write!(f, "in {}, {}", path.display(), error),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        ParseError::Io(err)
+    }
+}
+
 pub fn read_service_probes_file(f: &str) -> ServiceProbes {
-    let mut service_probes = ServiceProbes::new();
-    let f =
-        File::open(f).unwrap_or_else(|_| panic!("failed to read nmap_service_probes file {}", f));
-    let mut lines = BufReader::new(f).lines();
-    while let Some(line) = lines.next() {
-        let line = line.expect("failed to read line");
+    read_service_probes_file_with_options(f, &ParseOptions::default())
+        .unwrap_or_else(|e| panic!("failed to read nmap_service_probes file {}: {}", f, e))
+}
+
+// The first bytes of a gzip stream
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+/// Read a probes file, decompressing it first if it's gzipped. An `@include
+/// path` line reads another probes file in its place, relative to the
+/// including file, so a large probe set can be split across files.
+pub fn read_service_probes_file_with_options(
+    f: &str,
+    options: &ParseOptions,
+) -> Result<ServiceProbes, ParseError> {
+    let path = Path::new(f);
+    let mut options = options.clone();
+    if options.payload_dir.is_none() {
+        options.payload_dir = path.parent().map(Path::to_path_buf);
+    }
+    let mut parser = Parser::new(&options);
+    parser.reading.push(fs::canonicalize(path)?);
+    parser.read(open_probes_file(path)?, path.parent())?;
+    Ok(parser.finish())
+}
+
+fn regex_cache(options: &ParseOptions) -> RegexCache {
+    let cache = if options.ignore_case {
+        RegexCache::ignoring_case()
+    } else {
+        RegexCache::default()
+    };
+    if options.anchored {
+        cache.anchored()
+    } else {
+        cache
+    }
+}
+
+// Dispatch each line to the probe it belongs to. Directives and matches apply
+// to the most recent Probe line. Relative `@include` paths are resolved
+// against options.payload_dir.
+pub fn read_service_probes<R: BufRead>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<ServiceProbes, ParseError> {
+    let mut parser = Parser::new(options);
+    parser.read(reader, options.payload_dir.as_deref())?;
+    Ok(parser.finish())
+}
+
+// Open a probes file, decompressing it if it's gzipped
+fn open_probes_file(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    probes_reader(File::open(path)?)
+}
+
+// Read probes, decompressing them if they're gzipped
+fn probes_reader<R: Read + 'static>(reader: R) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))));
+    }
+    Ok(Box::new(reader))
+}
+
+// The state of reading a probes file, carried through the files it includes
+struct Parser<'a> {
+    options: &'a ParseOptions,
+    service_probes: ServiceProbes,
+    current: Option<ServiceProbe>,
+    regexes: RegexCache,
+    seen_probe: bool,
+    // the files being read, outermost first, to catch include cycles
+    reading: Vec<PathBuf>,
+    // the included file lines are coming from, None for the top file
+    file: Option<PathBuf>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(options: &'a ParseOptions) -> Self {
+        Self {
+            options,
+            service_probes: ServiceProbes::new(),
+            current: None,
+            regexes: regex_cache(options),
+            seen_probe: false,
+            reading: vec![],
+            file: None,
+        }
+    }
+
+    // Read every line, with includes relative to dir
+    fn read<R: BufRead>(&mut self, reader: R, dir: Option<&Path>) -> Result<(), ParseError> {
+        let mut lines = ProbeLines::new(reader, self.options);
+        for line in lines.by_ref() {
+            let (line_number, line) = line?;
+            match line.strip_prefix("@include ") {
+                Some(path) => self.include(line_number, path.trim(), dir)?,
+                None => self.read_line(line_number, line, dir)?,
+            }
+        }
+        for mut skipped in lines.too_long {
+            skipped.file = self.file.clone();
+            self.service_probes.skipped_lines.push(skipped);
+        }
+        Ok(())
+    }
+
+    // Read an included file's lines as if they were written in place of the
+    // include line
+    fn include(&mut self, line: usize, path: &str, dir: Option<&Path>) -> Result<(), ParseError> {
+        if !self.options.local_files {
+            let path = PathBuf::from(path);
+            return Err(ParseError::LocalFile { line, path });
+        }
+        let path = match dir {
+            Some(dir) => dir.join(path),
+            None => PathBuf::from(path),
+        };
+        let include_error = |error| ParseError::IncludeFile {
+            line,
+            path: path.clone(),
+            error,
+        };
+        let canonical = fs::canonicalize(&path).map_err(include_error)?;
+        if self.reading.contains(&canonical) {
+            return Err(ParseError::IncludeCycle { line, path });
+        }
+        let reader = open_probes_file(&canonical).map_err(include_error)?;
+
+        self.reading.push(canonical.clone());
+        let outer = self.file.replace(path.clone());
+        let r = self.read(reader, canonical.parent());
+        self.file = outer;
+        self.reading.pop();
+        r.map_err(|error| ParseError::Included {
+            path,
+            error: Box::new(error),
+        })
+    }
+
+    fn read_line(
+        &mut self,
+        line_number: usize,
+        line: String,
+        dir: Option<&Path>,
+    ) -> Result<(), ParseError> {
+        let options = self.options;
         if line.starts_with('#') || line.trim().is_empty() {
-            continue;
+            return Ok(());
+        } else if line.starts_with("Exclude") {
+            // Exclude applies to the whole file, so nmap only allows it
+            // before the first Probe
+            if self.seen_probe {
+                if options.strict {
+                    return Err(ParseError::MisplacedExclude { line: line_number });
+                }
+                tracing::warn!(
+                    "ignoring Exclude on line {}, it must come before the first Probe",
+                    line_number
+                );
+                self.skip(line_number, line, SkipReason::MisplacedExclude, None);
+                return Ok(());
+            }
+            let exclude = parse_exclude_line(&line);
+            match (exclude, self.service_probes.exclude.as_mut()) {
+                (Some(exclude), Some(existing)) => existing.extend(exclude),
+                (Some(exclude), None) => self.service_probes.exclude = Some(exclude),
+                (None, _) if options.strict => {
+                    return Err(ParseError::Malformed {
+                        line: line_number,
+                        text: line,
+                    })
+                }
+                (None, _) => {
+                    tracing::warn!("skipping malformed Exclude on line {}", line_number);
+                    self.skip(line_number, line, SkipReason::MalformedExclude, None);
+                }
+            }
         } else if line.starts_with("Probe") {
-            let probe = parse_probe_line(&line)
-                .unwrap_or_else(|| panic!("failed to parse probe line {}", &line));
-            let directives = read_probe_directives(&mut lines);
-            match &probe.transport_protocol {
-                TransportProtocol::TCP => {
-                    service_probes
-                        .tcp_probes
-                        .push(ServiceProbe { probe, directives });
+            self.seen_probe = true;
+            if let Some(service_probe) = self.current.take() {
+                self.service_probes.push(service_probe);
+            }
+            let (probe, warnings) = match parse_probe_line_with_warnings(&line) {
+                Some(parsed) => parsed,
+                None if options.strict => {
+                    return Err(ParseError::Malformed {
+                        line: line_number,
+                        text: line,
+                    })
+                }
+                None => {
+                    // the directives that follow belong to the skipped probe
+                    tracing::warn!("skipping malformed Probe on line {}", line_number);
+                    self.skip(line_number, line, SkipReason::MalformedProbe, None);
+                    return Ok(());
+                }
+            };
+            // A probe string that doesn't decode cleanly would otherwise just
+            // show up as a probe that never matches anything
+            for warning in warnings {
+                if options.strict_escapes {
+                    return Err(ParseError::SuspiciousProbe {
+                        line: line_number,
+                        warning,
+                    });
+                }
+                tracing::warn!("line {}: probe {} has {}", line_number, probe.name, warning);
+            }
+            self.current = Some(ServiceProbe {
+                probe,
+                directives: ProbeDirectives::new(),
+            });
+        } else if line.starts_with("payloadfile") {
+            // a probe's payload from a file, for binary payloads too big to
+            // write out in the Probe line
+            if self.current.is_none() {
+                self.skip(line_number, line, SkipReason::NoProbe, None);
+                return Ok(());
+            }
+            let path = match line.split_whitespace().nth(1) {
+                Some(path) => Path::new(path),
+                None if options.strict => {
+                    return Err(ParseError::Malformed {
+                        line: line_number,
+                        text: line,
+                    })
+                }
+                None => {
+                    tracing::warn!("skipping malformed payloadfile on line {}", line_number);
+                    self.skip(line_number, line, SkipReason::MalformedPayloadfile, None);
+                    return Ok(());
+                }
+            };
+            if !options.local_files {
+                return Err(ParseError::LocalFile {
+                    line: line_number,
+                    path: path.to_path_buf(),
+                });
+            }
+            let dir = match self.file {
+                Some(_) => dir,
+                None => options.payload_dir.as_deref(),
+            };
+            let path = match dir {
+                Some(dir) => dir.join(path),
+                None => path.to_path_buf(),
+            };
+            let data = fs::read(&path).map_err(|error| ParseError::PayloadFile {
+                line: line_number,
+                path,
+                error,
+            })?;
+            if let Some(service_probe) = self.current.as_mut() {
+                service_probe.probe.data = data;
+            }
+        } else if let Some(service_probe) = self.current.as_mut() {
+            // One pattern pcre2 can't compile shouldn't make the rest of the
+            // file unusable, so lenient mode skips the match and counts it
+            match read_probe_directive(&line, &mut service_probe.directives, &mut self.regexes) {
+                Ok(None) => (),
+                Ok(Some(reason)) => self.skip(line_number, line, reason, None),
+                Err(error) if options.strict => {
+                    return Err(ParseError::Match {
+                        line: line_number,
+                        error,
+                    })
                 }
-                TransportProtocol::UDP => {
-                    service_probes
-                        .udp_probes
-                        .push(ServiceProbe { probe, directives });
+                Err(error) => {
+                    tracing::warn!("line {}: skipping match, {}", line_number, error);
+                    self.service_probes.skipped_matches += 1;
+                    let reason = match error {
+                        MatchLineError::Malformed => SkipReason::MalformedMatch,
+                        MatchLineError::Regex(_) => SkipReason::BadPattern,
+                    };
+                    self.skip(line_number, line, reason, Some(error.to_string()));
                 }
             }
+        } else {
+            self.skip(line_number, line, SkipReason::NoProbe, None);
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, line: usize, text: String, reason: SkipReason, detail: Option<String>) {
+        self.service_probes.skipped_lines.push(SkippedLine {
+            line,
+            text,
+            reason,
+            file: self.file.clone(),
+            detail,
+        });
+    }
+
+    fn finish(mut self) -> ServiceProbes {
+        if let Some(service_probe) = self.current.take() {
+            self.service_probes.push(service_probe);
+        }
+        let regexes = &self.regexes;
+        tracing::debug!(
+            "compiled {} distinct patterns for {} match lines, {:.2} lines per pattern",
+            regexes.len(),
+            regexes.lookups(),
+            regexes.lookups() as f64 / regexes.len().max(1) as f64
+        );
+        let mut service_probes = self.service_probes;
+        service_probes
+            .skipped_lines
+            .sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+        service_probes
+    }
+}
+
+impl ServiceProbes {
+    fn push(&mut self, mut service_probe: ServiceProbe) {
+        service_probe.tag_matches();
+        match &service_probe.probe.transport_protocol {
+            TransportProtocol::TCP => self.tcp_probes.push(service_probe),
+            TransportProtocol::UDP => self.udp_probes.push(service_probe),
+        }
+    }
+}
+
+// Directives other than match and softmatch that a probe can have
+const PROBE_DIRECTIVES: [&str; 8] = [
+    "fallback",
+    "ports",
+    "sslports",
+    "totalwaitms",
+    "tcpwrappedms",
+    "rarity",
+    "tls",
+    "stripprefix",
+];
+
+// Read a match, softmatch, ports, sslports, totalwaitms, tcpwrappedms, rarity,
+// fallback, tls or stripprefix directive into the directives of the current
+// probe. Other lines are left out, returning why.
+fn read_probe_directive(
+    line: &str,
+    directives: &mut ProbeDirectives,
+    regexes: &mut RegexCache,
+) -> Result<Option<SkipReason>, MatchLineError> {
+    if line.starts_with("match") {
+        let nmap_match = try_parse_match_line_cached(line, regexes)?;
+        directives
+            .matches
+            .get_or_insert_with(Vec::new)
+            .push(nmap_match);
+        return Ok(None);
+    } else if line.starts_with("softmatch") {
+        let nmap_match = try_parse_match_line_cached(line, regexes)?;
+        directives
+            .soft_matches
+            .get_or_insert_with(Vec::new)
+            .push(nmap_match);
+        return Ok(None);
+    }
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if !PROBE_DIRECTIVES.contains(&parts[0]) {
+        return Ok(Some(SkipReason::UnknownDirective));
+    }
+    if parts.len() < 2 {
+        return Ok(Some(SkipReason::TooShort));
+    }
+
+    let directive = parts[0];
+
+    if directive == "fallback" {
+        directives.fallback = Some(parts[1].split(",").map(str::to_string).collect());
+    }
+    if directive == "ports" {
+        directives.ports = match parse_ports(parts[1]) {
+            Some(ports) => Some(ports),
+            None => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "sslports" {
+        directives.ssl_ports = match parse_ports(parts[1]) {
+            Some(ports) => Some(ports),
+            None => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "totalwaitms" {
+        directives.total_wait_ms = match parts[1].parse() {
+            Ok(ms) => Some(ms),
+            Err(_) => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "tcpwrappedms" {
+        directives.tcp_wrapped_ms = match parts[1].parse() {
+            Ok(ms) => Some(ms),
+            Err(_) => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "rarity" {
+        directives.rarity = match parts[1].parse() {
+            Ok(rarity) => Some(rarity),
+            Err(_) => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "tls" {
+        directives.tls = match parts[1] {
+            "required" => Some(ProbeTls::Required),
+            "never" => Some(ProbeTls::Never),
+            _ => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    if directive == "stripprefix" {
+        directives.strip_prefix = match parse_strip_prefix(&parts[1..]) {
+            Some(strip_prefix) => Some(strip_prefix),
+            None => return Ok(Some(SkipReason::MalformedDirective)),
+        };
+    }
+    Ok(None)
+}
+
+// `stripprefix <n>` or `stripprefix length <n>`, with a length of 1 to 8 bytes
+fn parse_strip_prefix(args: &[&str]) -> Option<StripPrefix> {
+    match *args {
+        [n] => n.parse().ok().map(StripPrefix::Bytes),
+        ["length", n] => match n.parse() {
+            Ok(n @ 1..=8) => Some(StripPrefix::Length(n)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Like BufRead::lines, but numbers the lines and refuses to buffer lines
+// longer than max_line_length. Overlong lines are an error in strict mode and
+// are skipped with a warning otherwise.
+struct ProbeLines<R> {
+    reader: R,
+    strict: bool,
+    max_line_length: usize,
+    line_number: usize,
+    // the overlong lines skipped
+    too_long: Vec<SkippedLine>,
+}
+
+impl<R: BufRead> ProbeLines<R> {
+    fn new(reader: R, options: &ParseOptions) -> Self {
+        Self {
+            reader,
+            strict: options.strict,
+            max_line_length: options.max_line_length,
+            line_number: 0,
+            too_long: vec![],
         }
     }
-    service_probes
-}
-
-// Read the ports, sslports, totalwaitms, tcpwrappedms rarity, and fallback directives,
-// then read all the match directives
-fn read_probe_directives(lines: &mut Lines<BufReader<File>>) -> ProbeDirectives {
-    let mut directives = ProbeDirectives::new();
-    let mut lines = lines.peekable();
-    loop {
-        match &lines.peek() {
-            None => break,
-            Some(line) => {
-                let line = line.as_ref().expect("failed to read line");
-                if line.starts_with("Probe") {
-                    break;
-                } else if line.starts_with('#') || line.trim().is_empty() {
-                } else if line.starts_with("match") || line.starts_with("softmatch") {
-                    let (matches, soft_matches) = read_matches(&mut lines);
-                    directives.matches = Some(matches);
-                    directives.soft_matches = Some(soft_matches);
-                    break;
-                } else {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if parts.len() < 2 {
-                        break;
-                    }
-
-                    let directive = parts[0];
-
-                    if directive == "fallback" {
-                        directives.fallback =
-                            Some(parts[1].split(",").map(str::to_string).collect());
-                    }
-                    if directive == "ports" {
-                        directives.ports = Some(
-                            parse_ports(parts[1])
-                                .unwrap_or_else(|| panic!("failed to parse ports line")),
-                        );
-                    }
-                    if directive == "sslports" {
-                        directives.ssl_ports = Some(
-                            parse_ports(parts[1])
-                                .unwrap_or_else(|| panic!("failed to parse ports line")),
-                        );
-                    }
-                    if directive == "totalwaitms" {
-                        directives.total_wait_ms =
-                            Some(parts[1].parse().expect("failed to parse totalwaitms"))
-                    }
-                    if directive == "tcpwrappedms" {
-                        directives.tcp_wrapped_ms =
-                            Some(parts[1].parse().expect("failed to parse tcpwrappedms"))
-                    }
-                    if directive == "rarity" {
-                        directives.rarity = Some(parts[1].parse().expect("failed to parse rarity"))
-                    }
+
+    // consume the remainder of the current line, returning its length
+    fn skip_line(&mut self) -> io::Result<usize> {
+        let mut skipped = 0;
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(skipped);
+            }
+            match buf.iter().position(|&b| b == b'\n') {
+                Some(i) => {
+                    self.reader.consume(i + 1);
+                    return Ok(skipped + i);
+                }
+                None => {
+                    let n = buf.len();
+                    self.reader.consume(n);
+                    skipped += n;
                 }
             }
         }
-        lines.next();
-    }
-
-    directives
-}
-
-// Read all the matches for a given probe, stopping at the next instance of a Probe directive
-fn read_matches(lines: &mut Peekable<&mut Lines<BufReader<File>>>) -> (Vec<Match>, Vec<Match>) {
-    let mut matches = vec![];
-    let mut soft_matches = vec![];
-    loop {
-        match &lines.peek() {
-            None => break,
-            Some(line) => {
-                let line = line.as_ref().expect("failed to read line");
-                if line.starts_with("Probe") {
-                    break;
-                } else {
-                    if line.starts_with('#') || line.trim().is_empty() {
-                    } else if line.starts_with("match") {
-                        let nmap_match = parse_match_line(&line)
-                            .unwrap_or_else(|| panic!("failed to parse match line {}", &line));
-                        matches.push(nmap_match);
-                    } else if line.starts_with("softmatch") {
-                        let nmap_match = parse_match_line(&line)
-                            .unwrap_or_else(|| panic!("failed to parse softmatch line {}", &line));
-                        soft_matches.push(nmap_match);
-                    }
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, ParseError> {
+        loop {
+            self.line_number += 1;
+            let mut buf = vec![];
+            let limit = self.max_line_length as u64 + 1;
+            let n = (&mut self.reader).take(limit).read_until(b'\n', &mut buf)?;
+            if n == 0 {
+                return Ok(None);
+            }
+
+            if buf.last() != Some(&b'\n') && n as u64 == limit {
+                let length = n + self.skip_line()?;
+                if self.strict {
+                    return Err(ParseError::LineTooLong {
+                        line: self.line_number,
+                        length,
+                    });
                 }
+                tracing::warn!(
+                    "skipping line {}, too long ({} bytes)",
+                    self.line_number,
+                    length
+                );
+                buf.truncate(SKIPPED_LINE_PREFIX);
+                self.too_long.push(SkippedLine {
+                    line: self.line_number,
+                    text: String::from_utf8_lossy(&buf).into_owned(),
+                    reason: SkipReason::LineTooLong,
+                    file: None,
+                    detail: Some(format!("{} bytes", length)),
+                });
+                continue;
             }
+
+            if buf.last() == Some(&b'\n') {
+                buf.pop();
+                if buf.last() == Some(&b'\r') {
+                    buf.pop();
+                }
+            }
+            let line = String::from_utf8(buf)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            return Ok(Some(line));
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ProbeLines<R> {
+    type Item = Result<(usize, String), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_line() {
+            Ok(Some(line)) => Some(Ok((self.line_number, line))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
         }
-        lines.next();
+    }
+}
+
+// Parse an Exclude line like "Exclude 53,T:9100-9107,U:30000-40000". Ports
+// before any T: or U: prefix apply to both protocols, a prefix applies to the
+// ports that follow it.
+fn parse_exclude_line(line: &str) -> Option<Exclude> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 2 || parts[0] != "Exclude" {
+        return None;
     }
 
-    (matches, soft_matches)
+    let mut exclude = Exclude::default();
+    let (mut tcp, mut udp) = (true, true);
+    for item in parts[1].split(",") {
+        let ports = if let Some(ports) = item.strip_prefix("T:") {
+            (tcp, udp) = (true, false);
+            ports
+        } else if let Some(ports) = item.strip_prefix("U:") {
+            (tcp, udp) = (false, true);
+            ports
+        } else {
+            item
+        };
+        let ports = parse_ports(ports)?;
+        if tcp {
+            exclude.tcp_ports.extend(&ports);
+        }
+        if udp {
+            exclude.udp_ports.extend(&ports);
+        }
+    }
+    Some(exclude)
 }
 
 fn parse_ports(ports: &str) -> Option<Vec<u16>> {
@@ -154,3 +770,562 @@ fn parse_ports(ports: &str) -> Option<Vec<u16>> {
     }
     Some(parsed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROBES: &str = "\
+# comment
+Probe TCP NULL q||
+totalwaitms 6000
+match ftp m/^220 FTP/ p/ftp/
+softmatch ftp m/^220/
+
+Probe TCP GenericLines q|\\r\\n\\r\\n|
+rarity 1
+ports 21,23,1000-1002
+match telnet m/^login: /
+
+Probe UDP DNSStatusRequest q|\\0\\0\\x10\\0\\0\\0\\0\\0\\0\\0\\0\\0|
+rarity 1
+match dns m/^\\0\\0\\x90\\x04/
+";
+
+    #[test]
+    fn test_read_service_probes() {
+        let service_probes =
+            read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+
+        assert_eq!(service_probes.tcp_probes.len(), 2);
+        assert_eq!(service_probes.udp_probes.len(), 1);
+
+        let null = &service_probes.tcp_probes[0];
+        assert_eq!(null.probe.name, "NULL");
+        assert_eq!(null.directives.total_wait_ms, Some(6000));
+        assert_eq!(null.directives.matches.as_ref().unwrap().len(), 1);
+        assert_eq!(null.directives.soft_matches.as_ref().unwrap().len(), 1);
+
+        let generic_lines = &service_probes.tcp_probes[1];
+        assert_eq!(generic_lines.probe.name, "GenericLines");
+        assert_eq!(generic_lines.directives.rarity, Some(1));
+        assert_eq!(
+            generic_lines.directives.ports,
+            Some(vec![21, 23, 1000, 1001, 1002])
+        );
+    }
+
+    #[test]
+    fn test_read_service_probes_exclude() {
+        let probes = format!("Exclude 53,T:9100-9102,U:161\n{}", PROBES);
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let exclude = service_probes.exclude.as_ref().unwrap();
+        assert_eq!(exclude.tcp_ports, vec![53, 9100, 9101, 9102]);
+        assert_eq!(exclude.udp_ports, vec![53, 161]);
+        assert!(service_probes.excludes(&TransportProtocol::TCP, 9101));
+        assert!(!service_probes.excludes(&TransportProtocol::UDP, 9101));
+    }
+
+    #[test]
+    fn test_read_service_probes_misplaced_exclude() {
+        let probes = format!("{}Exclude T:9100\n", PROBES);
+        match read_service_probes(probes.as_bytes(), &ParseOptions::default()) {
+            Err(ParseError::MisplacedExclude { line }) => assert_eq!(line, 15),
+            r => panic!("expected misplaced exclude error, got {:?}", r),
+        }
+
+        let options = ParseOptions {
+            strict: false,
+            ..Default::default()
+        };
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        assert!(service_probes.exclude.is_none());
+    }
+
+    #[test]
+    fn test_read_service_probes_suspicious_probe() {
+        let probes = format!("{}Probe TCP Broken q|\\x4|\n", PROBES);
+
+        // only a warning unless asked for, the escape is sent as written
+        for strict in [true, false] {
+            let options = ParseOptions {
+                strict,
+                ..ParseOptions::default()
+            };
+            let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+            assert_eq!(service_probes.tcp_probes[2].probe.data, b"\\x4");
+        }
+
+        let options = ParseOptions {
+            strict_escapes: true,
+            ..ParseOptions::default()
+        };
+        let err = read_service_probes(probes.as_bytes(), &options).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::SuspiciousProbe {
+                line: 15,
+                warning: UnescapeWarning::InvalidHexEscape(_)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_read_service_probes_bad_regex() {
+        let probes = format!("{}match broken m/(unclosed/\n", PROBES);
+
+        let err = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::Match {
+                line: 15,
+                error: MatchLineError::Regex(_)
+            }
+        ));
+
+        let options = ParseOptions {
+            strict: false,
+            ..ParseOptions::default()
+        };
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        assert_eq!(service_probes.skipped_matches, 1);
+        assert_eq!(service_probes.services().len(), 3);
+    }
+
+    #[test]
+    fn test_read_service_probes_skipped_lines() {
+        let probes = format!(
+            "{}match broken m/(unclosed/\nmatch nodelim\nrarity\nfrobnicate 3\n\
+             ports 80,http\nsslports 443-\ntotalwaitms soon\ntcpwrappedms -1\nrarity high\n\
+             Probe TCP Short\nmatch short m/^x/\n{}\n",
+            PROBES,
+            "x".repeat(200)
+        );
+        let options = ParseOptions {
+            strict: false,
+            max_line_length: 100,
+            ..ParseOptions::default()
+        };
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        let skipped: Vec<(usize, &str, SkipReason)> = service_probes
+            .skipped_lines
+            .iter()
+            .map(|s| (s.line, s.text.as_str(), s.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![
+                (15, "match broken m/(unclosed/", SkipReason::BadPattern),
+                (16, "match nodelim", SkipReason::MalformedMatch),
+                (17, "rarity", SkipReason::TooShort),
+                (18, "frobnicate 3", SkipReason::UnknownDirective),
+                (19, "ports 80,http", SkipReason::MalformedDirective),
+                (20, "sslports 443-", SkipReason::MalformedDirective),
+                (21, "totalwaitms soon", SkipReason::MalformedDirective),
+                (22, "tcpwrappedms -1", SkipReason::MalformedDirective),
+                (23, "rarity high", SkipReason::MalformedDirective),
+                (24, "Probe TCP Short", SkipReason::MalformedProbe),
+                (25, "match short m/^x/", SkipReason::NoProbe),
+                (
+                    26,
+                    &"x".repeat(SKIPPED_LINE_PREFIX)[..],
+                    SkipReason::LineTooLong
+                ),
+            ]
+        );
+        assert!(service_probes.skipped_lines[0].detail.is_some());
+        assert_eq!(
+            service_probes.skipped_lines[11].detail.as_deref(),
+            Some("200 bytes")
+        );
+        let record = serde_json::to_value(&service_probes.skipped_lines[2]).unwrap();
+        assert_eq!(
+            record,
+            serde_json::json!({"line": 17, "text": "rarity", "reason": "too_short"})
+        );
+    }
+
+    #[test]
+    fn test_read_service_probes_payload_file() {
+        let dir = std::env::temp_dir().join(format!("radar-payload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello.bin"), b"\x16\x03\x01\x00").unwrap();
+        let probes_file = dir.join("probes");
+        let probes = format!("{}Probe TCP Hello q||\npayloadfile hello.bin\n", PROBES);
+        fs::write(&probes_file, &probes).unwrap();
+
+        let service_probes = read_service_probes_file_with_options(
+            probes_file.to_str().unwrap(),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(service_probes.tcp_probes[2].probe.data, b"\x16\x03\x01\x00");
+
+        fs::remove_file(dir.join("hello.bin")).unwrap();
+        let err = read_service_probes_file_with_options(
+            probes_file.to_str().unwrap(),
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ParseError::PayloadFile { line: 16, .. }));
+        assert!(err.to_string().contains("hello.bin"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_service_probes_include_payload_file() {
+        let dir =
+            std::env::temp_dir().join(format!("radar-include-payload-{}", std::process::id()));
+        fs::create_dir_all(dir.join("tls/hello")).unwrap();
+        let probes_file = dir.join("probes");
+        fs::write(
+            &probes_file,
+            "Probe TCP NULL q||\n@include tls/hello/tls.probes\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("tls/hello/tls.probes"),
+            "Probe TCP Hello q||\npayloadfile hello.bin\n",
+        )
+        .unwrap();
+        // the payload next to the included file, not the one next to the top
+        fs::write(dir.join("tls/hello/hello.bin"), b"\x16\x03\x01").unwrap();
+        fs::write(dir.join("hello.bin"), b"wrong").unwrap();
+
+        let service_probes = read_service_probes_file_with_options(
+            probes_file.to_str().unwrap(),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(service_probes.tcp_probes[1].probe.data, b"\x16\x03\x01");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_service_probes_include() {
+        let dir = std::env::temp_dir().join(format!("radar-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("web")).unwrap();
+        let probes_file = dir.join("probes");
+        let path = probes_file.to_str().unwrap();
+        fs::write(
+            &probes_file,
+            "Probe TCP NULL q||\nmatch ftp m/^220/\n@include web/http.probes\nmatch ssh m/^SSH-/\n",
+        )
+        .unwrap();
+        // relative to the including file, not the top one
+        fs::write(
+            dir.join("web/http.probes"),
+            "Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|\nmatch http m/^HTTP/\n@include common.probes\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("web/common.probes"),
+            "match proxy m/^PROXY/\nfrobnicate 3\n",
+        )
+        .unwrap();
+        let lenient = ParseOptions {
+            strict: false,
+            ..Default::default()
+        };
+
+        let service_probes = read_service_probes_file_with_options(path, &lenient).unwrap();
+        let names: Vec<&str> = service_probes
+            .tcp_probes
+            .iter()
+            .map(|p| p.probe.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["NULL", "GetRequest"]);
+        // lines after an include still belong to the probe it left off on
+        let services: Vec<&str> = service_probes.tcp_probes[1]
+            .signatures()
+            .map(|(m, _)| m.service.as_str())
+            .collect();
+        assert_eq!(services, vec!["http", "proxy", "ssh"]);
+        let skipped = &service_probes.skipped_lines[0];
+        assert_eq!(
+            (skipped.line, skipped.reason),
+            (2, SkipReason::UnknownDirective)
+        );
+        assert_eq!(skipped.file, Some(dir.join("web/common.probes")));
+
+        // a cycle through another file
+        fs::write(dir.join("web/common.probes"), "@include ../probes\n").unwrap();
+        let err = read_service_probes_file_with_options(path, &lenient).unwrap_err();
+        assert!(err.to_string().contains("loop forever"), "{}", err);
+
+        fs::remove_file(dir.join("web/common.probes")).unwrap();
+        let err = read_service_probes_file_with_options(path, &lenient).unwrap_err();
+        match err {
+            ParseError::Included { path, error } => {
+                assert_eq!(path, dir.join("web/http.probes"));
+                assert!(matches!(*error, ParseError::IncludeFile { line: 3, .. }));
+            }
+            err => panic!("unexpected error {}", err),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_service_probes_tls_directive() {
+        let probes = format!(
+            "{}Probe TCP Hello q|hello|\ntls required\nProbe TCP Bye q|bye|\ntls never\ntls sometimes\n",
+            PROBES
+        );
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        assert_eq!(service_probes.tcp_probes[0].tls(), None);
+        assert_eq!(service_probes.tcp_probes[2].tls(), Some(ProbeTls::Required));
+        assert_eq!(service_probes.tcp_probes[3].tls(), Some(ProbeTls::Never));
+        let skipped = service_probes.skipped_lines.last().unwrap();
+        assert_eq!(skipped.text, "tls sometimes");
+        assert_eq!(skipped.reason, SkipReason::MalformedDirective);
+    }
+
+    #[test]
+    fn test_read_service_probes_malformed_directives() {
+        let probes = format!(
+            "{}Probe TCP Hello q|hello|\n\
+             ports 80,http\n\
+             sslports 443-\n\
+             totalwaitms soon\n\
+             tcpwrappedms -1\n\
+             rarity high\n\
+             rarity 4\n",
+            PROBES
+        );
+        let options = ParseOptions {
+            strict: false,
+            ..ParseOptions::default()
+        };
+        // each bad value is left out, the directives around it still apply
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        let hello = &service_probes.tcp_probes[2];
+        assert_eq!(hello.probe.name, "Hello");
+        assert_eq!(hello.directives.ports, None);
+        assert_eq!(hello.directives.ssl_ports, None);
+        assert_eq!(hello.directives.total_wait_ms, None);
+        assert_eq!(hello.directives.tcp_wrapped_ms, None);
+        assert_eq!(hello.directives.rarity, Some(4));
+    }
+
+    #[test]
+    fn test_read_service_probes_strip_prefix() {
+        let probes = "\
+Probe TCP Header q|x|
+stripprefix 4
+match svc m|^svc (\\d+)| p/$1/
+Probe TCP Length q|x|
+stripprefix length 2
+match svc m|^svc$|
+stripprefix length 9
+stripprefix some
+";
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let (header, length) = (&service_probes.tcp_probes[0], &service_probes.tcp_probes[1]);
+        let outcome = header.check_match(b"\x00\x00\x00\x07svc 12").unwrap();
+        assert_eq!(outcome.service_match.service, "svc");
+        assert!(header.check_match(b"svc 12").is_none());
+        assert!(header.check_match(b"\x00\x00").is_none());
+
+        // the payload is what the length says, trailing bytes are left out
+        assert!(length.check_match(b"\x00\x03svc").is_some());
+        assert!(length.check_match(b"\x00\x03svcsvc").is_some());
+        assert!(length.check_match(b"\x00\x04svc!").is_none());
+        let reasons: Vec<SkipReason> = service_probes
+            .skipped_lines
+            .iter()
+            .map(|s| s.reason)
+            .collect();
+        assert_eq!(reasons, vec![SkipReason::MalformedDirective; 2]);
+    }
+
+    #[test]
+    fn test_read_service_probes_gzip() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let dir = std::env::temp_dir().join(format!("radar-gzip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let probes_file = dir.join("nmap-service-probes.gz");
+        let mut encoder =
+            GzEncoder::new(File::create(&probes_file).unwrap(), Compression::default());
+        encoder.write_all(PROBES.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let gzipped = read_service_probes_file_with_options(
+            probes_file.to_str().unwrap(),
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let plain = read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&gzipped).unwrap(),
+            serde_json::to_value(&plain).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_service_probes_malformed_probe() {
+        let probes = format!("{}Probe TCP Short\nmatch short m/^x/\n", PROBES);
+
+        let err = read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap_err();
+        assert!(matches!(err, ParseError::Malformed { line: 15, .. }));
+
+        let options = ParseOptions {
+            strict: false,
+            ..ParseOptions::default()
+        };
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        assert_eq!(service_probes.tcp_probes.len(), 2);
+        assert!(!service_probes.services().contains("short"));
+    }
+
+    #[test]
+    fn test_check_match_soft_and_hard() {
+        let service_probes =
+            read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let null = &service_probes.tcp_probes[0];
+
+        let outcome = null.check_match(b"220 FTP ready").unwrap();
+        assert!(!outcome.soft);
+        assert_eq!(outcome.probe, "NULL");
+        assert_eq!(outcome.service_match.pattern, "^220 FTP");
+
+        let outcome = null.check_match(b"220 other").unwrap();
+        assert!(outcome.soft);
+        assert_eq!(outcome.service_match.service, "ftp");
+
+        assert!(null.check_match(b"SSH-2.0").is_none());
+    }
+
+    #[test]
+    fn test_check_match_substitutes_captures() {
+        let probes = "Probe TCP NULL q||\nmatch ftp m/^220 ([\\w-]+) FTP/ p/$1/ v/$2/\n";
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let null = &service_probes.tcp_probes[0];
+        let response = b"220 Pure-FTPd FTP server ready";
+
+        // the same version info Match::test gives, unmatched groups empty
+        let outcome = null.check_match(response).unwrap();
+        assert_eq!(outcome.service_match.version_info, "p/Pure-FTPd/ v//");
+        let m = null.signatures().next().unwrap().0;
+        assert_eq!(
+            m.test(response).unwrap().version_info,
+            outcome.service_match.version_info
+        );
+        assert_eq!(m.version_info, "p/$1/ v/$2/");
+    }
+
+    #[test]
+    fn test_with_rarity() {
+        let probes = format!("{}Probe TCP Rare q|rare|\nrarity 8\n", PROBES);
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let names = |probes: ServiceProbes| -> Vec<String> {
+            probes
+                .tcp_probes
+                .into_iter()
+                .map(|p| p.probe.name)
+                .collect()
+        };
+
+        // NULL is always included
+        assert_eq!(
+            names(service_probes.with_rarity(..=1)),
+            vec!["NULL", "GenericLines"]
+        );
+        assert_eq!(names(service_probes.with_rarity(2..)), vec!["NULL", "Rare"]);
+        assert_eq!(names(service_probes.with_rarity(2..=7)), vec!["NULL"]);
+    }
+
+    #[test]
+    fn test_sent_at_intensity() {
+        let probes = format!(
+            "{}Probe TCP Unrated q|unrated|\n\
+             Probe TCP Common q|common|\nrarity 3\n\
+             Probe TCP Rare q|rare|\nrarity 8\nports 8080\n",
+            PROBES
+        );
+        let service_probes =
+            read_service_probes(probes.as_bytes(), &ParseOptions::default()).unwrap();
+        let sent = |intensity, port| {
+            service_probes
+                .tcp_probes
+                .iter()
+                .filter(|p| p.sent_at_intensity(intensity, port))
+                .count()
+        };
+
+        // NULL and Unrated, rarity 0, at every intensity
+        assert_eq!(sent(0, 80), 2);
+        assert_eq!(sent(1, 80), 3);
+        assert_eq!(sent(3, 80), 4);
+        assert_eq!(sent(7, 80), 4);
+        assert_eq!(sent(9, 80), 5);
+        // GenericLines lists port 21, Rare lists 8080
+        assert_eq!(sent(0, 21), 3);
+        assert_eq!(sent(0, 8080), 3);
+        assert_eq!(sent(7, 8080), 5);
+    }
+
+    #[test]
+    fn test_read_service_probes_ignore_case() {
+        let service_probes =
+            read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let generic_lines = &service_probes.tcp_probes[1];
+        assert!(generic_lines.check_match(b"LOGIN: ").is_none());
+
+        let options = ParseOptions {
+            ignore_case: true,
+            ..Default::default()
+        };
+        let service_probes = read_service_probes(PROBES.as_bytes(), &options).unwrap();
+        let generic_lines = &service_probes.tcp_probes[1];
+        let outcome = generic_lines.check_match(b"LOGIN: ").unwrap();
+        assert_eq!(outcome.service_match.service, "telnet");
+        // the options are still as written
+        assert_eq!(outcome.service_match.pattern_options, "");
+    }
+
+    #[test]
+    fn test_services() {
+        let service_probes =
+            read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap();
+        let services: Vec<String> = service_probes.services().into_iter().collect();
+        assert_eq!(services, vec!["dns", "ftp", "telnet"]);
+    }
+
+    #[test]
+    fn test_read_service_probes_line_too_long_strict() {
+        let probes = format!("{}match ftp m/{}/\n", PROBES, "a".repeat(100));
+        let options = ParseOptions {
+            strict: true,
+            max_line_length: 64,
+            ..Default::default()
+        };
+        match read_service_probes(probes.as_bytes(), &options) {
+            Err(ParseError::LineTooLong { line, length }) => {
+                assert_eq!(line, 15);
+                assert_eq!(length, 113);
+            }
+            r => panic!("expected line too long error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_read_service_probes_line_too_long_lenient() {
+        let probes = format!("{}match ftp m/{}/\n", PROBES, "a".repeat(100));
+        let options = ParseOptions {
+            strict: false,
+            max_line_length: 64,
+            ..Default::default()
+        };
+        let service_probes = read_service_probes(probes.as_bytes(), &options).unwrap();
+        let dns = &service_probes.udp_probes[0];
+        assert_eq!(dns.directives.matches.as_ref().unwrap().len(), 1);
+    }
+}
@@ -1,10 +1,17 @@
-use crate::serviceprobes::parse::unescape::unescape;
+use crate::serviceprobes::parse::unescape::{unescape, UnescapeWarning};
 use crate::serviceprobes::{Probe, TransportProtocol};
 use std::str::FromStr;
 
 pub fn parse_probe_line(line: &str) -> Option<Probe> {
+    parse_probe_line_with_warnings(line).map(|(probe, _)| probe)
+}
+
+/// Parse a Probe line, also returning anything suspicious found while
+/// decoding the probe string
+pub fn parse_probe_line_with_warnings(line: &str) -> Option<(Probe, Vec<UnescapeWarning>)> {
     let parts: Vec<&str> = line.split_whitespace().collect();
-    if parts.len() < 3 || parts[0] != "Probe" {
+    // Probe <protocol> <name> q|<data>|
+    if parts.len() < 4 || parts[0] != "Probe" {
         return None;
     }
 
@@ -16,24 +23,27 @@ pub fn parse_probe_line(line: &str) -> Option<Probe> {
     let remainder = &probe[probe_start_index..];
     let probe_end_index = remainder.find(delimiter)?;
     let probe = &remainder[..probe_end_index];
-    let probe = unescape(probe.into());
+    let (probe, warnings) = unescape(probe);
     let mut no_payload = false;
 
     if remainder.len() > probe_end_index + 1 {
         let parts: Vec<&str> = remainder[probe_end_index + 1..]
             .split_whitespace()
             .collect();
-        if parts[0] == "no-payload" {
+        if parts.first() == Some(&"no-payload") {
             no_payload = true;
         }
     }
 
-    Some(Probe {
-        transport_protocol,
-        name,
-        data: probe.into(),
-        no_payload,
-    })
+    Some((
+        Probe {
+            transport_protocol,
+            name,
+            data: probe,
+            no_payload,
+        },
+        warnings,
+    ))
 }
 #[cfg(test)]
 mod tests {
@@ -48,7 +58,7 @@ mod tests {
 
         assert_eq!(parsed_line.transport_protocol, TransportProtocol::TCP);
         assert_eq!(parsed_line.data, "".as_bytes());
-        assert_eq!(parsed_line.no_payload, false);
+        assert!(!parsed_line.no_payload);
     }
 
     #[test]
@@ -60,7 +70,7 @@ mod tests {
 
         assert_eq!(parsed_line.transport_protocol, TransportProtocol::TCP);
         assert_eq!(parsed_line.data, ("\r\n\r\n").as_bytes());
-        assert_eq!(parsed_line.no_payload, false);
+        assert!(!parsed_line.no_payload);
     }
 
     #[test]
@@ -72,7 +82,21 @@ mod tests {
 
         assert_eq!(parsed_line.transport_protocol, TransportProtocol::UDP);
         assert_eq!(parsed_line.data, ("\x02").as_bytes());
-        assert_eq!(parsed_line.no_payload, true);
+        assert!(parsed_line.no_payload);
+    }
+
+    #[test]
+    fn test_parse_probe_line_too_short() {
+        assert!(parse_probe_line("Probe TCP GenericLines").is_none());
+        assert!(parse_probe_line("Probe TCP").is_none());
+        assert!(parse_probe_line("Probe TCP GenericLines q|unterminated").is_none());
+    }
+
+    #[test]
+    fn test_parse_probe_line_trailing_whitespace() {
+        let parsed_line = parse_probe_line("Probe TCP GenericLines q|\\r\\n| ").unwrap();
+        assert_eq!(parsed_line.data, b"\r\n");
+        assert!(!parsed_line.no_payload);
     }
 
     #[test]
@@ -81,5 +105,6 @@ mod tests {
         let result = parse_probe_line(line);
         assert!(result.is_some());
         let parsed_line = result.unwrap();
+        assert_eq!(parsed_line.data, b"\xd7");
     }
 }
@@ -1,37 +1,133 @@
-// gross
-pub fn unescape(s: String) -> Vec<u8> {
-    let mut chars: Vec<char> = s.chars().into_iter().rev().collect();
-    let mut unescaped = vec![];
+use std::fmt;
 
-    while let Some(c) = chars.pop() {
-        if c != '\\' {
-            unescaped.push(c.as_ascii().unwrap().to_u8());
+/// Something in a probe string that doesn't decode cleanly, so the bytes sent
+/// may not be what the probes file author meant
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnescapeWarning {
+    /// A backslash at the end of the string, sent as a literal backslash
+    TrailingBackslash,
+    /// A \x not followed by two hex digits, sent as written
+    InvalidHexEscape(String),
+    /// An escape nmap doesn't know, sent as the escaped character
+    UnknownEscape(char),
+    /// A character outside ASCII, sent as its UTF-8 bytes
+    NonAscii(char),
+}
 
-            continue;
+impl fmt::Display for UnescapeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnescapeWarning::TrailingBackslash => write!(f, "trailing backslash"),
+            UnescapeWarning::InvalidHexEscape(escape) => {
+                write!(f, "invalid hex escape \\x{}", escape)
+            }
+            UnescapeWarning::UnknownEscape(c) => write!(f, "unknown escape \\{}", c),
+            UnescapeWarning::NonAscii(c) => write!(f, "non-ASCII character {:?}", c),
         }
+    }
+}
 
-        let c = chars.pop().unwrap();
-        let c = match c {
-            '0' => '\0'.as_ascii().unwrap().to_u8(),
-            'n' => '\n'.as_ascii().unwrap().to_u8(),
-            'r' => '\r'.as_ascii().unwrap().to_u8(),
-            't' => '\t'.as_ascii().unwrap().to_u8(),
-            '\'' | '\"' | '\\' | '/' => c.as_ascii().unwrap().to_u8(),
-            'x' => {
-                let mut byte = String::new();
+/// Decode the C style escapes nmap allows in probe strings, returning the
+/// bytes along with anything that looked wrong while decoding
+pub fn unescape(s: &str) -> (Vec<u8>, Vec<UnescapeWarning>) {
+    let mut unescaped = Vec::with_capacity(s.len());
+    let mut warnings = vec![];
+    let mut chars = s.chars();
 
-                for i in 0..2 {
-                    let c = chars.pop().unwrap();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            if !c.is_ascii() {
+                warnings.push(UnescapeWarning::NonAscii(c));
+            }
+            let mut utf8 = [0; 4];
+            unescaped.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
 
-                    byte.push(c);
+        let c = match chars.next() {
+            Some(c) => c,
+            None => {
+                warnings.push(UnescapeWarning::TrailingBackslash);
+                unescaped.push(b'\\');
+                break;
+            }
+        };
+        let byte = match c {
+            '0' => b'\0',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'n' => b'\n',
+            'r' => b'\r',
+            't' => b'\t',
+            'v' => 0x0b,
+            '\'' | '"' | '\\' | '/' => c as u8,
+            'x' => {
+                let hex: String = chars.clone().take(2).collect();
+                if hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    chars.nth(1);
+                    u8::from_str_radix(&hex, 16).unwrap()
+                } else {
+                    warnings.push(UnescapeWarning::InvalidHexEscape(hex));
+                    unescaped.extend_from_slice(b"\\x");
+                    continue;
                 }
-                u8::from_str_radix(&byte, 16).unwrap()
             }
-            _ => c.as_ascii().unwrap().to_u8(),
+            _ => {
+                warnings.push(UnescapeWarning::UnknownEscape(c));
+                let mut utf8 = [0; 4];
+                unescaped.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                continue;
+            }
         };
 
-        unescaped.push(c);
+        unescaped.push(byte);
+    }
+
+    (unescaped, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape() {
+        let (bytes, warnings) = unescape(r"GET / HTTP/1.0\r\n\x00\xfF\\\0");
+        assert_eq!(bytes, b"GET / HTTP/1.0\r\n\x00\xff\\\0");
+        assert!(warnings.is_empty());
     }
 
-    unescaped
+    #[test]
+    fn test_unescape_warnings() {
+        assert_eq!(
+            unescape(r"abc\"),
+            (b"abc\\".to_vec(), vec![UnescapeWarning::TrailingBackslash])
+        );
+        assert_eq!(
+            unescape(r"\x4"),
+            (
+                b"\\x4".to_vec(),
+                vec![UnescapeWarning::InvalidHexEscape("4".into())]
+            )
+        );
+        assert_eq!(
+            unescape(r"\xzz1"),
+            (
+                b"\\xzz1".to_vec(),
+                vec![UnescapeWarning::InvalidHexEscape("zz".into())]
+            )
+        );
+        assert_eq!(
+            unescape(r"\q"),
+            (b"q".to_vec(), vec![UnescapeWarning::UnknownEscape('q')])
+        );
+        assert_eq!(
+            unescape("é"),
+            (
+                "é".as_bytes().to_vec(),
+                vec![UnescapeWarning::NonAscii('é')]
+            )
+        );
+    }
 }
@@ -0,0 +1,884 @@
+// Writing results out, to files, rotated by size or not, to sockets, to a
+// Splunk HTTP Event Collector and to stdout. Every result goes to each sink,
+// and a sink that fails is dropped while the rest carry on.
+
+use indicatif::ProgressBar;
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::hec::{post_events, HecConfig};
+use crate::output::{HostOutput, OutputFormat, RadarOutput, ScanSummary};
+
+// Results held for a socket sink while it reconnects, before writes block
+const SOCKET_SINK_BUFFER: usize = 10_000;
+const SOCKET_SINK_RECONNECT_ATTEMPTS: usize = 10;
+const SOCKET_SINK_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// events queued for a collector before writes to it wait
+const HEC_SINK_BUFFER: usize = 10_000;
+
+/// A socket to stream results to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutSink {
+    Tcp(String),
+    Unix(String),
+}
+
+impl FromStr for OutSink {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<OutSink, Self::Err> {
+        if let Some(addr) = input.strip_prefix("tcp://") {
+            Ok(OutSink::Tcp(addr.to_string()))
+        } else if let Some(path) = input.strip_prefix("unix://") {
+            Ok(OutSink::Unix(path.to_string()))
+        } else {
+            Err(format!(
+                "unknown sink {}, expected tcp://host:port or unix:///path",
+                input
+            ))
+        }
+    }
+}
+
+impl fmt::Display for OutSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutSink::Tcp(addr) => write!(f, "tcp://{}", addr),
+            OutSink::Unix(path) => write!(f, "unix://{}", path),
+        }
+    }
+}
+
+impl OutSink {
+    async fn connect(&self) -> io::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        match self {
+            OutSink::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+            #[cfg(unix)]
+            OutSink::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            OutSink::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported on this platform",
+            )),
+        }
+    }
+}
+
+// Send each encoded record to the socket. When a write fails, the record is
+// held while reconnecting and sent again whole on the new connection, so the
+// framing there starts on a record, and the records queued behind it fill
+// the channel meanwhile until writes to the sink block.
+async fn forward_to_socket(
+    sink: OutSink,
+    mut socket: Box<dyn AsyncWrite + Unpin + Send>,
+    mut records: mpsc::Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    while let Some(record) = records.recv().await {
+        let mut attempts = 0;
+        let mut sent = write_and_flush(&mut socket, &record).await;
+        while let Err(e) = sent {
+            attempts += 1;
+            if attempts > SOCKET_SINK_RECONNECT_ATTEMPTS {
+                return Err(e);
+            }
+            tracing::warn!("failed to write to {}, reconnecting: {}", sink, e);
+            tokio::time::sleep(SOCKET_SINK_RECONNECT_DELAY).await;
+            sent = match sink.connect().await {
+                Ok(reconnected) => {
+                    socket = reconnected;
+                    write_and_flush(&mut socket, &record).await
+                }
+                Err(e) => Err(e),
+            };
+        }
+    }
+    socket.shutdown().await
+}
+
+async fn write_and_flush<W>(writer: &mut W, data: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+type Sink = BufWriter<Box<dyn AsyncWrite + Unpin + Send>>;
+
+// A collector's name, the events for it and the task posting them
+type HecSink = (String, mpsc::Sender<Vec<u8>>, JoinHandle<io::Result<()>>);
+
+// A socket's name, the encoded records for it and the task sending them
+type SocketSink = (String, mpsc::Sender<Vec<u8>>, JoinHandle<io::Result<()>>);
+
+// Splits a file sink across numbered files. The next file is started before
+// a record once the current one has reached max_size, so records are never
+// split, and each file is shut down complete before the next is opened.
+struct Rotation {
+    path: String,
+    max_size: u64,
+    // bytes written to the current file
+    written: u64,
+    // number of the current file, 0 for the first which isn't numbered
+    file: usize,
+}
+
+impl Rotation {
+    fn new(path: &str, max_size: u64) -> Self {
+        Rotation {
+            path: path.to_string(),
+            max_size,
+            written: 0,
+            file: 0,
+        }
+    }
+
+    // Close the current file and open the next one when it's full
+    async fn rotate_if_full(&mut self, writer: &mut Sink) -> io::Result<()> {
+        if self.written < self.max_size {
+            return Ok(());
+        }
+        writer.shutdown().await?;
+        self.file += 1;
+        self.written = 0;
+        let path = numbered_path(&self.path, self.file);
+        tracing::info!(
+            "output reached {} bytes, continuing in {}",
+            self.max_size,
+            path
+        );
+        let file: Box<dyn AsyncWrite + Unpin + Send> = Box::new(File::create(path).await?);
+        *writer = BufWriter::new(file);
+        Ok(())
+    }
+}
+
+// The path with n before its extension, or after it when it has none
+fn numbered_path(path: &str, n: usize) -> String {
+    let p = std::path::Path::new(path);
+    match (p.file_stem(), p.extension()) {
+        (Some(stem), Some(ext)) => p
+            .with_file_name(format!(
+                "{}.{}.{}",
+                stem.to_string_lossy(),
+                n,
+                ext.to_string_lossy()
+            ))
+            .to_string_lossy()
+            .into_owned(),
+        _ => format!("{}.{}", path, n),
+    }
+}
+
+// Every result is written to each sink. A sink that fails is dropped and the
+// rest carry on, the first failure is returned once all results are written.
+pub struct Sinks {
+    sinks: Vec<(String, Sink, Option<Rotation>)>,
+    sockets: Vec<SocketSink>,
+    hec: Option<HecSink>,
+    error: Option<io::Error>,
+    format: OutputFormat,
+    pretty: bool,
+}
+
+impl Sinks {
+    pub fn new(pretty: bool) -> Self {
+        Self {
+            sinks: vec![],
+            sockets: vec![],
+            hec: None,
+            error: None,
+            format: OutputFormat::Json,
+            pretty,
+        }
+    }
+
+    pub fn push<W>(&mut self, name: &str, writer: W)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let writer: Box<dyn AsyncWrite + Unpin + Send> = Box::new(writer);
+        self.sinks
+            .push((name.to_string(), BufWriter::new(writer), None));
+    }
+
+    // A file sink rotated to a new file every max_size bytes
+    pub async fn push_rotating(&mut self, path: &str, max_size: u64) -> io::Result<()> {
+        self.push(path, File::create(path).await?);
+        if let Some((_, _, rotation)) = self.sinks.last_mut() {
+            *rotation = Some(Rotation::new(path, max_size));
+        }
+        Ok(())
+    }
+
+    // Results for a socket are queued as whole records for a task to send, so
+    // a reconnect holds up the writes to it rather than losing them
+    pub fn push_socket(&mut self, sink: OutSink, socket: Box<dyn AsyncWrite + Unpin + Send>) {
+        let name = sink.to_string();
+        let (tx, rx) = mpsc::channel(SOCKET_SINK_BUFFER);
+        let forwarder = tokio::spawn(forward_to_socket(sink, socket, rx));
+        self.sockets.push((name, tx, forwarder));
+    }
+
+    // Results for a collector are encoded as HEC events whatever the output
+    // format, and posted in batches by a task
+    pub fn push_hec(&mut self, config: HecConfig) {
+        let name = config.url.clone();
+        let (tx, rx) = mpsc::channel(HEC_SINK_BUFFER);
+        let poster = tokio::spawn(async move {
+            post_events(config, rx)
+                .await
+                .map_err(|e| io::Error::other(e.to_string()))
+        });
+        self.hec = Some((name, tx, poster));
+    }
+
+    pub async fn write<R: Serialize>(&mut self, result: &R) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.sinks.len() {
+            let (_, writer, rotation) = &mut self.sinks[i];
+            let written = match rotation {
+                Some(rotation) => match rotation.rotate_if_full(writer).await {
+                    Ok(()) => write_result(writer, result, self.format, self.pretty)
+                        .await
+                        .map(|n| rotation.written += n as u64),
+                    Err(e) => Err(e),
+                },
+                None => write_result(writer, result, self.format, self.pretty)
+                    .await
+                    .map(|_| ()),
+            };
+            match written {
+                Ok(()) => i += 1,
+                Err(e) => self.drop_sink(i, e),
+            }
+        }
+        let mut i = 0;
+        while i < self.sockets.len() {
+            let sent = match self.format.encode(result, self.pretty) {
+                Ok(record) => self.sockets[i].1.send(record).await.is_ok(),
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    false
+                }
+            };
+            match sent {
+                true => i += 1,
+                false => self.drop_socket(i).await,
+            }
+        }
+        if let Some((_, tx, _)) = &self.hec {
+            let sent = match OutputFormat::Hec.encode(result, false) {
+                Ok(event) => tx.send(event).await.is_ok(),
+                Err(e) => {
+                    self.error.get_or_insert(e);
+                    false
+                }
+            };
+            if !sent {
+                self.drop_hec().await;
+            }
+        }
+        self.any_left()
+    }
+
+    // The task sending to the socket gave up, take its error
+    async fn drop_socket(&mut self, i: usize) {
+        let (name, tx, forwarder) = self.sockets.remove(i);
+        drop(tx);
+        let e = match forwarder.await.map_err(io::Error::other).and_then(|r| r) {
+            Ok(()) => io::Error::other("stopped sending"),
+            Err(e) => e,
+        };
+        tracing::error!(
+            "failed to write to {}, no longer writing to it: {}",
+            name,
+            e
+        );
+        self.error.get_or_insert(e);
+    }
+
+    // The task posting to the collector gave up, take its error
+    async fn drop_hec(&mut self) {
+        let (name, tx, poster) = match self.hec.take() {
+            Some(hec) => hec,
+            None => return,
+        };
+        drop(tx);
+        let e = match poster.await.map_err(io::Error::other).and_then(|r| r) {
+            Ok(()) => io::Error::other("stopped posting"),
+            Err(e) => e,
+        };
+        tracing::error!("failed to post to {}, no longer posting to it: {}", name, e);
+        self.error.get_or_insert(e);
+    }
+
+    // Flush every sink, dropping those that fail like a failed write
+    pub async fn flush(&mut self) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.sinks.len() {
+            match self.sinks[i].1.flush().await {
+                Ok(()) => i += 1,
+                Err(e) => self.drop_sink(i, e),
+            }
+        }
+        self.any_left()
+    }
+
+    fn drop_sink(&mut self, i: usize, e: io::Error) {
+        let (name, _, _) = self.sinks.remove(i);
+        tracing::error!(
+            "failed to write to {}, no longer writing to it: {}",
+            name,
+            e
+        );
+        self.error.get_or_insert(e);
+    }
+
+    fn any_left(&mut self) -> io::Result<()> {
+        if self.sinks.is_empty() && self.sockets.is_empty() && self.hec.is_none() {
+            return Err(self
+                .error
+                .take()
+                .unwrap_or_else(|| io::Error::other("no output sinks")));
+        }
+        Ok(())
+    }
+
+    // Flush, then wait for the socket sinks to send everything written to them
+    // and the collector to take every event
+    pub async fn finish(mut self) -> io::Result<()> {
+        if let Err(e) = self.flush().await {
+            self.error.get_or_insert(e);
+        }
+        let Sinks {
+            sinks,
+            sockets,
+            hec,
+            mut error,
+            ..
+        } = self;
+        drop(sinks);
+        let mut forwarders = vec![];
+        for (name, tx, forwarder) in sockets.into_iter().chain(hec) {
+            drop(tx);
+            forwarders.push((name, forwarder));
+        }
+        for (name, forwarder) in forwarders {
+            let result = forwarder.await.map_err(io::Error::other).and_then(|r| r);
+            if let Err(e) = result {
+                tracing::error!("failed to write to {}: {}", name, e);
+                error.get_or_insert(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+}
+
+/// Where results are written
+#[derive(Debug, Default)]
+pub struct SinkOptions {
+    pub out_file: Option<String>,
+    /// Rotate out_file to a new numbered file every this many bytes
+    pub max_output_size: Option<u64>,
+    pub out_sink: Option<OutSink>,
+    pub hec: Option<HecConfig>,
+    /// Write to stdout as well as the other sinks
+    pub also_stdout: bool,
+    pub format: OutputFormat,
+    pub pretty: bool,
+}
+
+// Results go to the out file, the socket and the collector, and to stdout
+// when there's none of them or also_stdout is set
+pub async fn open_sinks(options: SinkOptions) -> io::Result<Sinks> {
+    let mut sinks = Sinks::new(options.pretty);
+    sinks.format = options.format;
+    match (&options.out_file, options.max_output_size) {
+        (Some(f), Some(max_size)) => sinks.push_rotating(f, max_size).await?,
+        (Some(f), None) => sinks.push(f, File::create(f).await?),
+        (None, _) => (),
+    }
+    let elsewhere =
+        options.out_file.is_some() || options.out_sink.is_some() || options.hec.is_some();
+    if let Some(sink) = options.out_sink {
+        let socket = sink.connect().await?;
+        sinks.push_socket(sink, socket);
+    }
+    if let Some(hec) = options.hec {
+        sinks.push_hec(hec);
+    }
+    if !elsewhere || options.also_stdout {
+        sinks.push("stdout", io::stdout());
+    }
+    Ok(sinks)
+}
+
+pub async fn write_results(
+    mut sinks: Sinks,
+    mut rx: mpsc::Receiver<RadarOutput>,
+    progress: ProgressBar,
+    mut summary: ScanSummary,
+) -> io::Result<ScanSummary> {
+    let mut next = rx.recv().await;
+    while let Some(result) = next {
+        sinks.write(&result).await?;
+        progress.inc(1);
+        summary.add(&result);
+        // Batch writes while results are queued up, flush once the channel is
+        // drained so output isn't held back while scans are in flight
+        next = match rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::error::TryRecvError::Empty) => {
+                sinks.flush().await?;
+                rx.recv().await
+            }
+            Err(mpsc::error::TryRecvError::Disconnected) => None,
+        };
+    }
+    sinks.finish().await?;
+    Ok(summary)
+}
+
+// Sorting or grouping holds every result in memory, warn when that gets large
+const SORTED_RESULTS_WARN_THRESHOLD: usize = 1_000_000;
+
+// Every result, once the scan is done, counted in the summary as they come.
+// flag is the option that needs them all, named in the warning when they get
+// large.
+async fn buffer_results(
+    rx: &mut mpsc::Receiver<RadarOutput>,
+    progress: &ProgressBar,
+    summary: &mut ScanSummary,
+    flag: &str,
+) -> Vec<RadarOutput> {
+    let mut results = vec![];
+    while let Some(result) = rx.recv().await {
+        summary.add(&result);
+        results.push(result);
+        progress.inc(1);
+        if results.len() == SORTED_RESULTS_WARN_THRESHOLD {
+            tracing::warn!(
+                "holding {} results in memory for {}, consider scanning without it",
+                results.len(),
+                flag
+            );
+        }
+    }
+    results
+}
+
+// Buffer all results and write them sorted by target address and port
+pub async fn write_sorted_results(
+    mut sinks: Sinks,
+    mut rx: mpsc::Receiver<RadarOutput>,
+    progress: ProgressBar,
+    mut summary: ScanSummary,
+) -> io::Result<ScanSummary> {
+    let mut results = buffer_results(&mut rx, &progress, &mut summary, "--sort").await;
+    results.sort_by(|a, b| a.target.cmp_by_address(&b.target));
+    for result in &results {
+        sinks.write(result).await?;
+    }
+    sinks.finish().await?;
+    Ok(summary)
+}
+
+// Buffer all results and write a record per host, in address order
+pub async fn write_host_results(
+    mut sinks: Sinks,
+    mut rx: mpsc::Receiver<RadarOutput>,
+    progress: ProgressBar,
+    mut summary: ScanSummary,
+) -> io::Result<ScanSummary> {
+    let results = buffer_results(&mut rx, &progress, &mut summary, "--group-by-host").await;
+    for host in HostOutput::group(results) {
+        sinks.write(&host).await?;
+    }
+    sinks.finish().await?;
+    Ok(summary)
+}
+
+// One record per line, or with pretty, one indented record per block of
+// lines. Binary formats are length prefixed. Left buffered, the caller
+// decides when to flush. Returns the number of bytes written.
+async fn write_result<T, R>(
+    writer: &mut BufWriter<T>,
+    result: &R,
+    format: OutputFormat,
+    pretty: bool,
+) -> io::Result<usize>
+where
+    T: AsyncWriteExt + Unpin,
+    R: Serialize,
+{
+    let data = format.encode(result, pretty)?;
+    writer.write_all(&data).await?;
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan::Target;
+    use tokio::io::AsyncReadExt;
+
+    // Fails every write, like a full disk or a closed pipe
+    struct BrokenSink;
+
+    impl AsyncWrite for BrokenSink {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            std::task::Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sinks_keep_writing_after_one_fails() {
+        let (good, mut good_reader) = io::duplex(4096);
+        let mut sinks = Sinks::new(false);
+        sinks.push("broken", BrokenSink);
+        sinks.push("good", good);
+
+        let (tx, rx) = mpsc::channel(2);
+        for port in [1, 2] {
+            let target = Target::new("10.0.0.1", port);
+            tx.send(RadarOutput::from_connect(target, Ok(())))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+
+        let e = write_results(sinks, rx, ProgressBar::hidden(), Default::default())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+
+        let mut written = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut good_reader, &mut written)
+            .await
+            .unwrap();
+        assert_eq!(written.lines().count(), 2);
+    }
+
+    // Counts flushes and the lines written
+    #[derive(Clone, Default)]
+    struct FlushCounter {
+        flushes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+        lines: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl FlushCounter {
+        fn counts(&self) -> (usize, usize) {
+            let ordering = std::sync::atomic::Ordering::SeqCst;
+            (self.flushes.load(ordering), self.lines.load(ordering))
+        }
+    }
+
+    impl AsyncWrite for FlushCounter {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            let lines = buf.iter().filter(|b| **b == b'\n').count();
+            self.lines
+                .fetch_add(lines, std::sync::atomic::Ordering::SeqCst);
+            std::task::Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            self.flushes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_results_flush_when_drained() {
+        let counter = FlushCounter::default();
+        let mut sinks = Sinks::new(false);
+        sinks.push("counter", counter.clone());
+        let result = |port| {
+            let target = Target::new("10.0.0.1", port);
+            RadarOutput::from_connect(target, Ok(()))
+        };
+        let wait_for_flushes = |n| {
+            let counter = counter.clone();
+            async move {
+                while counter.counts().0 < n {
+                    tokio::task::yield_now().await;
+                }
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(8);
+        for port in 1..=3 {
+            tx.send(result(port)).await.unwrap();
+        }
+        let writer = tokio::spawn(write_results(
+            sinks,
+            rx,
+            ProgressBar::hidden(),
+            Default::default(),
+        ));
+
+        // the queued results are written together with a single flush
+        wait_for_flushes(1).await;
+        assert_eq!(counter.counts(), (1, 3));
+
+        // a lone result is flushed as soon as it's written
+        tx.send(result(4)).await.unwrap();
+        wait_for_flushes(2).await;
+        assert_eq!(counter.counts(), (2, 4));
+
+        drop(tx);
+        writer.await.unwrap().unwrap();
+        assert_eq!(counter.counts(), (3, 4));
+    }
+
+    #[tokio::test]
+    async fn test_write_result_pretty() {
+        let target = Target::new("10.0.0.1", 80);
+        let result = RadarOutput::from_connect(target, Ok(()));
+
+        let mut compact = BufWriter::new(vec![]);
+        write_result(&mut compact, &result, OutputFormat::Json, false)
+            .await
+            .unwrap();
+        compact.flush().await.unwrap();
+        let compact = String::from_utf8(compact.into_inner()).unwrap();
+        assert_eq!(compact.lines().count(), 1);
+
+        let mut pretty = BufWriter::new(vec![]);
+        write_result(&mut pretty, &result, OutputFormat::Json, true)
+            .await
+            .unwrap();
+        pretty.flush().await.unwrap();
+        let pretty = String::from_utf8(pretty.into_inner()).unwrap();
+        assert!(pretty.lines().count() > 1);
+        assert!(pretty.ends_with("}\n"));
+        let parsed: serde_json::Value = serde_json::from_str(&pretty).unwrap();
+        assert_eq!(
+            parsed,
+            serde_json::from_str::<serde_json::Value>(&compact).unwrap()
+        );
+    }
+
+    fn sink_names(sinks: &Sinks) -> Vec<&str> {
+        sinks
+            .sinks
+            .iter()
+            .map(|(name, _, _)| name.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_open_sinks() {
+        let sinks = open_sinks(SinkOptions::default()).await.unwrap();
+        assert_eq!(sink_names(&sinks), vec!["stdout"]);
+
+        let dir = std::env::temp_dir().join(format!("radar-sinks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.jsonl").to_string_lossy().into_owned();
+        let options = || SinkOptions {
+            out_file: Some(out.clone()),
+            ..Default::default()
+        };
+        let sinks = open_sinks(options()).await.unwrap();
+        assert_eq!(sink_names(&sinks), vec![out.as_str()]);
+
+        let sinks = open_sinks(SinkOptions {
+            also_stdout: true,
+            ..options()
+        })
+        .await
+        .unwrap();
+        assert_eq!(sink_names(&sinks), vec![out.as_str(), "stdout"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_output_rotation() {
+        assert_eq!(numbered_path("out/scan.json", 2), "out/scan.2.json");
+        assert_eq!(numbered_path("scan", 1), "scan.1");
+
+        let dir = std::env::temp_dir().join(format!("radar-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.json").to_string_lossy().into_owned();
+        let record = |port| {
+            let target = Target::new("10.0.0.1", port);
+            RadarOutput::from_connect(target, Ok(()))
+        };
+        let record_size = OutputFormat::Json.encode(&record(1), false).unwrap().len();
+
+        // two records fit before a file is full, the next starts a new file
+        let mut sinks = Sinks::new(false);
+        sinks
+            .push_rotating(&out, 2 * record_size as u64 - 1)
+            .await
+            .unwrap();
+        for port in 1..=5 {
+            sinks.write(&record(port)).await.unwrap();
+        }
+        sinks.finish().await.unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(path).unwrap();
+        let files = [
+            read(&out),
+            read(&numbered_path(&out, 1)),
+            read(&numbered_path(&out, 2)),
+        ];
+        let lines: Vec<usize> = files.iter().map(|f| f.lines().count()).collect();
+        assert_eq!(lines, vec![2, 2, 1]);
+        for line in files.iter().flat_map(|f| f.lines()) {
+            serde_json::from_str::<RadarOutput>(line).unwrap();
+        }
+        assert!(!std::path::Path::new(&numbered_path(&out, 3)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_out_sink_from_str() {
+        assert_eq!(
+            "tcp://127.0.0.1:9000".parse(),
+            Ok(OutSink::Tcp("127.0.0.1:9000".into()))
+        );
+        assert_eq!(
+            "unix:///run/radar.sock".parse(),
+            Ok(OutSink::Unix("/run/radar.sock".into()))
+        );
+        assert!("udp://127.0.0.1:9000".parse::<OutSink>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_sink() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = OutSink::Tcp(listener.local_addr().unwrap().to_string());
+        let collector = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).await.unwrap();
+            received
+        });
+
+        let mut sinks = Sinks::new(false);
+        sinks.push_socket(sink.clone(), sink.connect().await.unwrap());
+        let (tx, rx) = mpsc::channel(2);
+        for port in [1, 2] {
+            let target = Target::new("10.0.0.1", port);
+            tx.send(RadarOutput::from_connect(target, Ok(())))
+                .await
+                .unwrap();
+        }
+        drop(tx);
+        write_results(sinks, rx, ProgressBar::hidden(), Default::default())
+            .await
+            .unwrap();
+
+        assert_eq!(collector.await.unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_socket_sink_reconnect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = OutSink::Tcp(listener.local_addr().unwrap().to_string());
+        let collector = tokio::spawn(async move {
+            // the first connection is closed straight away
+            drop(listener.accept().await.unwrap());
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = String::new();
+            socket.read_to_string(&mut received).await.unwrap();
+            received
+        });
+
+        let mut sinks = Sinks::new(false);
+        sinks.push_socket(sink.clone(), sink.connect().await.unwrap());
+        for port in 1..=5 {
+            let target = Target::new("10.0.0.1", port);
+            sinks
+                .write(&RadarOutput::from_connect(target, Ok(())))
+                .await
+                .unwrap();
+            if port < 3 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+        sinks.finish().await.unwrap();
+
+        // records written before the failure showed are lost, the rest
+        // arrive whole on the new connection
+        let received = collector.await.unwrap();
+        let ports: Vec<u64> = received
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["target"]["port"].as_u64().unwrap()
+            })
+            .collect();
+        assert!(!ports.is_empty());
+        assert_eq!(ports.last(), Some(&5));
+    }
+
+    #[tokio::test]
+    async fn test_hec_sink_failure() {
+        // nothing listens on the port once the listener is dropped
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/services/collector/event",
+            listener.local_addr().unwrap()
+        );
+        drop(listener);
+
+        let mut sinks = Sinks::new(false);
+        sinks.push_hec(HecConfig {
+            retries: 0,
+            ..HecConfig::new(&url, "token-1")
+        });
+        let (tx, rx) = mpsc::channel(1);
+        let target = Target::new("10.0.0.1", 1);
+        tx.send(RadarOutput::from_connect(target, Ok(())))
+            .await
+            .unwrap();
+        drop(tx);
+        // the events that couldn't be posted fail the run
+        let e = write_results(sinks, rx, ProgressBar::hidden(), Default::default())
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("refused"), "{}", e);
+    }
+}
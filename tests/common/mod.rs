@@ -0,0 +1,88 @@
+// Mock servers the scan tests run against, shared by the unit tests in
+// src/scan.rs and the end to end tests in tests/. Each test uses some of the
+// behaviors.
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// What a mock server does with each connection it accepts
+#[derive(Clone, Copy)]
+pub enum Behavior {
+    // send a banner as soon as the connection is accepted
+    Banner(&'static [u8]),
+    // wait for a request, then answer it
+    Respond(&'static [u8]),
+    // answer every request on the connection with the request plus -ok
+    Echo,
+    // accept and never send anything
+    Silent,
+    // send these bytes, if any, then close the connection
+    Hangup(&'static [u8]),
+    // accept then reset the connection
+    Reset,
+}
+
+// Listen on a loopback port, returning it and a count of the connections
+// accepted
+pub async fn mock_server(behavior: Behavior) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(serve(socket, behavior));
+        }
+    });
+    (port, connections)
+}
+
+async fn serve(mut socket: TcpStream, behavior: Behavior) {
+    match behavior {
+        Behavior::Banner(banner) => {
+            let _ = socket.write_all(banner).await;
+            // held open, as a service waiting on a command would
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        }
+        Behavior::Respond(response) => {
+            let mut buf = [0u8; 1024];
+            if let Ok(n) = socket.read(&mut buf).await {
+                if n > 0 {
+                    let _ = socket.write_all(response).await;
+                }
+            }
+        }
+        Behavior::Echo => {
+            let mut buf = [0u8; 64];
+            loop {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => {
+                        let mut response = buf[..n].to_vec();
+                        response.extend_from_slice(b"-ok");
+                        if socket.write_all(&response).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+        Behavior::Silent => tokio::time::sleep(Duration::from_secs(10)).await,
+        Behavior::Hangup(bytes) => {
+            let _ = socket.write_all(bytes).await;
+        }
+        Behavior::Reset => {
+            // closing with a zero linger sends a reset instead of a fin, and
+            // with nothing left to send the drop doesn't block
+            #[allow(deprecated)]
+            let _ = socket.set_linger(Some(Duration::ZERO));
+            drop(socket);
+        }
+    }
+}
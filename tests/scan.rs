@@ -0,0 +1,180 @@
+// The scan path end to end, against local servers that answer the way real
+// services do or fail to
+
+mod common;
+
+use common::{mock_server, Behavior};
+use radar::output::{PortState, RadarOutput};
+use radar::scan::{scan, start_scan_probe_sets, AdaptiveTimeout, ProbeSet, ScanConfig, Target};
+use radar::serviceprobes::parse::{read_service_probes, ParseOptions};
+use radar::serviceprobes::ServiceProbes;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_native_tls::TlsConnector;
+
+const PROBES: &str = "\
+Probe TCP NULL q||
+match ftp m|^220 ([\\w.-]+) FTP|s p/$1/
+match ssh m|^SSH-2\\.0-OpenSSH_([\\w.]+)| p/OpenSSH/ v/$1/
+Probe TCP GetRequest q|GET / HTTP/1.0\\r\\n\\r\\n|
+match http m|^HTTP/1\\.[01] \\d\\d\\d| p/generic http/
+";
+
+fn probes() -> ServiceProbes {
+    read_service_probes(PROBES.as_bytes(), &ParseOptions::default()).unwrap()
+}
+
+fn tls_connector() -> TlsConnector {
+    native_tls::TlsConnector::new().unwrap().into()
+}
+
+// Short reads, so a silent server doesn't hold a test for the default timeout
+fn config() -> ScanConfig {
+    ScanConfig {
+        adaptive_timeout: Some(AdaptiveTimeout {
+            rtt_multiplier: 1,
+            min_ms: 300,
+            max_ms: 300,
+        }),
+        ..Default::default()
+    }
+}
+
+fn response(output: &RadarOutput) -> Vec<u8> {
+    base64::decode(output.response.as_ref().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn test_banner_match() {
+    let (port, _) = mock_server(Behavior::Banner(b"SSH-2.0-OpenSSH_9.6\r\n")).await;
+    let cx = tls_connector();
+    let output = scan(
+        Target::new("127.0.0.1", port),
+        &probes(),
+        Some(&cx),
+        &config(),
+    )
+    .await;
+
+    let service_match = output.service_match.as_ref().unwrap();
+    assert_eq!(service_match.service, "ssh");
+    assert!(service_match.version_info.starts_with("p/OpenSSH/"));
+    assert_eq!(response(&output), b"SSH-2.0-OpenSSH_9.6\r\n");
+    assert_eq!(output.port_state, Some(PortState::Open));
+    assert_eq!(output.tls, Some(false));
+    assert!(output.tls_handshake.is_none());
+    assert!(output.tls_service_match.is_none());
+    assert!(output.error.is_none());
+}
+
+#[tokio::test]
+async fn test_probe_response_match() {
+    let (port, _) = mock_server(Behavior::Respond(b"HTTP/1.0 200 OK\r\n\r\n")).await;
+    let output = scan(Target::new("127.0.0.1", port), &probes(), None, &config()).await;
+
+    assert_eq!(output.service_match.as_ref().unwrap().service, "http");
+    assert_eq!(response(&output), b"HTTP/1.0 200 OK\r\n\r\n");
+    // without a tls connector tls is never tried
+    assert_eq!(output.tls, None);
+    assert_eq!(output.tls_disabled, Some(true));
+}
+
+#[tokio::test]
+async fn test_no_detection() {
+    let (port, _) = mock_server(Behavior::Banner(b"* OK unknown service ready\r\n")).await;
+    let cx = tls_connector();
+    let output = scan(
+        Target::new("127.0.0.1", port),
+        &probes(),
+        Some(&cx),
+        &config(),
+    )
+    .await;
+
+    assert!(output.service_match.is_none());
+    assert_eq!(output.error.as_deref(), Some("No Detection"));
+    assert_eq!(response(&output), b"* OK unknown service ready\r\n");
+    // the banner came again on the last probe's own connection
+    assert_eq!(output.response_probe.as_deref(), Some("GetRequest"));
+    assert_eq!(output.port_state, Some(PortState::Open));
+    assert!(output.tls_handshake.is_none());
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let (port, _) = mock_server(Behavior::Silent).await;
+    let start = Instant::now();
+    let output = scan(Target::new("127.0.0.1", port), &probes(), None, &config()).await;
+
+    assert!(output.service_match.is_none());
+    assert!(output.response.is_none());
+    assert!(output.error.unwrap().contains("elapsed"));
+    assert_eq!(output.port_state, Some(PortState::OpenNoBanner));
+    // both probes waited out the short read timeout, not the default one
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[tokio::test]
+async fn test_reset() {
+    let (port, _) = mock_server(Behavior::Reset).await;
+    let output = scan(Target::new("127.0.0.1", port), &probes(), None, &config()).await;
+
+    assert!(output.service_match.is_none());
+    assert!(output.response.is_none());
+    assert!(output.error.unwrap().to_lowercase().contains("reset"));
+    assert_eq!(output.port_state, Some(PortState::Open));
+}
+
+#[tokio::test]
+async fn test_probe_sets() {
+    let (port, _) = mock_server(Behavior::Banner(b"SSH-2.0-OpenSSH_9.6\r\n")).await;
+    let candidate = "Probe TCP NULL q||\nmatch openssh m|^SSH-2\\.0-OpenSSH| p/OpenSSH/\n";
+    let candidate = read_service_probes(candidate.as_bytes(), &ParseOptions::default()).unwrap();
+    // keeping only the best match keeps the best of each set
+    for best_match in [false, true] {
+        let probe_sets = vec![
+            ProbeSet {
+                name: "stock".into(),
+                probes: probes(),
+            },
+            ProbeSet {
+                name: "candidate".into(),
+                probes: candidate.clone(),
+            },
+        ];
+        let (tx, mut rx) = mpsc::channel(4);
+        let targets = futures::stream::iter(vec![Target::new("127.0.0.1", port)]);
+        let config = ScanConfig {
+            best_match,
+            ..config()
+        };
+        start_scan_probe_sets(targets, probe_sets, tx, config).await;
+
+        let mut detected = vec![];
+        while let Some(output) = rx.recv().await {
+            let service = output.service_match.map(|m| m.service);
+            detected.push((output.probe_set.unwrap(), service.unwrap()));
+        }
+        assert_eq!(
+            detected,
+            vec![
+                ("stock".to_string(), "ssh".to_string()),
+                ("candidate".to_string(), "openssh".to_string()),
+            ]
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_closed_port() {
+    // bound then dropped, so nothing listens on the port
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let output = scan(Target::new("127.0.0.1", port), &probes(), None, &config()).await;
+
+    assert!(output.service_match.is_none());
+    assert_eq!(output.port_state, Some(PortState::Closed));
+}